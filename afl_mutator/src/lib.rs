@@ -1,7 +1,7 @@
 extern crate byteorder;
 extern crate rand;
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
-use rand::{thread_rng, Rng};
+use rand::Rng;
 use std::cmp;
 use std::io::Cursor;
 use std::ops::Range;
@@ -232,9 +232,8 @@ impl MutationState {
     pub fn startbruteforce(&mut self, offset: usize) {
         self.stage = Stage::Bruteforce { offset, value: 0 };
     }
-    //Do one random permutation
-    pub fn havoc(&mut self, data: &mut Vec<u8>) {
-        let mut rng = thread_rng();
+    //Do one random permutation, all random choices are drawn from rng
+    pub fn havoc<R: Rng>(&mut self, data: &mut Vec<u8>, rng: &mut R) {
         let max_step: u8;
         let mut datalen;
         if self.options.change_size {
@@ -246,20 +245,20 @@ impl MutationState {
         for _ in 0..(1 << 1 + rng.gen_range(0, AFL_HAVOC_STACK_POW2)) {
             datalen = data.len();
             match rng.gen_range(0, max_step) {
-                0 => self.flip_random(data, datalen), //Flip a single bit somewhere
-                1 => self.set_random_interest8(data, datalen), //Set byte to interesting value
-                2 => self.set_random_interest16(data, datalen), //Set word to interesting value
-                3 => self.set_random_interest32(data, datalen), //Set dword to interesting value
-                4 => self.subtract_random_arith8(data, datalen), //Randomly subtract from byte
-                5 => self.add_random_arith8(data, datalen), //Randomly add to byte
-                6 => self.subtract_random_arith16(data, datalen), //Randomly subtract from word
-                7 => self.add_random_arith16(data, datalen), //Randomly add to word
-                8 => self.subtract_random_arith32(data, datalen), //Randomly subtract from dword
-                9 => self.add_random_arith32(data, datalen), //Randomly add to dword
-                10 => self.set_random_byte(data, datalen), //Set Random byte to random value
-                11 => self.overwrite_random_bytes(data, datalen), //Overwrite bytes with randomly selected chunk (75%) or fixed bytes (25%)
-                12...13 => self.delete_random_bytes(data, datalen), //Delete bytes
-                14 => self.insert_random_bytes(data, datalen), //75% clone bytes, 25% insert random byte
+                0 => self.flip_random(data, datalen, rng), //Flip a single bit somewhere
+                1 => self.set_random_interest8(data, datalen, rng), //Set byte to interesting value
+                2 => self.set_random_interest16(data, datalen, rng), //Set word to interesting value
+                3 => self.set_random_interest32(data, datalen, rng), //Set dword to interesting value
+                4 => self.subtract_random_arith8(data, datalen, rng), //Randomly subtract from byte
+                5 => self.add_random_arith8(data, datalen, rng), //Randomly add to byte
+                6 => self.subtract_random_arith16(data, datalen, rng), //Randomly subtract from word
+                7 => self.add_random_arith16(data, datalen, rng), //Randomly add to word
+                8 => self.subtract_random_arith32(data, datalen, rng), //Randomly subtract from dword
+                9 => self.add_random_arith32(data, datalen, rng), //Randomly add to dword
+                10 => self.set_random_byte(data, datalen, rng), //Set Random byte to random value
+                11 => self.overwrite_random_bytes(data, datalen, rng), //Overwrite bytes with randomly selected chunk (75%) or fixed bytes (25%)
+                12...13 => self.delete_random_bytes(data, datalen, rng), //Delete bytes
+                14 => self.insert_random_bytes(data, datalen, rng), //75% clone bytes, 25% insert random byte
                 _ => {}
             };
         }
@@ -1374,25 +1373,21 @@ impl MutationState {
 
     //Functions for havoc
 
-    fn set_random_byte(&mut self, data: &mut Vec<u8>, datalen: usize) {
-        let mut rng = thread_rng();
+    fn set_random_byte<R: Rng>(&mut self, data: &mut Vec<u8>, datalen: usize, rng: &mut R) {
         data[rng.gen_range(0, datalen)] = rng.gen_range(0, 255);
     }
-    fn flip_random(&mut self, data: &mut Vec<u8>, datalen: usize) {
-        let mut rng = thread_rng();
+    fn flip_random<R: Rng>(&mut self, data: &mut Vec<u8>, datalen: usize, rng: &mut R) {
         let offset = rng.gen_range(0, datalen * 8);
         //Flip bit
         data[offset >> 3] ^= 128 >> (offset & 7);
     }
-    fn set_random_interest8(&mut self, data: &mut Vec<u8>, datalen: usize) {
-        let mut rng = thread_rng();
+    fn set_random_interest8<R: Rng>(&mut self, data: &mut Vec<u8>, datalen: usize, rng: &mut R) {
         let offset = rng.gen_range(0, datalen);
         let value = rng.gen_range(0, INTERESTING_8_BIT.len() as u8);
         //Overwrite value
         data[offset] = INTERESTING_8_BIT[value as usize];
     }
-    fn set_random_interest16(&mut self, data: &mut Vec<u8>, datalen: usize) {
-        let mut rng = thread_rng();
+    fn set_random_interest16<R: Rng>(&mut self, data: &mut Vec<u8>, datalen: usize, rng: &mut R) {
         let offset = rng.gen_range(0, datalen - 1);
         let value = rng.gen_range(0, INTERESTING_16_BIT.len() as u8);
         let endianess = rng.gen();
@@ -1412,8 +1407,7 @@ impl MutationState {
             }
         }
     }
-    fn set_random_interest32(&mut self, data: &mut Vec<u8>, datalen: usize) {
-        let mut rng = thread_rng();
+    fn set_random_interest32<R: Rng>(&mut self, data: &mut Vec<u8>, datalen: usize, rng: &mut R) {
         let offset = rng.gen_range(0, datalen - 3);
         let value = rng.gen_range(0, INTERESTING_32_BIT.len() as u8);
         let endianess = rng.gen();
@@ -1433,22 +1427,19 @@ impl MutationState {
             }
         }
     }
-    fn subtract_random_arith8(&mut self, data: &mut Vec<u8>, datalen: usize) {
-        let mut rng = thread_rng();
+    fn subtract_random_arith8<R: Rng>(&mut self, data: &mut Vec<u8>, datalen: usize, rng: &mut R) {
         let offset = rng.gen_range(0, datalen);
         let value = rng.gen_range(-(AFL_ARITH_MAX as i8), -1);
         //Add value
         data[offset] = data[offset].wrapping_add(value as u8);
     }
-    fn add_random_arith8(&mut self, data: &mut Vec<u8>, datalen: usize) {
-        let mut rng = thread_rng();
+    fn add_random_arith8<R: Rng>(&mut self, data: &mut Vec<u8>, datalen: usize, rng: &mut R) {
         let offset = rng.gen_range(0, datalen);
         let value = rng.gen_range(1, AFL_ARITH_MAX as u8);
         //Add value
         data[offset] = data[offset].wrapping_add(value as u8);
     }
-    fn subtract_random_arith16(&mut self, data: &mut Vec<u8>, datalen: usize) {
-        let mut rng = thread_rng();
+    fn subtract_random_arith16<R: Rng>(&mut self, data: &mut Vec<u8>, datalen: usize, rng: &mut R) {
         let offset = rng.gen_range(0, datalen - 1);
         let value = rng.gen_range(-(AFL_ARITH_MAX as i16), -1);
         let endianess = rng.gen();
@@ -1485,8 +1476,7 @@ impl MutationState {
             }
         }
     }
-    fn add_random_arith16(&mut self, data: &mut Vec<u8>, datalen: usize) {
-        let mut rng = thread_rng();
+    fn add_random_arith16<R: Rng>(&mut self, data: &mut Vec<u8>, datalen: usize, rng: &mut R) {
         let offset = rng.gen_range(0, datalen - 1);
         let value = rng.gen_range(1, AFL_ARITH_MAX as i16);
         let endianess = rng.gen();
@@ -1522,8 +1512,7 @@ impl MutationState {
             }
         }
     }
-    fn subtract_random_arith32(&mut self, data: &mut Vec<u8>, datalen: usize) {
-        let mut rng = thread_rng();
+    fn subtract_random_arith32<R: Rng>(&mut self, data: &mut Vec<u8>, datalen: usize, rng: &mut R) {
         let offset = rng.gen_range(0, datalen - 3);
         let value = rng.gen_range(-(AFL_ARITH_MAX as i32), -1);
         let endianess = rng.gen();
@@ -1559,8 +1548,7 @@ impl MutationState {
             }
         }
     }
-    fn add_random_arith32(&mut self, data: &mut Vec<u8>, datalen: usize) {
-        let mut rng = thread_rng();
+    fn add_random_arith32<R: Rng>(&mut self, data: &mut Vec<u8>, datalen: usize, rng: &mut R) {
         let offset = rng.gen_range(0, datalen - 3);
         let value = rng.gen_range(1, AFL_ARITH_MAX as i32);
         let endianess = rng.gen();
@@ -1596,9 +1584,8 @@ impl MutationState {
             }
         }
     }
-    fn delete_random_bytes(&mut self, data: &mut Vec<u8>, datalen: usize) {
+    fn delete_random_bytes<R: Rng>(&mut self, data: &mut Vec<u8>, datalen: usize, rng: &mut R) {
         if datalen > 4 {
-            let mut rng = thread_rng();
             let offset = rng.gen_range(0, datalen - 1);
             let delete_len = rng.gen_range(
                 0,
@@ -1609,8 +1596,7 @@ impl MutationState {
             }
         }
     }
-    fn insert_random_bytes(&mut self, data: &mut Vec<u8>, datalen: usize) {
-        let mut rng = thread_rng();
+    fn insert_random_bytes<R: Rng>(&mut self, data: &mut Vec<u8>, datalen: usize, rng: &mut R) {
         let offset = rng.gen_range(0, datalen - 1);
         let insert_len = rng.gen_range(1, cmp::min(AFL_HAVOC_BLK_LARGE, datalen - offset));
         let not_clone = rng.gen_weighted_bool(4);
@@ -1634,8 +1620,7 @@ impl MutationState {
             }
         }
     }
-    fn overwrite_random_bytes(&mut self, data: &mut Vec<u8>, datalen: usize) {
-        let mut rng = thread_rng();
+    fn overwrite_random_bytes<R: Rng>(&mut self, data: &mut Vec<u8>, datalen: usize, rng: &mut R) {
         let offset = rng.gen_range(0, datalen - 1);
         let overwrite_len = rng.gen_range(1, cmp::min(AFL_HAVOC_BLK_LARGE, datalen - offset));
         let not_clone = rng.gen_weighted_bool(4);
@@ -1659,6 +1644,7 @@ impl MutationState {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rand::{thread_rng, SeedableRng, StdRng};
 
     //Test for Flip1
     #[test]
@@ -2373,6 +2359,7 @@ mod tests {
         };
         //my_mut_state.start_havoc();
         //let mut changed_bits: Range<usize> = 0..0;
+        let mut rng = thread_rng();
         for _ in 0..10000 {
             let mut v = vec![
                 0b00001111u8,
@@ -2381,7 +2368,7 @@ mod tests {
                 0b11111100u8,
                 0b11110000u8,
             ];
-            my_mut_state.havoc(&mut v);
+            my_mut_state.havoc(&mut v, &mut rng);
             //print!("v_len: {}, v: ", v.len());
             //for x in 0..v.len() {
             //    print!("{} ", v[x]);
@@ -2389,6 +2376,24 @@ mod tests {
             //print!("\n");
         }
     }
+    //The same seed gives the same mutations
+    #[test]
+    fn test_havoc_seeded() {
+        let mut my_mut_state = MutationState {
+            stage: Stage::Finished,
+            effector_map: vec![1; 64],
+            options: Options { change_size: true },
+        };
+        let mut rng1 = StdRng::from_seed(&[42usize][..]);
+        let mut rng2 = StdRng::from_seed(&[42usize][..]);
+        for _ in 0..1000 {
+            let mut v1 = (0..64).collect::<Vec<u8>>();
+            let mut v2 = v1.clone();
+            my_mut_state.havoc(&mut v1, &mut rng1);
+            my_mut_state.havoc(&mut v2, &mut rng2);
+            assert_eq!(v1, v2);
+        }
+    }
     //Test Constructor
     #[test]
     fn test_constructor_bitflip() {
@@ -0,0 +1,100 @@
+//AFL++ custom mutator (AFL_CUSTOM_MUTATOR_LIBRARY) that mutates the inputs on their Nautilus trees.
+//The grammar is read from NAUTILUS_GRAMMAR (.json or .g4), NAUTILUS_MAX_TREE_SIZE overrides the
+//default maximum tree size of 1000.
+extern crate forksrv;
+extern crate grammartec;
+extern crate libc;
+extern crate rand;
+extern crate ron;
+
+pub mod state;
+
+use libc::{c_char, c_uint, c_void, size_t};
+use std::env;
+use std::ffi::CStr;
+use std::panic;
+use std::ptr;
+use std::slice;
+
+use grammartec::rng;
+use state::{load_grammar, MutatorState};
+
+const DEFAULT_MAX_TREE_SIZE: usize = 1000;
+
+//The grammartec rng of the calling thread is seeded with the seed of afl-fuzz (fixed with -s)
+#[no_mangle]
+pub extern "C" fn afl_custom_init(_afl: *mut c_void, seed: c_uint) -> *mut c_void {
+    rng::seed(seed as u64);
+    let grammar_path = match env::var("NAUTILUS_GRAMMAR") {
+        Ok(path) => path,
+        Err(_) => {
+            eprintln!("NAUTILUS_GRAMMAR is not set");
+            return ptr::null_mut();
+        }
+    };
+    let max_tree_size = env::var("NAUTILUS_MAX_TREE_SIZE")
+        .ok()
+        .and_then(|size| size.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_MAX_TREE_SIZE);
+    //A panic must not unwind into afl-fuzz, a NULL return makes it abort with an error instead
+    let res = panic::catch_unwind(|| {
+        return load_grammar(&grammar_path, max_tree_size)
+            .map(|(ctx, hash)| MutatorState::new(ctx, hash));
+    });
+    return match res {
+        Ok(Ok(state)) => Box::into_raw(Box::new(state)) as *mut c_void,
+        Ok(Err(e)) => {
+            eprintln!("{}", e);
+            ptr::null_mut()
+        }
+        Err(_) => ptr::null_mut(),
+    };
+}
+
+//Returns the size of the mutated input, *out_buf points into the state until the next call.
+//add_buf is not used, splicing takes its chunks from all queue entries seen so far. Returns 0,
+//which makes AFL skip the input, if the mutation panicked.
+//Safety: data is the state returned by afl_custom_init, buf points to buf_size readable bytes and
+//out_buf is writable, as guaranteed by afl-fuzz.
+#[no_mangle]
+pub unsafe extern "C" fn afl_custom_fuzz(
+    data: *mut c_void,
+    buf: *mut u8,
+    buf_size: size_t,
+    out_buf: *mut *mut u8,
+    _add_buf: *mut u8,
+    _add_buf_size: size_t,
+    max_size: size_t,
+) -> size_t {
+    let state = &mut *(data as *mut MutatorState);
+    let input = slice::from_raw_parts(buf, buf_size);
+    let res = panic::catch_unwind(panic::AssertUnwindSafe(|| state.fuzz(input, max_size).len()));
+    *out_buf = state.output.as_mut_ptr();
+    return res.unwrap_or(0);
+}
+
+//Caches the tree of a new queue entry. Returns 0 since the file is never modified.
+//Safety: data is the state returned by afl_custom_init and filename_new_queue is a NUL terminated
+//string, as guaranteed by afl-fuzz.
+#[no_mangle]
+pub unsafe extern "C" fn afl_custom_queue_new_entry(
+    data: *mut c_void,
+    filename_new_queue: *const c_char,
+    _filename_orig_queue: *const c_char,
+) -> u8 {
+    let state = &mut *(data as *mut MutatorState);
+    let path = CStr::from_ptr(filename_new_queue);
+    if let Ok(path) = path.to_str() {
+        //Only the cache is missing the entry if this panics
+        let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| state.add_queue_entry(path)));
+    }
+    return 0;
+}
+
+//Safety: data is NULL or the state returned by afl_custom_init, afl-fuzz doesn't use it afterwards
+#[no_mangle]
+pub unsafe extern "C" fn afl_custom_deinit(data: *mut c_void) {
+    if !data.is_null() {
+        drop(Box::from_raw(data as *mut MutatorState));
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::path::Path;
+
+use forksrv::error::SubprocessError;
+use grammartec::chunkstore::ChunkStore;
+use grammartec::context::Context;
+use grammartec::loader::{self, LoadError, LoadOptions};
+use grammartec::mutator::Mutator;
+use grammartec::newtypes::NodeID;
+use grammartec::parser::Parser;
+use grammartec::rng;
+use grammartec::sync::{afl_tree_path_of_copy, SyncEntry};
+use grammartec::tree::{Tree, TreeLike, TreeMutation};
+use rand::Rng;
+use ron;
+
+//How often a mutation is retried if it didn't change the tree or the output was larger than max_size
+const MAX_MUTATION_ATTEMPTS: usize = 16;
+//AFL doesn't tell us when it removes an entry, the oldest trees are dropped once the cache is full.
+//A dropped entry is parsed again the next time it is fuzzed.
+const MAX_CACHED_ENTRIES: usize = 4096;
+
+struct Entry {
+    tree: Tree,
+    recursions: Option<Vec<(NodeID, NodeID)>>,
+}
+
+pub struct MutatorState {
+    pub ctx: Context,
+    pub hash_of_grammar: u64,
+    mutator: Mutator,
+    //Chunks of the queue entries AFL told us about, entries parsed again after they were dropped
+    //from the cache are not added twice
+    cks: ChunkStore,
+    //Tree of every input AFL asked us to mutate or told us about, by the hash of its bytes
+    entries: HashMap<u64, Entry>,
+    //Hashes of the cached entries, oldest first
+    entry_order: VecDeque<u64>,
+    //Tree of the last output, AFL calls queue_new_entry with its bytes if it was interesting
+    last_output: Option<(u64, Tree)>,
+    //AFL reads the output from this buffer, it must stay valid until the next call to fuzz
+    pub output: Vec<u8>,
+}
+
+//Loads a JSON or ANTLR (.g4) grammar the same way the fuzzer does and returns the context and
+//the hash of the grammar file
+pub fn load_grammar(grammar_path: &str, max_tree_size: usize) -> Result<(Context, u64), LoadError> {
+    let hash = loader::source_for_path(grammar_path)?.hash()?;
+    let ctx = loader::load_path(grammar_path, &LoadOptions::new(max_tree_size))?;
+    return Ok((ctx, hash));
+}
+
+fn hash_of_bytes(bytes: &[u8]) -> u64 {
+    let mut s = DefaultHasher::new();
+    bytes.hash(&mut s);
+    return s.finish();
+}
+
+impl MutatorState {
+    pub fn new(ctx: Context, hash_of_grammar: u64) -> Self {
+        let mutator = Mutator::new(&ctx);
+        return MutatorState {
+            ctx,
+            hash_of_grammar,
+            mutator,
+            cks: ChunkStore::new(),
+            entries: HashMap::new(),
+            entry_order: VecDeque::new(),
+            last_output: None,
+            output: vec![],
+        };
+    }
+
+    //Called for every new queue entry. The tree of an entry afl-fuzz imported from gramophone
+    //(--afl-sync) is read from the .tree file gramophone exported with it, the tree of an entry
+    //found by our own mutations is taken from the last output, all others are parsed.
+    //Entries that don't match the grammar get no tree, fuzz generates a new one for them.
+    pub fn add_queue_entry(&mut self, path: &str) {
+        let mut bytes = vec![];
+        if File::open(path).and_then(|mut f| f.read_to_end(&mut bytes)).is_err() {
+            return;
+        }
+        let hash = hash_of_bytes(&bytes);
+        if self.entries.contains_key(&hash) {
+            return;
+        }
+        let mut content = String::new();
+        let sidecar = afl_tree_path_of_copy(Path::new(path))
+            .and_then(|tree_path| File::open(tree_path).and_then(|mut f| f.read_to_string(&mut content)).ok())
+            .and_then(|_| ron::de::from_str::<SyncEntry>(&content).ok())
+            .filter(|entry| entry.hash_of_grammar == self.hash_of_grammar)
+            .map(|entry| entry.tree);
+        let tree = match sidecar {
+            Some(tree) => Some(tree),
+            None => match self.last_output.take() {
+                Some((output_hash, tree)) if output_hash == hash => Some(tree),
+                _ => Parser::new(&self.ctx)
+                    .parse(self.ctx.nt_id("START"), &bytes)
+                    .ok(),
+            },
+        };
+        if let Some(tree) = tree {
+            self.cks.add_tree(tree.clone(), &self.ctx);
+            self.add_tree(hash, tree);
+        }
+    }
+
+    fn add_tree(&mut self, hash: u64, tree: Tree) {
+        let recursions = tree.has_recursions(&self.ctx);
+        if self.entries.len() >= MAX_CACHED_ENTRIES {
+            if let Some(oldest) = self.entry_order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(hash, Entry { tree, recursions });
+        self.entry_order.push_back(hash);
+    }
+
+    //Tree for the bytes of an input: cached, parsed or, if the input doesn't match the grammar,
+    //newly generated so that AFL still gets valid inputs from this entry
+    fn entry_for(&mut self, bytes: &[u8]) -> u64 {
+        let hash = hash_of_bytes(bytes);
+        if !self.entries.contains_key(&hash) {
+            let tree = match Parser::new(&self.ctx).parse(self.ctx.nt_id("START"), bytes) {
+                Ok(tree) => tree,
+                Err(_) => {
+                    let nt = self.ctx.nt_id("START");
+                    let len = self.ctx.get_random_len_for_nt(&nt);
+                    self.ctx.generate_tree_from_nt(nt, len)
+                }
+            };
+            self.add_tree(hash, tree);
+        }
+        return hash;
+    }
+
+    //Applies one random tree mutation (random subtree, splice or recursion) to the tree of bytes and
+    //stores the unparsed result in self.output. The output is cut to max_size if no mutation
+    //produced a small enough input.
+    pub fn fuzz(&mut self, bytes: &[u8], max_size: usize) -> &[u8] {
+        let hash = self.entry_for(bytes);
+        let mut mutated: Option<(Vec<u8>, Tree)> = None;
+        for _ in 0..MAX_MUTATION_ATTEMPTS {
+            let mut result = None;
+            {
+                let entry = &self.entries[&hash];
+                let mut tester = |m: &TreeMutation, ctx: &Context| -> Result<(), SubprocessError> {
+                    result = Some((m.unparse_to_vec(ctx), m.to_tree(ctx)));
+                    return Ok(());
+                };
+                let res = match (rng::rng().gen_range(0, 3), &entry.recursions) {
+                    (0, &Some(ref recursions)) => self.mutator.mut_random_recursion(
+                        &entry.tree,
+                        recursions,
+                        &self.ctx,
+                        &mut tester,
+                    ),
+                    (1, _) if self.cks.trees() > 0 => {
+                        self.mutator
+                            .mut_splice(&entry.tree, &self.ctx, &self.cks, &mut tester)
+                    }
+                    _ => self.mutator.mut_random(&entry.tree, &self.ctx, &mut tester),
+                };
+                res.expect("RAND_3376520863");
+            }
+            if let Some((output, tree)) = result {
+                let fits = output.len() <= max_size;
+                mutated = Some((output, tree));
+                if fits {
+                    break;
+                }
+            }
+        }
+        let (mut output, tree) = mutated.unwrap_or_else(|| {
+            let tree = self.entries[&hash].tree.clone();
+            return (tree.unparse_to_vec(&self.ctx), tree);
+        });
+        if output.len() > max_size {
+            output.truncate(max_size);
+            self.last_output = None;
+        } else {
+            self.last_output = Some((hash_of_bytes(&output), tree));
+        }
+        self.output = output;
+        return &self.output;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use grammartec::parser::Parser;
+    use grammartec::rng;
+    use grammartec::sync::{afl_tree_path, SyncEntry};
+    use grammartec::tree::TreeLike;
+    use ron;
+    use state::{hash_of_bytes, load_grammar, MutatorState, MAX_CACHED_ENTRIES};
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::Path;
+    use std::process;
+
+    #[test]
+    fn check_fuzz_and_queue_entry() {
+        rng::seed(0);
+        let dir = env::temp_dir();
+        let grammar_path = format!("{}/aflpp_mutator_{}.json", dir.display(), process::id());
+        File::create(&grammar_path)
+            .and_then(|mut f| f.write_all(br#"[["E", "({E}+{E})"], ["E", "{N}"], ["N", "1"], ["N", "2"]]"#))
+            .expect("RAND_2916016240");
+        let (ctx, hash) = load_grammar(&grammar_path, 100).expect("RAND_2741130986");
+        let mut state = MutatorState::new(ctx, hash);
+        //Inputs that don't match the grammar are replaced by a generated tree
+        for input in [&b"(1+2)"[..], &b"not in the grammar"[..]].iter() {
+            for _ in 0..50 {
+                let output = state.fuzz(input, 1000).to_vec();
+                assert!(Parser::new(&state.ctx)
+                    .parse(state.ctx.nt_id("START"), &output)
+                    .is_ok());
+            }
+        }
+        //A mutation can give an input that is already cached, that one would not be a new entry
+        let output = loop {
+            let output = state.fuzz(b"(1+2)", 1000).to_vec();
+            if !state.entries.contains_key(&hash_of_bytes(&output)) {
+                break output;
+            }
+        };
+        let entry_path = format!("{}/aflpp_mutator_{}.entry", dir.display(), process::id());
+        File::create(&entry_path)
+            .and_then(|mut f| f.write_all(&output))
+            .expect("RAND_1483470417");
+        let entries = state.entries.len();
+        state.add_queue_entry(&entry_path);
+        assert!(state.last_output.is_none());
+        assert_eq!(state.entries.len(), entries + 1);
+        assert_eq!(state.fuzz(b"", 0).len(), 0);
+    }
+
+    #[test]
+    fn check_entries_are_bounded() {
+        let dir = env::temp_dir();
+        let grammar_path = format!("{}/aflpp_mutator_bounded_{}.json", dir.display(), process::id());
+        File::create(&grammar_path)
+            .and_then(|mut f| f.write_all(br#"[["N", "1"], ["N", "2"]]"#))
+            .expect("RAND_3011467429");
+        let (ctx, hash) = load_grammar(&grammar_path, 100).expect("RAND_3519572260");
+        let mut state = MutatorState::new(ctx, hash);
+        let tree = Parser::new(&state.ctx)
+            .parse(state.ctx.nt_id("START"), b"1")
+            .expect("RAND_1862045377");
+        for i in 0..MAX_CACHED_ENTRIES as u64 + 10 {
+            state.add_tree(i, tree.clone());
+        }
+        assert_eq!(state.entries.len(), MAX_CACHED_ENTRIES);
+        assert!(!state.entries.contains_key(&9));
+        assert!(state.entries.contains_key(&10));
+        assert_eq!(state.cks.trees(), 0);
+    }
+
+    #[test]
+    fn check_tree_of_synced_entry() {
+        let dir = format!("{}/aflpp_mutator_sync_{}", env::temp_dir().display(), process::id());
+        let grammar_path = format!("{}/grammar.json", dir);
+        fs::create_dir_all(format!("{}/afl1/queue", dir)).expect("RAND_2589930377");
+        fs::create_dir_all(format!("{}/gramophone/.trees", dir)).expect("RAND_3849561170");
+        File::create(&grammar_path)
+            .and_then(|mut f| f.write_all(br#"[["N", "1"], ["N", "{N}{N}"]]"#))
+            .expect("RAND_1080735318");
+        let (ctx, hash) = load_grammar(&grammar_path, 100).expect("RAND_2218913411");
+        let mut state = MutatorState::new(ctx, hash);
+        //The exported tree is used even if the bytes parse to a different one
+        let tree = Parser::new(&state.ctx)
+            .parse(state.ctx.nt_id("START"), b"11")
+            .expect("RAND_3567211346");
+        let entry = SyncEntry { hash_of_grammar: hash, tree: tree.clone() };
+        File::create(afl_tree_path(Path::new(&dir), "gramophone", 7))
+            .and_then(|mut f| f.write_all(ron::ser::to_string(&entry).expect("RAND_2143305731").as_bytes()))
+            .expect("RAND_1356542394");
+        let copy_path = format!("{}/afl1/queue/id:000042,sync:gramophone,src:000007", dir);
+        File::create(&copy_path)
+            .and_then(|mut f| f.write_all(b"1"))
+            .expect("RAND_4198862413");
+        state.add_queue_entry(&copy_path);
+        let cached = &state.entries[&hash_of_bytes(b"1")].tree;
+        assert_eq!(cached.unparse_to_vec(&state.ctx), b"11".to_vec());
+        fs::remove_dir_all(&dir).expect("RAND_2703911658");
+    }
+}
@@ -21,6 +21,10 @@ uint8_t* __rofl_area_ptr = __rofl_pre_init_bitmap;
 
 __thread uint32_t __rofl_prev_loc;
 
+//the fuzzer reads the pid of each child and its status from this pipe, it uses the pid
+//to kill the child once its timeout expires
+int __rofl_st_fd = -1;
+
 void __rofl_init(){
   memset(__rofl_area_ptr, 0, ROFL_MAP_SIZE);
   __rofl_prev_loc = 0;
@@ -59,6 +63,9 @@ void __rofl_forkserver(){
     fsync(0);
     if(getenv("ROFL_SHM_FD")){
       printf("running forkserver\n");
+      if(getenv("ROFL_ST_FD")){
+        __rofl_st_fd = atoi(getenv("ROFL_ST_FD"));
+      }
       while(1){
         uint8_t buffer;
         int pid;
@@ -68,22 +75,27 @@ void __rofl_forkserver(){
         //fork the next running instance
         if((pid = fork()) < 0) {
             fprintf(stderr, "Could not fork... %s\n", strerror(errno));
+            if(__rofl_st_fd >= 0){
+              write(__rofl_st_fd, &pid, 4);
+            }
         } else if(pid == 0) {
-             struct itimerval timer;
-             //trigger timeout signal after timer expires (20 ms)
-             timer.it_value.tv_sec     = 0;
-             timer.it_value.tv_usec    = 70000;
-             timer.it_interval.tv_sec  = 0;
-             timer.it_interval.tv_usec = 0;
-             setitimer (ITIMER_VIRTUAL, &timer, NULL);
+            if(__rofl_st_fd >= 0){
+              close(__rofl_st_fd);
+            }
             __rofl_reset();
             return;
         } else {
           /* Elternprozess */
           int status;
+          if(__rofl_st_fd >= 0){
+            write(__rofl_st_fd, &pid, 4);
+          }
           waitpid(pid, &status, 0);
           rofl_feedback_data->magic = 0x5a5a55464c464f52; //"ROFLFUZZ"
           rofl_feedback_data->status = status;
+          if(__rofl_st_fd >= 0){
+            write(__rofl_st_fd, &status, 4);
+          }
         }
       }
     }
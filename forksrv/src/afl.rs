@@ -0,0 +1,280 @@
+//Backend for targets built with afl-gcc/afl-clang(-fast) or AFL++. It speaks the standard AFL
+//forkserver protocol: the target reads 4 byte commands from fd 198 and reports the pid of every
+//child and its waitpid status on fd 199, the coverage map is a SysV shared memory segment whose id
+//is passed in __AFL_SHM_ID.
+use libc;
+use nix::fcntl::*;
+use nix::sys::signal::*;
+use nix::sys::wait::*;
+use nix::unistd::*;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::process;
+use std::ptr;
+use std::slice;
+use std::time::Duration;
+use tempfile;
+
+use error::{descr_err, SpawnError, SubprocessError};
+use exitreason::ExitReason;
+use {get_filename, read_u32, wait_readable};
+
+//The map size afl-clang and afl-gcc are compiled with
+pub const MAP_SIZE: usize = 1 << 16;
+
+const FORKSRV_FD: RawFd = 198;
+
+//Options of the extended hello sent by AFL++ forkservers
+const FS_OPT_ENABLED: u32 = 0x80000001;
+const FS_OPT_MAPSIZE: u32 = 0x40000000;
+const FS_OPT_SHDMEM_FUZZ: u32 = 0x01000000;
+const FS_OPT_AUTODICT: u32 = 0x10000000;
+
+fn fs_opt_get_mapsize(status: u32) -> usize {
+    return (((status & 0x00fffffe) >> 1) + 1) as usize;
+}
+
+#[derive(Debug)]
+pub struct AflForkServer {
+    child_pid: i32,
+    shm_id: i32,
+    trace_bits: *mut u8,
+    ctl_pipe: File,
+    st_pipe: File,
+    //Persistent mode targets must not resume a child that was killed
+    last_run_killed: bool,
+    inp_file: tempfile::NamedTempFile,
+    //stdout and stderr are opened once and inherited by every run, regular files are truncated
+    //before each run so they only hold the output of the last one
+    out_file: File,
+    err_file: File,
+    pub out_path: String,
+    pub err_path: String,
+}
+
+impl AflForkServer {
+    pub fn new(
+        path: &String,
+        args: &Vec<String>,
+        out_path: String,
+        err_path: String,
+    ) -> Result<Self, SubprocessError> {
+        let inp_file = tempfile::NamedTempFile::new()?;
+        let inp_file_path = inp_file
+            .path()
+            .to_str()
+            .expect("temp path should be unicode!")
+            .to_string();
+        let out_file = OpenOptions::new().append(true).create(true).open(&out_path)?;
+        let err_file = OpenOptions::new().append(true).create(true).open(&err_path)?;
+        //O_CLOEXEC keeps forkservers started by other threads from holding on to our pipes
+        let (ctl_read, ctl_write) = pipe2(O_CLOEXEC)?;
+        let (st_read, st_write) = pipe2(O_CLOEXEC)?;
+        let (shm_id, trace_bits) = AflForkServer::create_shm()?;
+        let child = match fork().expect("fork failed") {
+            ForkResult::Parent { child } => child,
+            ForkResult::Child => {
+                {
+                    //Same as for the ROFL forkserver: default signal mask and own process group
+                    SigSet::empty()
+                        .thread_set_mask()
+                        .expect("RAND_3190526571");
+                    setpgid(0, 0).expect("RAND_1475632957");
+                    let res = AflForkServer::run_process(
+                        path,
+                        args,
+                        shm_id,
+                        (ctl_read, st_write),
+                        &inp_file.as_file(),
+                        &out_file,
+                        &err_file,
+                        &inp_file_path,
+                    );
+                    let err = res.err();
+                    print!("Executing Target failed {:?}\n", err)
+                }
+                process::exit(0x0f00);
+            }
+        };
+        close(ctl_read)?;
+        close(st_write)?;
+        let mut srv = AflForkServer {
+            child_pid: child,
+            shm_id,
+            trace_bits,
+            ctl_pipe: unsafe { File::from_raw_fd(ctl_write) },
+            st_pipe: unsafe { File::from_raw_fd(st_read) },
+            last_run_killed: false,
+            inp_file,
+            out_file,
+            err_file,
+            out_path,
+            err_path,
+        };
+        srv.handshake()?;
+        return Ok(srv);
+    }
+
+    pub fn get_bitmap<'a>(&'a self) -> &'a [u8] {
+        unsafe { return slice::from_raw_parts(self.trace_bits, MAP_SIZE) }
+    }
+
+    pub fn get_bitmap_mut<'a>(&'a mut self) -> &'a mut [u8] {
+        unsafe { return slice::from_raw_parts_mut(self.trace_bits, MAP_SIZE) }
+    }
+
+    //Runs the input and kills the child if it takes longer than timeout (wall clock)
+    pub fn run_on<I: AsRef<[u8]>>(
+        &mut self,
+        data: &I,
+        timeout: Duration,
+    ) -> Result<ExitReason, SubprocessError> {
+        //Unlike the ROFL runtime, AFL targets expect the fuzzer to clear the map
+        for elem in self.get_bitmap_mut().iter_mut() {
+            *elem = 0;
+        }
+        let mut inp_file = self.inp_file.as_file();
+        inp_file.set_len(0)?;
+        inp_file.seek(SeekFrom::Start(0))?;
+        inp_file.write_all(data.as_ref())?;
+        inp_file.seek(SeekFrom::Start(0))?;
+        for file in [&self.out_file, &self.err_file].iter() {
+            if file.metadata()?.is_file() {
+                file.set_len(0)?;
+            }
+        }
+
+        //The command is the "was killed" flag of the previous run
+        let was_killed = self.last_run_killed as u32;
+        self.ctl_pipe.write_all(&was_killed.to_ne_bytes())?;
+        if !wait_readable(self.st_pipe.as_raw_fd(), timeout)? {
+            return descr_err("AFL forkserver did not report a child pid");
+        }
+        let pid = read_u32(&mut self.st_pipe)? as i32;
+        if pid <= 0 {
+            return descr_err("AFL forkserver failed to fork");
+        }
+        self.last_run_killed = !wait_readable(self.st_pipe.as_raw_fd(), timeout)?;
+        if self.last_run_killed {
+            //The forkserver still reports the status of the killed child
+            let _ = kill(pid, SIGKILL);
+        }
+        let status = read_u32(&mut self.st_pipe)? as i32;
+        if self.last_run_killed {
+            return Ok(ExitReason::Timeouted);
+        }
+        //A stopped child is a persistent mode target that finished one iteration
+        if unsafe { libc::WIFSTOPPED(status) } {
+            return Ok(ExitReason::Normal(0));
+        }
+        return Ok(ExitReason::from_int(status));
+    }
+
+    //private functions
+
+    fn handshake(&mut self) -> Result<(), SubprocessError> {
+        let hello = match read_u32(&mut self.st_pipe) {
+            Ok(hello) => hello,
+            Err(_) => {
+                let res = waitpid(self.child_pid, None);
+                return descr_err(&format!(
+                    "no AFL forkserver hello, is the target instrumented with AFL? ({:?})",
+                    res
+                ));
+            }
+        };
+        if (hello & FS_OPT_ENABLED) != FS_OPT_ENABLED {
+            return Ok(());
+        }
+        if (hello & FS_OPT_MAPSIZE) == FS_OPT_MAPSIZE && fs_opt_get_mapsize(hello) > MAP_SIZE {
+            return descr_err(&format!(
+                "target needs a map of {} bytes, only {} are supported",
+                fs_opt_get_mapsize(hello),
+                MAP_SIZE
+            ));
+        }
+        //AFL++ waits for an answer if it offers shared memory inputs or an auto dictionary. We
+        //decline the former and read and drop the dictionary.
+        if (hello & (FS_OPT_SHDMEM_FUZZ | FS_OPT_AUTODICT)) != 0 {
+            let reply = FS_OPT_ENABLED | (hello & FS_OPT_AUTODICT);
+            self.ctl_pipe.write_all(&reply.to_ne_bytes())?;
+            if (hello & FS_OPT_AUTODICT) != 0 {
+                let len = read_u32(&mut self.st_pipe)? as usize;
+                let mut dict = vec![0u8; len];
+                self.st_pipe.read_exact(&mut dict)?;
+            }
+        }
+        return Ok(());
+    }
+
+    fn create_shm() -> Result<(i32, *mut u8), SubprocessError> {
+        let shm_id = unsafe {
+            libc::shmget(
+                libc::IPC_PRIVATE,
+                MAP_SIZE,
+                libc::IPC_CREAT | libc::IPC_EXCL | 0o600,
+            )
+        };
+        if shm_id < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let ptr = unsafe { libc::shmat(shm_id, ptr::null(), 0) };
+        if ptr as isize == -1 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::shmctl(shm_id, libc::IPC_RMID, ptr::null_mut()) };
+            return Err(err.into());
+        }
+        return Ok((shm_id, ptr as *mut u8));
+    }
+
+    fn run_process(
+        path: &String,
+        args: &Vec<String>,
+        shm_id: i32,
+        pipes: (RawFd, RawFd),
+        inp_file: &File,
+        out_file: &File,
+        err_file: &File,
+        inp_file_path: &String,
+    ) -> Result<(), SpawnError> {
+        let filename = get_filename(path)?;
+        let cpath = CString::new(path.clone())?;
+        let args_iter = args
+            .iter()
+            .map(|arg| if arg == "@@" { inp_file_path } else { arg });
+        let args_iter = Some(&filename).into_iter().chain(args_iter); //add filename as argv[0]
+        let cargs = args_iter
+            .map(|s| Ok(CString::new(s.clone())?))
+            .collect::<Result<Vec<CString>, SpawnError>>()?; //convert all String args to CStrin args
+        let env = vec![
+            CString::new("LD_BIND_NOW=1").expect("RAND_1841245407"),
+            CString::new(format!("__AFL_SHM_ID={}", shm_id)).expect("RAND_2733094568"),
+            CString::new(format!("AFL_MAP_SIZE={}", MAP_SIZE)).expect("RAND_3561738470"),
+            CString::new("ASAN_OPTIONS=exitcode=223").expect("RAND_1075846212"),
+        ];
+        let (ctl_read, st_write) = pipes;
+        dup2(ctl_read, FORKSRV_FD)?;
+        dup2(st_write, FORKSRV_FD + 1)?;
+        //dup2 clears O_CLOEXEC on the new fds, the original ones are closed by execve
+        dup2(inp_file.as_raw_fd(), 0)?;
+        dup2(out_file.as_raw_fd(), 1)?;
+        dup2(err_file.as_raw_fd(), 2)?;
+        execve(&cpath, &cargs, &env)?;
+        unreachable!()
+    }
+}
+
+impl Drop for AflForkServer {
+    fn drop(&mut self) {
+        if kill(self.child_pid, SIGKILL).is_ok() {
+            let _ = waitpid(self.child_pid, None);
+        }
+        unsafe {
+            libc::shmdt(self.trace_bits as *const libc::c_void);
+            libc::shmctl(self.shm_id, libc::IPC_RMID, ptr::null_mut());
+        }
+    }
+}
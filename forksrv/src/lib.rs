@@ -6,10 +6,13 @@ extern crate quick_error;
 extern crate serde_derive;
 extern crate tempfile;
 
+pub mod afl;
 pub mod error;
 pub mod exitreason;
 
+use exitreason::ExitReason;
 use nix::fcntl::*;
+use nix::poll::*;
 use nix::sys::mman::*;
 use nix::sys::signal::kill;
 use nix::sys::signal::Signal::SIGCONT;
@@ -19,20 +22,67 @@ use nix::sys::wait::*;
 use nix::unistd::*;
 use std::ffi::CString;
 use std::fs::File;
-use std::io::{Seek, SeekFrom, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::mem;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::Path;
 use std::process;
+use std::time::{Duration, Instant};
 
 pub use error::{descr_err, SubprocessError};
 use error::{path_err, SpawnError};
 
+fn get_filename(path: &String) -> Result<String, SpawnError> {
+    let path_obj = Path::new(&path);
+
+    if !path_obj.is_absolute() {
+        return path_err("should be absolute");
+    }
+
+    let name = match path_obj.file_name() {
+        Some(name) => name,
+        _ => return path_err("should be a file"),
+    };
+
+    match name.to_os_string().into_string() {
+        Ok(res) => return Ok(res),
+        _ => return path_err("should be unicode"),
+    }
+}
+
+fn read_u32(pipe: &mut File) -> Result<u32, SubprocessError> {
+    let mut buf = [0u8; 4];
+    pipe.read_exact(&mut buf)?;
+    return Ok(u32::from_ne_bytes(buf));
+}
+
+//Waits until fd becomes readable, returns false if the timeout expired first
+fn wait_readable(fd: RawFd, timeout: Duration) -> Result<bool, SubprocessError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+        let left = deadline - now;
+        let millis = left.as_secs() * 1000 + (left.subsec_nanos() as u64 + 999_999) / 1_000_000;
+        let mut fds = [PollFd::new(fd, POLLIN, EventFlags::empty())];
+        match poll(&mut fds, millis as i32) {
+            Ok(0) => return Ok(false),
+            Ok(_) => return Ok(true),
+            Err(nix::Error::Sys(nix::Errno::EINTR)) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ForkServer<T> {
     child_pid: i32,
     shm_file: File,
     inp_file: tempfile::NamedTempFile,
+    //The runtime reports the pid of every child and its waitpid status here
+    st_pipe: File,
     pub out_path: String,
     pub err_path: String,
     shared_data: *mut T,
@@ -52,19 +102,26 @@ impl<T> ForkServer<T> {
             .to_str()
             .expect("temp path should be unicode!")
             .to_string();
-        let child = ForkServer::<T>::start(
+        //O_CLOEXEC keeps forkservers started by other threads from holding on to the pipe
+        let (st_read, st_write) = pipe2(O_CLOEXEC)?;
+        let res = ForkServer::<T>::start(
             path,
             args,
             &shm_file,
             &inp_file.as_file(),
+            st_write,
             &out_path,
             &err_path,
             &inp_file_path,
-        )?;
+        );
+        close(st_write)?;
+        let st_pipe = unsafe { File::from_raw_fd(st_read) };
+        let child = res?;
         return Ok(ForkServer {
             child_pid: child,
             shm_file,
             inp_file,
+            st_pipe,
             out_path,
             err_path,
             shared_data,
@@ -79,22 +136,45 @@ impl<T> ForkServer<T> {
         unsafe { return &*self.shared_data }
     }
 
-    pub fn run_on<I: AsRef<[u8]>>(&mut self, data: &I) -> Result<(), SubprocessError> {
+    //Runs the input and kills the child if it takes longer than timeout (wall clock)
+    pub fn run_on<I: AsRef<[u8]>>(
+        &mut self,
+        data: &I,
+        timeout: Duration,
+    ) -> Result<ExitReason, SubprocessError> {
         let mut inp_file = self.inp_file.as_file();
         inp_file.set_len(0)?;
         inp_file.seek(SeekFrom::Start(0))?;
-        inp_file.write(data.as_ref())?;
+        inp_file.write_all(data.as_ref())?;
         inp_file.seek(SeekFrom::Start(0))?;
         kill(self.child_pid, SIGCONT)?;
+        //A stuck forkserver or a target built without ROFL_ST_FD support never reports a pid
+        if !wait_readable(self.st_pipe.as_raw_fd(), timeout)? {
+            return descr_err("forkserver did not report a child pid");
+        }
+        let pid = read_u32(&mut self.st_pipe)? as i32;
+        if pid <= 0 {
+            return descr_err("forkserver failed to fork");
+        }
+        let timeouted = !wait_readable(self.st_pipe.as_raw_fd(), timeout)?;
+        if timeouted {
+            //The forkserver still reports the status of the killed child
+            let _ = kill(pid, SIGKILL);
+        }
+        let status = read_u32(&mut self.st_pipe)? as i32;
         let res = waitpid(self.child_pid, Some(WUNTRACED))
             .expect("waitpid failed - this shouldn't happen");
         match res {
             Exited(_, exitcode) => {
                 return descr_err(&format!("Parent died on run with exitcode {}", exitcode))
             }
-            Stopped(_, sig) if sig == SIGSTOP => return Ok(()),
+            Stopped(_, sig) if sig == SIGSTOP => {}
             _ => return descr_err(&format!("Parent died on run {:?}", res)),
         };
+        if timeouted {
+            return Ok(ExitReason::Timeouted);
+        }
+        return Ok(ExitReason::from_int(status));
     }
 
     //private functions
@@ -116,34 +196,17 @@ impl<T> ForkServer<T> {
         return Ok((shm_file, ptr as *mut T));
     }
 
-    fn get_filename(path: &String) -> Result<String, SpawnError> {
-        let path_obj = Path::new(&path);
-
-        if !path_obj.is_absolute() {
-            return path_err("should be absolute");
-        }
-
-        let name = match path_obj.file_name() {
-            Some(name) => name,
-            _ => return path_err("should be a file"),
-        };
-
-        match name.to_os_string().into_string() {
-            Ok(res) => return Ok(res),
-            _ => return path_err("should be unicode"),
-        }
-    }
-
     fn run_process(
         path: &String,
         args: &Vec<String>,
         shm_file: &File,
         inp_file: &File,
+        st_fd: RawFd,
         out_file: &String,
         err_file: &String,
         inp_file_path: &String,
     ) -> Result<(), SpawnError> {
-        let filename = ForkServer::<T>::get_filename(path)?;
+        let filename = get_filename(path)?;
         let cpath = CString::new(path.clone())?;
         let args_iter = args
             .iter()
@@ -158,6 +221,7 @@ impl<T> ForkServer<T> {
             CString::new("LD_BIND_NOW=1").expect("RAND_508190816"),
             CString::new(format!("ROFL_SHM_FD={}", shm_fd)).expect("RAND_3630438482"),
             CString::new(format!("ROFL_INP_FD={}", inp_fd)).expect("RAND_734314699"),
+            CString::new(format!("ROFL_ST_FD={}", st_fd)).expect("RAND_2461290547"),
             CString::new(format!("ROFL_OUT_PATH={}", out_file)).expect("RAND_2015012392"),
             CString::new(format!("ROFL_ERR_PATH={}", err_file)).expect("RAND_3568988286"),
             CString::new("ASAN_OPTIONS=exitcode=223,abort_on_erro=true").expect("RAND_2089158993"),
@@ -170,6 +234,10 @@ impl<T> ForkServer<T> {
             inp_fd,
             F_SETFD(FdFlag::from_bits(0).expect("RAND_1556107492")),
         )?;
+        fcntl(
+            st_fd,
+            F_SETFD(FdFlag::from_bits(0).expect("RAND_3802816215")),
+        )?;
         //use inp_fd instead of the original stdin
         dup2(inp_fd, 0)?;
         execve(&cpath, &cargs, &env)?;
@@ -181,6 +249,7 @@ impl<T> ForkServer<T> {
         args: &Vec<String>,
         shm_file: &File,
         inp_file: &File,
+        st_fd: RawFd,
         out_file: &String,
         err_file: &String,
         inp_file_path: &String,
@@ -208,11 +277,19 @@ impl<T> ForkServer<T> {
             }
             ForkResult::Child => {
                 {
+                    //The fuzzer blocks SIGINT/SIGTERM to handle them in a dedicated thread. The
+                    //target must not inherit that mask, and it gets its own process group so a
+                    //Ctrl-C on the terminal only reaches the fuzzer, which then shuts it down.
+                    SigSet::empty()
+                        .thread_set_mask()
+                        .expect("RAND_1745209383");
+                    setpgid(0, 0).expect("RAND_2882910327");
                     let res = ForkServer::<T>::run_process(
                         path,
                         args,
                         shm_file,
                         inp_file,
+                        st_fd,
                         out_file,
                         err_file,
                         inp_file_path,
@@ -225,3 +302,13 @@ impl<T> ForkServer<T> {
         }
     }
 }
+
+impl<T> Drop for ForkServer<T> {
+    fn drop(&mut self) {
+        //The forkserver sits in its own process group and would outlive us otherwise.
+        //If it already died (which is why forkservers get replaced) kill just fails.
+        if kill(self.child_pid, SIGKILL).is_ok() {
+            let _ = waitpid(self.child_pid, None);
+        }
+    }
+}
@@ -16,6 +16,7 @@ use std::env;
 use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
+use std::time::Duration;
 
 use std::collections::{HashMap, HashSet};
 
@@ -236,6 +237,8 @@ impl fmt::Debug for FeedbackData {
     }
 }
 
+const TIMEOUT_IN_MILLIS: u64 = 70;
+
 struct Fuzzer {
     forksrv: ForkServer<FeedbackData>,
     infosrv: ForkServer<FeedbackData>,
@@ -264,7 +267,7 @@ impl Fuzzer {
 
     pub fn run_on(&mut self, input: &[u8]) -> Result<(), SubprocessError> {
         self.forksrv.get_shared_mut().magic = 0x1337133713371337;
-        self.forksrv.run_on(&input)?;
+        self.forksrv.run_on(&input, Duration::from_millis(TIMEOUT_IN_MILLIS))?;
         if self.forksrv.get_shared().magic != 0x5a5a55464c464f52 {
             return descr_err("Failed to get magic value from subprocess");
         }
@@ -298,7 +301,7 @@ impl Fuzzer {
     }
 
     fn capture_output_on<T: AsRef<[u8]>>(&mut self, data: &T) -> Result<String, SubprocessError> {
-        self.infosrv.run_on(&data)?;
+        self.infosrv.run_on(&data, Duration::from_millis(TIMEOUT_IN_MILLIS))?;
         let mut file = File::open(&self.infosrv.out_path)?;
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
@@ -0,0 +1,214 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use tempfile::NamedTempFile;
+
+use context::Context;
+
+//Binary cache of an initialized context (the tree counts and the probabilities of the samplers),
+//so that the counting of Context::initialize only runs once per grammar and tree size. The file
+//starts with a header; a cache whose header doesn't match the grammar and settings is ignored and
+//overwritten. All numbers are little endian.
+const MAGIC: &[u8; 8] = b"NAUTCTX\0";
+//Has to be increased whenever the layout of the cache or the way the counts are computed changes
+pub const CACHE_VERSION: u32 = 2;
+
+#[derive(Debug, PartialEq)]
+pub struct CacheHeader {
+    pub version: u32,
+    pub hash_of_grammar: u64,
+    pub max_len: u64,
+    pub dumb: bool,
+    //A cache written with lazy samplers only has the samplers that were built before it was written
+    pub lazy_samplers: bool,
+    pub number_of_rules: u64,
+}
+
+impl CacheHeader {
+    pub fn new(ctx: &Context, hash_of_grammar: u64, max_len: usize) -> Self {
+        return CacheHeader {
+            version: CACHE_VERSION,
+            hash_of_grammar,
+            max_len: max_len as u64,
+            dumb: ctx.is_dumb(),
+            lazy_samplers: ctx.has_lazy_samplers(),
+            number_of_rules: ctx.get_num_rules() as u64,
+        };
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_u32::<LittleEndian>(self.version)?;
+        w.write_u64::<LittleEndian>(self.hash_of_grammar)?;
+        w.write_u64::<LittleEndian>(self.max_len)?;
+        w.write_u8(self.dumb as u8)?;
+        w.write_u8(self.lazy_samplers as u8)?;
+        w.write_u64::<LittleEndian>(self.number_of_rules)?;
+        return Ok(());
+    }
+
+    pub fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a context cache"));
+        }
+        return Ok(CacheHeader {
+            version: r.read_u32::<LittleEndian>()?,
+            hash_of_grammar: r.read_u64::<LittleEndian>()?,
+            max_len: r.read_u64::<LittleEndian>()?,
+            dumb: r.read_u8()? != 0,
+            lazy_samplers: r.read_u8()? != 0,
+            number_of_rules: r.read_u64::<LittleEndian>()?,
+        });
+    }
+}
+
+//Initializes ctx like Context::initialize, but takes the counts and samplers from the cache at path
+//if it was created for the same grammar, max_len, mode and lazy_samplers. Otherwise they are computed and the
+//cache is (re)written. A cache that can't be written is reported and skipped.
+pub fn initialize_with_cache(
+    ctx: &mut Context,
+    max_len: usize,
+    verbose: bool,
+    path: &str,
+    hash_of_grammar: u64,
+) {
+    let header = CacheHeader::new(ctx, hash_of_grammar, max_len);
+    match load(ctx, &header, path) {
+        Ok(()) => return,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => {
+            if verbose {
+                println!("Ignoring context cache {}: {}", path, e);
+            }
+        }
+    }
+    ctx.initialize(max_len, verbose);
+    if let Err(e) = save(ctx, &header, path) {
+        eprintln!("Cannot write context cache {}: {}", path, e);
+    }
+}
+
+fn load(ctx: &mut Context, header: &CacheHeader, path: &str) -> io::Result<()> {
+    let mut r = BufReader::new(File::open(path)?);
+    if CacheHeader::read(&mut r)? != *header {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "created for another grammar, max_tree_size, mode or lazy_samplers",
+        ));
+    }
+    ctx.initialize_from_cache(header.max_len as usize, &mut r)?;
+    let mut rest = [0u8; 1];
+    if r.read(&mut rest)? != 0 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "trailing data"));
+    }
+    return Ok(());
+}
+
+//Written to a temporary file with a unique name in the same folder first, so that neither an
+//interrupted write nor another process writing the same cache leaves a broken cache
+fn save(ctx: &Context, header: &CacheHeader, path: &str) -> io::Result<()> {
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let tmp_file = NamedTempFile::new_in(dir)?;
+    {
+        let mut w = BufWriter::new(tmp_file.as_file());
+        header.write(&mut w)?;
+        ctx.write_cache(&mut w)?;
+        w.flush()?;
+    }
+    tmp_file.persist(path).map_err(|e| e.error)?;
+    return Ok(());
+}
+
+pub fn write_len<W: Write>(w: &mut W, len: usize) -> io::Result<()> {
+    return w.write_u64::<LittleEndian>(len as u64);
+}
+
+pub fn read_len<R: Read>(r: &mut R) -> io::Result<usize> {
+    return Ok(r.read_u64::<LittleEndian>()? as usize);
+}
+
+pub fn write_f64s<W: Write>(w: &mut W, values: &[f64]) -> io::Result<()> {
+    write_len(w, values.len())?;
+    for v in values.iter() {
+        w.write_f64::<LittleEndian>(*v)?;
+    }
+    return Ok(());
+}
+
+pub fn read_f64s<R: Read>(r: &mut R) -> io::Result<Vec<f64>> {
+    let len = read_len(r)?;
+    let mut res = Vec::with_capacity(len.min(1 << 16));
+    for _ in 0..len {
+        res.push(r.read_f64::<LittleEndian>()?);
+    }
+    return Ok(res);
+}
+
+#[cfg(test)]
+mod tests {
+    use cache::{initialize_with_cache, CacheHeader};
+    use context::Context;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::process;
+
+    fn context(dumb: bool) -> Context {
+        let mut ctx = Context::with_dump(dumb);
+        ctx.add_rule("START", "{E}");
+        ctx.add_rule("E", "({E}+{E})");
+        ctx.add_rule("E", "({E}*{E})");
+        ctx.add_rule("E", "{N}");
+        ctx.add_rule("N", "1");
+        ctx.add_rule("N", "2");
+        return ctx;
+    }
+
+    fn header(path: &str) -> CacheHeader {
+        let mut f = File::open(path).expect("RAND_3263958093");
+        return CacheHeader::read(&mut f).expect("RAND_1446322908");
+    }
+
+    #[test]
+    fn check_cache() {
+        let path = format!("{}/grammartec_cache_{}.gfc", env::temp_dir().display(), process::id());
+        let _ = fs::remove_file(&path);
+        let mut ctx = context(false);
+        initialize_with_cache(&mut ctx, 30, false, &path, 1);
+        assert_eq!(header(&path), CacheHeader::new(&ctx, 1, 30));
+
+        //Loaded from the cache
+        let mut cached = context(false);
+        initialize_with_cache(&mut cached, 30, false, &path, 1);
+        let nt = cached.nt_id("START");
+        for _ in 0..100 {
+            let len = cached.get_random_len_for_nt(&nt);
+            cached.generate_tree_from_nt(nt, len);
+        }
+
+        //Another grammar, max_len, mode or lazy_samplers invalidates the cache
+        let others = [(2, 30, false, false), (2, 20, false, false), (2, 20, false, true), (2, 20, true, false)];
+        for &(hash, max_len, dumb, lazy) in others.iter() {
+            let mut other = context(dumb);
+            other.set_lazy_samplers(lazy);
+            initialize_with_cache(&mut other, max_len, false, &path, hash);
+            assert_eq!(header(&path), CacheHeader::new(&other, hash, max_len));
+        }
+
+        //Broken caches are replaced
+        File::create(&path)
+            .and_then(|mut f| f.write_all(b"NAUTCTX\0broken"))
+            .expect("RAND_2415069476");
+        let mut ctx = context(false);
+        initialize_with_cache(&mut ctx, 30, false, &path, 1);
+        assert_eq!(header(&path), CacheHeader::new(&ctx, 1, 30));
+        let _ = fs::remove_file(&path);
+    }
+}
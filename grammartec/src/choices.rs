@@ -0,0 +1,129 @@
+use context::Context;
+use newtypes::{NTermID, RuleID};
+
+//Source of the decisions taken while a tree is generated: how the length is split between the
+//children of a rule and which rule is used for every child
+pub trait Choices {
+    //Length for the subtree of rhs[0] if the subtrees of rhs have len nodes in total
+    fn len_for_first_nt(&mut self, ctx: &Context, len: usize, rhs: &Vec<NTermID>) -> usize;
+    //Rule for nt that can be derived within len nodes
+    fn rule_for_nt(&mut self, ctx: &Context, nt: NTermID, len: usize) -> RuleID;
+}
+
+//The random choices of the samplers of the context
+pub struct RandomChoices;
+
+impl Choices for RandomChoices {
+    fn len_for_first_nt(&mut self, ctx: &Context, len: usize, rhs: &Vec<NTermID>) -> usize {
+        return ctx.get_random_len(len, rhs);
+    }
+
+    fn rule_for_nt(&mut self, ctx: &Context, nt: NTermID, len: usize) -> RuleID {
+        return ctx.get_random_rule_for_nt(nt, len);
+    }
+}
+
+//Takes the choices from a byte stream, like arbitrary::Unstructured. Every choice consumes as few
+//bytes as it needs, once the bytes are used up the first option is taken.
+pub struct Unstructured<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Unstructured<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        return Unstructured { data };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.data.is_empty();
+    }
+
+    //Number in 0..n
+    pub fn below(&mut self, n: usize) -> usize {
+        assert!(n > 0);
+        let mut res: u64 = 0;
+        let mut range: u64 = 1;
+        while range < n as u64 && !self.data.is_empty() {
+            res = (res << 8) | self.data[0] as u64;
+            range = range.saturating_mul(256);
+            self.data = &self.data[1..];
+        }
+        return (res % n as u64) as usize;
+    }
+}
+
+impl<'a> Choices for Unstructured<'a> {
+    fn len_for_first_nt(&mut self, ctx: &Context, len: usize, rhs: &Vec<NTermID>) -> usize {
+        return ctx.get_len_with(len, rhs, &mut |n| self.below(n));
+    }
+
+    fn rule_for_nt(&mut self, ctx: &Context, nt: NTermID, len: usize) -> RuleID {
+        let rules = ctx.get_applicable_rules(nt, len);
+        if rules.is_empty() {
+            panic!(
+                "there is no way to derive {} within {} steps",
+                ctx.nt_id_to_s(nt),
+                len
+            );
+        }
+        return rules[self.below(rules.len())];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use choices::Unstructured;
+    use context::Context;
+    use tree::TreeLike;
+
+    #[test]
+    fn check_below() {
+        let mut u = Unstructured::new(&[7, 1, 2, 3]);
+        assert_eq!(u.below(1), 0);
+        assert_eq!(u.below(5), 2);
+        assert_eq!(u.below(1000), 0x0102);
+        assert_eq!(u.below(2), 1);
+        assert!(u.is_empty());
+        assert_eq!(u.below(2), 0);
+    }
+
+    #[test]
+    fn check_generate_from_unstructured() {
+        for dumb in [false, true].iter() {
+            let mut ctx = Context::with_dump(*dumb);
+            ctx.add_rule("E", "({E}+{E})");
+            ctx.add_rule("E", "{N}");
+            ctx.add_rule("N", "1");
+            ctx.add_rule("N", "2");
+            ctx.initialize(20, false);
+            let nt = ctx.nt_id("E");
+            //Without bytes the smallest tree is generated
+            let tree = ctx
+                .generate_tree_from_unstructured(nt, &mut Unstructured::new(&[]))
+                .expect("RAND_2583346190");
+            assert_eq!(tree.unparse_to_vec(&ctx), b"1");
+            let data = (0..64).map(|i| (i * 37) as u8).collect::<Vec<_>>();
+            for i in 0..data.len() {
+                let first = ctx
+                    .generate_tree_from_unstructured(nt, &mut Unstructured::new(&data[i..]))
+                    .expect("RAND_1056432619");
+                let second = ctx
+                    .generate_tree_from_unstructured(nt, &mut Unstructured::new(&data[i..]))
+                    .expect("RAND_3943081275");
+                assert_eq!(first.rules, second.rules);
+                assert!(first.size() <= 20);
+            }
+        }
+    }
+
+    #[test]
+    fn check_generate_from_unstructured_without_lens() {
+        //The lengths are only counted by initialize
+        let mut ctx = Context::new();
+        ctx.add_rule("A", "a");
+        let nt = ctx.nt_id("A");
+        assert!(ctx
+            .generate_tree_from_unstructured(nt, &mut Unstructured::new(&[1, 2, 3]))
+            .is_none());
+    }
+}
@@ -1,11 +1,12 @@
 use std::collections::HashMap;
 // use std::collections::HashSet;
-use rand::{sample, thread_rng};
+use rand::sample;
 use std::sync::atomic::AtomicBool;
 use std::sync::RwLock;
 
 use context::Context;
 use newtypes::{NTermID, NodeID, RuleID};
+use rng;
 use tree::{Tree, TreeLike};
 
 pub struct ChunkStoreWrapper {
@@ -63,7 +64,7 @@ impl ChunkStore {
             vec.iter()
                 .filter(move |&&(tid, nid)| self.trees[tid].get_rule_id(nid) != Some(r))
         });
-        let selected = relevant.and_then(|iter| sample(&mut thread_rng(), iter, 1).pop());
+        let selected = relevant.and_then(|iter| sample(&mut rng::rng(), iter, 1).pop());
         return selected.map(|&(tid, nid)| (&self.trees[tid], nid));
     }
 
@@ -1,33 +1,44 @@
-use num::CheckedAdd;
-use num::{ToPrimitive, Zero};
 use std::cell::RefCell;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::collections::HashSet;
+use std::f64;
+use std::io::{self, ErrorKind, Read, Write};
 
 use loaded_dice::LoadedDiceSampler;
-use rand::{sample, thread_rng, Rng, StdRng};
+use rand::Rng;
+use rayon::prelude::*;
 
+use cache::{read_f64s, read_len, write_f64s, write_len};
+use choices::{Choices, Unstructured};
+use count::LogCount;
+use diagnostic::{self, Diagnostic, Severity, SourceLocation};
 use newtypes::{NTermID, RuleID};
-use rule::Rule;
+use rng::{self, NautilusRng};
+use rule::{Rule, RuleChild};
 use tree::Tree;
 
+//get_random_len picks the split of the length with a random number in 0..SPLIT_RESOLUTION
+const SPLIT_RESOLUTION: usize = 1 << 32;
+
 #[derive(Clone)]
 pub struct Context {
     rules: Vec<Rule>,
     nts_to_rules: HashMap<NTermID, Vec<RuleID>>,
     nt_ids_to_name: HashMap<NTermID, String>,
     names_to_nt_id: HashMap<String, NTermID>,
+    rule_locations: HashMap<RuleID, SourceLocation>,
 
     rules_to_min_size: HashMap<RuleID, usize>,
     nts_to_min_size: HashMap<NTermID, usize>,
-    nts_to_rule_samplers: HashMap<NTermID, Vec<Option<RefCell<LoadedDiceSampler<StdRng>>>>>,
-    nts_to_len_samplers: HashMap<NTermID, RefCell<LoadedDiceSampler<StdRng>>>,
-    nt_and_n_to_count: HashMap<(NTermID, usize), u16>,
-    rhs_and_n_to_count: HashMap<(Vec<NTermID>, usize), u16>,
-    rhs_and_n_to_count_u32: HashMap<(Vec<NTermID>, usize), u32>,
+    //Built by initialize for every nonterminal and length, or on first use with lazy samplers
+    nts_to_rule_samplers: RefCell<HashMap<(NTermID, usize), LoadedDiceSampler<NautilusRng>>>,
+    nts_to_len_samplers: RefCell<HashMap<NTermID, LoadedDiceSampler<NautilusRng>>>,
+    nt_and_n_to_count: HashMap<(NTermID, usize), LogCount>,
+    rhs_and_n_to_count: HashMap<(Vec<NTermID>, usize), LogCount>,
     rule_id_to_possible_lens: HashMap<RuleID, Vec<usize>>,
     max_len: usize,
     dumb: bool,
+    lazy_samplers: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -38,9 +49,8 @@ pub struct SerializableContext {
     names_to_nt_id: HashMap<String, NTermID>,
     rules_to_min_size: HashMap<RuleID, usize>,
     nts_to_min_size: HashMap<NTermID, usize>,
-    nt_and_n_to_count: HashMap<(NTermID, usize), u16>,
-    rhs_and_n_to_count: HashMap<(Vec<NTermID>, usize), u16>,
-    rhs_and_n_to_count_u32: HashMap<(Vec<NTermID>, usize), u32>,
+    nt_and_n_to_count: HashMap<(NTermID, usize), LogCount>,
+    rhs_and_n_to_count: HashMap<(Vec<NTermID>, usize), LogCount>,
     rule_id_to_possible_lens: HashMap<RuleID, Vec<usize>>,
     max_len: usize,
     pub hash_of_original: u64,
@@ -58,28 +68,192 @@ impl Context {
             nts_to_rules: HashMap::new(),
             nt_ids_to_name: HashMap::new(),
             names_to_nt_id: HashMap::new(),
+            rule_locations: HashMap::new(),
             rules_to_min_size: HashMap::new(),
             nts_to_min_size: HashMap::new(),
-            nts_to_rule_samplers: HashMap::new(),
-            nts_to_len_samplers: HashMap::new(),
-            rhs_and_n_to_count_u32: HashMap::new(),
+            nts_to_rule_samplers: RefCell::new(HashMap::new()),
+            nts_to_len_samplers: RefCell::new(HashMap::new()),
             nt_and_n_to_count: HashMap::new(),
             rhs_and_n_to_count: HashMap::new(),
             rule_id_to_possible_lens: HashMap::new(),
             max_len: 0,
             dumb,
+            lazy_samplers: false,
         };
     }
 
+    //With lazy samplers initialize only counts the trees and the samplers for a nonterminal and
+    //length are built the first time they are needed. This saves most of the startup time if only a
+    //few trees are generated.
+    pub fn set_lazy_samplers(&mut self, lazy_samplers: bool) {
+        self.lazy_samplers = lazy_samplers;
+    }
+
+    pub fn has_lazy_samplers(&self) -> bool {
+        return self.lazy_samplers;
+    }
+
     pub fn initialize(&mut self, max_len: usize, verbose: bool) {
+        self.assert_valid(max_len);
         self.calc_min_len();
         self.max_len = max_len + 2;
         if !self.dumb {
-            self.calc_sampler(max_len, verbose);
+            self.calc_counts(verbose);
+            self.calc_samplers();
             self.set_rule_id_to_possible_lengths();
         }
     }
 
+    //Like initialize, but the counts and the probabilities of the samplers are read from r (written
+    //by write_cache for the same grammar and max_len, see cache.rs). On errors the counts and
+    //samplers are left as they were.
+    pub fn initialize_from_cache<R: Read>(&mut self, max_len: usize, r: &mut R) -> io::Result<()> {
+        self.assert_valid(max_len);
+        self.calc_min_len();
+        let max_len = max_len + 2;
+        let num_nts = self.nt_ids_to_name.len();
+        let invalid = |what| io::Error::new(ErrorKind::InvalidData, what);
+        let read_nt = |r: &mut R| -> io::Result<NTermID> {
+            let nt = read_len(r)?;
+            if nt >= num_nts {
+                return Err(invalid("invalid nonterminal"));
+            }
+            return Ok(NTermID::from(nt));
+        };
+
+        let mut nt_and_n_to_count = HashMap::new();
+        for _ in 0..read_len(r)? {
+            let nt = read_nt(r)?;
+            for (len, ln) in read_f64s(r)?.into_iter().enumerate() {
+                if !ln.is_nan() {
+                    nt_and_n_to_count.insert((nt, len), LogCount::from_ln(ln));
+                }
+            }
+        }
+        let mut rhs_and_n_to_count = HashMap::new();
+        for _ in 0..read_len(r)? {
+            let mut rhs = vec![];
+            for _ in 0..read_len(r)? {
+                rhs.push(read_nt(r)?);
+            }
+            for (len, ln) in read_f64s(r)?.into_iter().enumerate() {
+                if !ln.is_nan() {
+                    rhs_and_n_to_count.insert((rhs.clone(), len), LogCount::from_ln(ln));
+                }
+            }
+        }
+        let mut rule_id_to_possible_lens = HashMap::new();
+        for _ in 0..read_len(r)? {
+            let rule = read_len(r)?;
+            if rule >= self.rules.len() {
+                return Err(invalid("invalid rule"));
+            }
+            let mut lens = vec![];
+            for _ in 0..read_len(r)? {
+                lens.push(read_len(r)?);
+            }
+            rule_id_to_possible_lens.insert(RuleID::from(rule), lens);
+        }
+        let mut rule_probabilities = vec![];
+        for _ in 0..read_len(r)? {
+            let nt = read_nt(r)?;
+            let len = read_len(r)?;
+            let probabilities = read_f64s(r)?;
+            let number_of_rules = self.nts_to_rules.get(&nt).map(|rules| rules.len());
+            if len >= max_len || number_of_rules != Some(probabilities.len()) {
+                return Err(invalid("invalid rule sampler"));
+            }
+            rule_probabilities.push((nt, len, probabilities));
+        }
+        let mut len_probabilities = vec![];
+        for _ in 0..read_len(r)? {
+            let nt = read_nt(r)?;
+            let probabilities = read_f64s(r)?;
+            if probabilities.len() != max_len {
+                return Err(invalid("invalid length sampler"));
+            }
+            len_probabilities.push((nt, probabilities));
+        }
+
+        self.max_len = max_len;
+        self.nt_and_n_to_count = nt_and_n_to_count;
+        self.rhs_and_n_to_count = rhs_and_n_to_count;
+        self.rule_id_to_possible_lens = rule_id_to_possible_lens;
+        for (nt, len, probabilities) in rule_probabilities {
+            self.nts_to_rule_samplers
+                .borrow_mut()
+                .insert((nt, len), LoadedDiceSampler::new(probabilities, rng::rng()));
+        }
+        for (nt, probabilities) in len_probabilities {
+            self.nts_to_len_samplers
+                .borrow_mut()
+                .insert(nt, LoadedDiceSampler::new(probabilities, rng::rng()));
+        }
+        return Ok(());
+    }
+
+    //Writes the counts and the probabilities of the samplers that were built so far. The counts are
+    //written per nonterminal and right hand side for all lengths from 0 on.
+    pub fn write_cache<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut nt_counts: HashMap<NTermID, Vec<f64>> = HashMap::new();
+        for (&(nt, len), count) in self.nt_and_n_to_count.iter() {
+            set_at(nt_counts.entry(nt).or_insert_with(|| vec![]), len, count.ln());
+        }
+        write_len(w, nt_counts.len())?;
+        for (nt, counts) in nt_counts.iter() {
+            write_len(w, nt.to_i())?;
+            write_f64s(w, counts)?;
+        }
+
+        let mut rhs_counts: HashMap<&Vec<NTermID>, Vec<f64>> = HashMap::new();
+        for (&(ref rhs, len), count) in self.rhs_and_n_to_count.iter() {
+            set_at(rhs_counts.entry(rhs).or_insert_with(|| vec![]), len, count.ln());
+        }
+        write_len(w, rhs_counts.len())?;
+        for (rhs, counts) in rhs_counts.iter() {
+            write_len(w, rhs.len())?;
+            for nt in rhs.iter() {
+                write_len(w, nt.to_i())?;
+            }
+            write_f64s(w, counts)?;
+        }
+
+        write_len(w, self.rule_id_to_possible_lens.len())?;
+        for (rule, lens) in self.rule_id_to_possible_lens.iter() {
+            write_len(w, rule.to_i())?;
+            write_len(w, lens.len())?;
+            for len in lens.iter() {
+                write_len(w, *len)?;
+            }
+        }
+
+        let rule_samplers = self
+            .nts_to_rule_samplers
+            .borrow()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        write_len(w, rule_samplers.len())?;
+        for &(nt, len) in rule_samplers.iter() {
+            write_len(w, nt.to_i())?;
+            write_len(w, len)?;
+            write_f64s(w, &self.rule_probabilities(nt, len).expect("RAND_2687329186"))?;
+        }
+
+        let len_samplers = self
+            .nts_to_len_samplers
+            .borrow()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        write_len(w, len_samplers.len())?;
+        for nt in len_samplers.iter() {
+            write_len(w, nt.to_i())?;
+            write_f64s(w, &self.len_probabilities(*nt))?;
+        }
+        return Ok(());
+    }
+
     pub fn create_serializable_context(&self, hash_of_original: u64) -> SerializableContext {
         return SerializableContext {
             rules: self.rules.clone(),
@@ -88,7 +262,6 @@ impl Context {
             names_to_nt_id: self.names_to_nt_id.clone(),
             rules_to_min_size: self.rules_to_min_size.clone(),
             nts_to_min_size: self.nts_to_min_size.clone(),
-            rhs_and_n_to_count_u32: self.rhs_and_n_to_count_u32.clone(),
             nt_and_n_to_count: self.nt_and_n_to_count.clone(),
             rhs_and_n_to_count: self.rhs_and_n_to_count.clone(),
             rule_id_to_possible_lens: self.rule_id_to_possible_lens.clone(),
@@ -102,6 +275,7 @@ impl Context {
         saved_context: SerializableContext,
         verbose: bool,
         dumb: bool,
+        lazy_samplers: bool,
     ) -> Self {
         let max_len = saved_context.max_len;
         let mut context = Context {
@@ -109,22 +283,25 @@ impl Context {
             nts_to_rules: saved_context.nts_to_rules,
             nt_ids_to_name: saved_context.nt_ids_to_name,
             names_to_nt_id: saved_context.names_to_nt_id,
+            rule_locations: HashMap::new(),
             rules_to_min_size: saved_context.rules_to_min_size,
             nts_to_min_size: saved_context.nts_to_min_size,
-            nts_to_rule_samplers: HashMap::new(),
-            nts_to_len_samplers: HashMap::new(),
-            rhs_and_n_to_count_u32: saved_context.rhs_and_n_to_count_u32,
+            nts_to_rule_samplers: RefCell::new(HashMap::new()),
+            nts_to_len_samplers: RefCell::new(HashMap::new()),
             nt_and_n_to_count: saved_context.nt_and_n_to_count,
             rhs_and_n_to_count: saved_context.rhs_and_n_to_count,
             rule_id_to_possible_lens: saved_context.rule_id_to_possible_lens,
             max_len,
             dumb,
+            lazy_samplers,
         };
         if !dumb {
-            context.calc_sampler(max_len - 2, verbose);
-            if saved_context.dumb && !dumb {
+            //A dumb context has no counts
+            if saved_context.dumb {
+                context.calc_counts(verbose);
                 context.set_rule_id_to_possible_lengths();
             }
+            context.calc_samplers();
         }
         return context;
     }
@@ -134,6 +311,10 @@ impl Context {
         return &self.rules[id];
     }
 
+    pub fn get_num_rules(&self) -> usize {
+        return self.rules.len();
+    }
+
     pub fn get_nt(&self, r: RuleID) -> NTermID {
         return self.get_rule(r).nonterm();
     }
@@ -154,6 +335,18 @@ impl Context {
         return rid;
     }
 
+    pub fn add_rule_with_children(&mut self, nt: &str, children: Vec<RuleChild>) -> RuleID {
+        let rid = self.rules.len().into();
+        let rule = Rule::from_children(self, nt, children);
+        let ntid = self.aquire_nt_id(nt);
+        self.rules.push(rule);
+        self.nts_to_rules
+            .entry(ntid)
+            .or_insert_with(|| vec![])
+            .push(rid);
+        return rid;
+    }
+
     pub fn add_term_rule(&mut self, nt: &str, term: &Vec<u8>) -> RuleID {
         let rid = self.rules.len().into();
         let ntid = self.aquire_nt_id(nt);
@@ -176,6 +369,47 @@ impl Context {
         return self.dumb;
     }
 
+    //Where r was defined, used by the diagnostics. Loaders that know it set it after add_rule.
+    pub fn set_rule_location(&mut self, r: RuleID, location: SourceLocation) {
+        self.rule_locations.insert(r, location);
+    }
+
+    pub fn get_rule_location(&self, r: RuleID) -> Option<&SourceLocation> {
+        return self.rule_locations.get(&r);
+    }
+
+    //Undefined, unproductive and unreachable nonterminals, duplicate rules and, once initialized,
+    //rules that are too large for max_tree_size
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        return diagnostic::validate(self, self.max_len.checked_sub(2));
+    }
+
+    pub fn validate_for(&self, max_tree_size: usize) -> Vec<Diagnostic> {
+        return diagnostic::validate(self, Some(max_tree_size));
+    }
+
+    //The diagnostics that keep initialize from working with max_tree_size. The size of the trees is
+    //not limited in dumb mode.
+    pub fn errors_for(&self, max_tree_size: usize) -> Vec<Diagnostic> {
+        let max_tree_size = if self.dumb { None } else { Some(max_tree_size) };
+        return diagnostic::validate(self, max_tree_size)
+            .into_iter()
+            .filter(|d| d.severity() == Severity::Error)
+            .collect();
+    }
+
+    //Fails with the errors of the grammar instead of somewhere in the counting
+    fn assert_valid(&self, max_tree_size: usize) {
+        let errors = self
+            .errors_for(max_tree_size)
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<_>>();
+        if errors.len() > 0 {
+            panic!("invalid grammar:\n{}", errors.join("\n"));
+        }
+    }
+
     pub fn nt_id(&self, nt: &str) -> NTermID {
         return *self
             .names_to_nt_id
@@ -257,106 +491,166 @@ impl Context {
         }
     }
 
-    fn calc_sampler(&mut self, max_len: usize, verbose: bool) {
+    //Counts the trees of every nonterminal and every suffix of a right hand side for all lengths
+    //below max_len. A count for len only depends on the counts for smaller lengths and, for a right
+    //hand side, on its suffix without the first nonterminal. So the lengths are counted one after
+    //the other, each in parallel over the nonterminals and then over the suffixes with the same
+    //number of nonterminals.
+    fn calc_counts(&mut self, verbose: bool) {
         //Check if all min_lens are less than max_len
         for min_size in self.rules_to_min_size.values() {
             assert!(*min_size < self.max_len);
         }
 
-        //Get set of all nterms
-        let mut nterms = HashSet::new();
+        let mut suffix_ids: HashMap<Vec<NTermID>, usize> = HashMap::new();
+        let mut suffixes: Vec<Vec<NTermID>> = vec![];
         for rule in self.rules.iter() {
-            nterms.insert(rule.nonterm());
+            let nterms = rule.nonterms();
+            for i in 0..nterms.len() {
+                if !suffix_ids.contains_key(&nterms[i..]) {
+                    suffix_ids.insert(nterms[i..].to_vec(), suffixes.len());
+                    suffixes.push(nterms[i..].to_vec());
+                }
+            }
         }
-
-        //Initialize HashMaps for all nterms
-        for nterm in nterms.iter() {
-            self.nts_to_rule_samplers
-                .insert(nterm.clone(), vec![None; self.max_len]);
+        //tails[id] is the id of the suffix without its first nonterminal, None if that is empty
+        let tails = suffixes
+            .iter()
+            .map(|suffix| suffix_ids.get(&suffix[1..]).cloned())
+            .collect::<Vec<_>>();
+        let max_suffix_len = suffixes.iter().map(|suffix| suffix.len()).max().unwrap_or(0);
+        let mut suffixes_by_len = vec![vec![]; max_suffix_len + 1];
+        for (id, suffix) in suffixes.iter().enumerate() {
+            suffixes_by_len[suffix.len()].push(id);
+        }
+        //The right hand sides of the rules of every nonterminal, in the order of nts_to_rules
+        let num_nts = self.nt_ids_to_name.len();
+        let mut nt_rhs = vec![vec![]; num_nts];
+        for (nt, rules) in self.nts_to_rules.iter() {
+            nt_rhs[nt.to_i()] = rules
+                .iter()
+                .map(|rule| suffix_ids.get(&self.get_rule(*rule).nonterms()[..]).cloned())
+                .collect::<Vec<_>>();
         }
 
-        //Calculate subtrees
+        //Counts of the empty right hand side
+        let empty = |len: usize| if len == 0 { LogCount::one() } else { LogCount::zero() };
+        let mut nt_counts = vec![Vec::with_capacity(self.max_len); num_nts];
+        let mut suffix_counts = vec![Vec::with_capacity(self.max_len); suffixes.len()];
         if verbose {
             print!("Calculating possible subtrees:");
         }
-        for i in 1..self.max_len {
-            for nterm in nterms.iter() {
-                self.count_possibilities_nterm(&nterm, i);
-
-                //create rule sampler
-                if self
-                    .nt_and_n_to_count
-                    .get(&(*nterm, i))
-                    .expect("RAND_2374448501") != &0
-                {
-                    let mut norm_factor: u64 = 0;
-                    let rules_for_nt = self
-                        .nts_to_rules
-                        .get(&nterm)
-                        .expect("RAND_2561305800")
-                        .clone();
-                    let mut rule_probabilities: Vec<f64> = vec![0.0; rules_for_nt.len()];
-                    for (x, rule_id) in rules_for_nt.iter().enumerate() {
-                        let nterms = self.get_rule(rule_id.clone()).nonterms().clone();
-                        rule_probabilities[x] +=
-                            self.count_possibilities_rule(&nterms, i - 1)
-                                .to_u32()
-                                .unwrap_or(u32::max_value()) as f64;
-                        norm_factor +=
-                            self.count_possibilities_rule(&nterms, i - 1)
-                                .to_u32()
-                                .unwrap_or(u32::max_value()) as u64;
+        for len in 0..self.max_len {
+            let layer = nt_rhs
+                .par_iter()
+                .map(|rhs_of_rules| {
+                    if len < 1 {
+                        return LogCount::zero();
                     }
-                    for x in 0..rule_probabilities.len() {
-                        rule_probabilities[x] /= norm_factor as f64;
-                    }
-                    // println!("Sampler: Nterm: {};\tDepth: {};\t\tRule probabilities: {:?}", self.nt_ids_to_name.get(&nterm).expect("RAND_1038242446"), i, rule_probabilities);
-                    let sampler = LoadedDiceSampler::new(
-                        rule_probabilities,
-                        StdRng::new().expect("RAND_2910855259"),
-                    );
-                    self.nts_to_rule_samplers
-                        .get_mut(&nterm)
-                        .expect("RAND_1458598779")[i] = Some(RefCell::new(sampler));
+                    let counts = rhs_of_rules
+                        .iter()
+                        .map(|rhs| match *rhs {
+                            Some(id) => suffix_counts[id][len - 1],
+                            None => empty(len - 1),
+                        })
+                        .collect::<Vec<_>>();
+                    return LogCount::sum(&counts);
+                })
+                .collect::<Vec<_>>();
+            for (counts, count) in nt_counts.iter_mut().zip(layer) {
+                counts.push(count);
+            }
+            for ids in suffixes_by_len.iter() {
+                let layer = ids
+                    .par_iter()
+                    .map(|&id| {
+                        let first = &nt_counts[suffixes[id][0].to_i()];
+                        let terms = (0..len + 1)
+                            .map(|s| {
+                                let rest = match tails[id] {
+                                    Some(tail) => suffix_counts[tail][s],
+                                    None => empty(s),
+                                };
+                                return rest * first[len - s];
+                            })
+                            .collect::<Vec<_>>();
+                        return LogCount::sum(&terms);
+                    })
+                    .collect::<Vec<_>>();
+                for (&id, count) in ids.iter().zip(layer) {
+                    suffix_counts[id].push(count);
                 }
             }
-            if verbose {
+            if verbose && len > 0 {
                 print!(
                     "\rCalculating possible subtrees: {}%",
-                    (i * 100) / (self.max_len - 1)
+                    (len * 100) / (self.max_len - 1)
                 );
             }
         }
-        for nterm in nterms.iter() {
-            let mut probabilities = vec![0.0; self.max_len];
-            let mut norm_factor: u64 = 0;
-            for i in 1..self.max_len {
-                let p = self.get_possibilities_for_nterm(nterm, i);
-                norm_factor += p as u64;
-                probabilities[i] = p as f64;
+        if verbose {
+            print!("\n");
+        }
+
+        self.nt_and_n_to_count = HashMap::new();
+        for (nt, counts) in nt_counts.into_iter().enumerate() {
+            for (len, count) in counts.into_iter().enumerate().skip(1) {
+                self.nt_and_n_to_count.insert((NTermID::from(nt), len), count);
             }
-            for i in 1..self.max_len {
-                probabilities[i] /= norm_factor as f64;
+        }
+        self.rhs_and_n_to_count = HashMap::new();
+        for (suffix, counts) in suffixes.into_iter().zip(suffix_counts) {
+            for (len, count) in counts.into_iter().enumerate() {
+                self.rhs_and_n_to_count.insert((suffix.clone(), len), count);
             }
-            // println!("Len Sampler: Nterm: {};\tLen probabilities: {:?}", self.nt_ids_to_name.get(&nterm).expect("RAND_3680791943"), probabilities);
-            let sampler =
-                LoadedDiceSampler::new(probabilities, StdRng::new().expect("RAND_2190718084"));
-            self.nts_to_len_samplers
-                .insert(nterm.clone(), RefCell::new(sampler));
         }
-        if verbose {
-            print!("\n");
+    }
+
+    //Builds the rule samplers for all lengths and the length sampler of every nonterminal, unless
+    //they are built on first use
+    fn calc_samplers(&mut self) {
+        if self.lazy_samplers {
+            return;
+        }
+        let mut rule_samplers = HashMap::new();
+        let mut len_samplers = HashMap::new();
+        for nt in self.nts_to_rules.keys() {
+            for len in 1..self.max_len {
+                if let Some(probabilities) = self.rule_probabilities(*nt, len) {
+                    rule_samplers.insert((*nt, len), LoadedDiceSampler::new(probabilities, rng::rng()));
+                }
+            }
+            let probabilities = self.len_probabilities(*nt);
+            len_samplers.insert(*nt, LoadedDiceSampler::new(probabilities, rng::rng()));
+        }
+        self.nts_to_rule_samplers = RefCell::new(rule_samplers);
+        self.nts_to_len_samplers = RefCell::new(len_samplers);
+    }
+
+    //Every rule is picked with the share of the trees of size len it derives, None if nt derives no
+    //tree of size len
+    fn rule_probabilities(&self, nt: NTermID, len: usize) -> Option<Vec<f64>> {
+        let count = self.get_possibilities_for_nterm(&nt, len);
+        if count.is_zero() {
+            return None;
         }
+        let probabilities = self.nts_to_rules[&nt]
+            .iter()
+            .map(|rule_id| {
+                self.get_possibilities_for_rule(self.get_rule(*rule_id).nonterms(), len - 1)
+                    .ratio(count)
+            })
+            .collect();
+        return Some(normalize(probabilities));
+    }
 
-        // for nterm in  nterms.iter() {
-        //     for i in 1..max_len {
-        //         println!("Nterm: {}(NtermID: {})\tLen: {}\tPossible Subtrees: {:?}", self.nt_ids_to_name.get(&nterm).expect("RAND_3444108000"), self.nt_ids_to_name[&nterm], i, self.nt_and_n_to_count.get(&(*nterm, i)).expect("RAND_3444108000").to_u64().unwrap_or(u64::max_value()));
-        //     }
-        // }
-        // for (key, possibilities) in self.rhs_and_n_to_count.iter() {
-        //     println!("Nonterms and len: {:?}\t\tNumber of Subrees: {:?}\t\tExact: {:?}", key, possibilities.to_u64().unwrap_or(u64::max_value()), possibilities);
-        // }
-        // println!("{:?}", self.nt_ids_to_name);
+    //Every size is picked with the share of the trees of nt that have this size
+    fn len_probabilities(&self, nt: NTermID) -> Vec<f64> {
+        let counts = (0..self.max_len)
+            .map(|i| self.get_possibilities_for_nterm(&nt, i))
+            .collect::<Vec<_>>();
+        let total = LogCount::sum(&counts);
+        return normalize(counts.iter().map(|c| c.ratio(total)).collect());
     }
 
     fn set_rule_id_to_possible_lengths(&mut self) {
@@ -375,138 +669,74 @@ impl Context {
         }
     }
 
-    fn count_possibilities_nterm(&mut self, nt: &NTermID, len: usize) -> u16 {
-        if len < 1 {
-            return 0;
-        }
-        if self.nt_and_n_to_count.contains_key(&(*nt, len)) {
-            return self
-                .nt_and_n_to_count
-                .get(&(*nt, len))
-                .expect("RAND_1364312715")
-                .clone();
-        }
-        let mut sum = 0;
-        let rules = self.nts_to_rules.get(&nt).expect("RAND_3987216527").clone();
-        for rule_id in rules.iter() {
-            let nterms = self.get_rule(rule_id.clone()).nonterms().clone();
-            match sum.checked_add(&self.count_possibilities_rule(&nterms, len - 1)) {
-                Some(x) => sum = x,
-                None => {
-                    sum = u16::max_value();
-                    break;
-                }
-            }
-        }
-        self.nt_and_n_to_count.insert((*nt, len), sum);
-        return sum;
-    }
-
-    fn count_possibilities_rule(&mut self, nterms: &Vec<NTermID>, len: usize) -> u16 {
-        if nterms.len() == 0 {
-            return if len == 0 { 1 } else { 0 };
-        }
-        if self.rhs_and_n_to_count.contains_key(&(nterms.clone(), len)) {
-            return self
-                .rhs_and_n_to_count
-                .get(&(nterms.clone(), len))
-                .expect("RAND_4255885820")
-                .clone();
-        }
-        let mut possibilities: u32 = 0;
-        let mut new_nterms = Vec::new();
-        new_nterms.extend_from_slice(&nterms[1..]);
-        for s in 0..len + 1 {
-            possibilities += self
-                .count_possibilities_rule(&new_nterms, s)
-                .checked_mul(self.count_possibilities_nterm(&nterms[0], len - s))
-                .unwrap_or(u16::max_value()) as u32;
-        }
-
-        let res: u16 = if possibilities > u16::max_value() as u32 {
-            u16::max_value()
-        } else {
-            possibilities as u16
-        };
-        self.rhs_and_n_to_count_u32
-            .insert((nterms.clone(), len), possibilities);
-        self.rhs_and_n_to_count.insert((nterms.clone(), len), res);
-        return res;
-    }
-
-    pub fn get_possibilities_for_rule(&self, nterms: &Vec<NTermID>, len: usize) -> u16 {
+    pub fn get_possibilities_for_rule(&self, nterms: &Vec<NTermID>, len: usize) -> LogCount {
         if nterms.len() == 0 {
-            return if len == 0 { 1 } else { 0 };
+            return if len == 0 { LogCount::one() } else { LogCount::zero() };
         }
-        return self
+        return *self
             .rhs_and_n_to_count
             .get(&(nterms.clone(), len))
-            .expect("RAND_3786858109")
-            .clone();
+            .expect("RAND_3786858109");
     }
 
-    pub fn get_possibilities_for_rule_u32(&self, nterms: &Vec<NTermID>, len: usize) -> u32 {
-        if nterms.len() == 0 {
-            return if len == 0 { 1 } else { 0 };
-        }
-        return self
-            .rhs_and_n_to_count_u32
-            .get(&(nterms.clone(), len))
-            .expect("RAND_3519890687")
-            .clone();
-    }
-
-    fn get_possibilities_for_nterm(&self, nt: &NTermID, len: usize) -> u16 {
+    fn get_possibilities_for_nterm(&self, nt: &NTermID, len: usize) -> LogCount {
         if len < 1 {
-            return 0;
+            return LogCount::zero();
         }
-        return self
+        return *self
             .nt_and_n_to_count
             .get(&(*nt, len))
-            .expect("RAND_3164178569")
-            .clone();
+            .expect("RAND_3164178569");
     }
 
     pub fn check_if_nterm_has_multiple_possiblities(&self, nt: &NTermID) -> bool {
         if self.dumb {
             return self.get_rules_for_nt(*nt).len() > 1;
         }
-        let mut counter = 0;
-        for i in 1..self.max_len {
-            counter = counter
-                .checked_add(&self.get_possibilities_for_nterm(nt, i))
-                .unwrap_or(2);
-            if counter > 1 {
-                return true;
-            }
-        }
-        return false;
+        let counts = (1..self.max_len)
+            .map(|i| self.get_possibilities_for_nterm(nt, i))
+            .collect::<Vec<_>>();
+        return LogCount::sum(&counts) > LogCount::one();
     }
 
     pub fn get_random_len(&self, len: usize, rhs_of_rule: &Vec<NTermID>) -> usize {
+        return self.get_len_with(len, rhs_of_rule, &mut |n| rng::rng().gen_range(0, n));
+    }
+
+    //Like get_random_len, but the random numbers are taken from random(n), which returns a number
+    //in 0..n
+    pub fn get_len_with<F>(&self, len: usize, rhs_of_rule: &Vec<NTermID>, random: &mut F) -> usize
+    where
+        F: FnMut(usize) -> usize,
+    {
         if self.dumb {
-            return self.dumb_get_random_len(rhs_of_rule.len(), len);
+            return self.dumb_get_random_len(rhs_of_rule.len(), len, random);
         }
-        let possibilities = self.get_possibilities_for_rule_u32(rhs_of_rule, len);
-        assert_ne!(possibilities, 0);
-        let mut counter = 0;
+        let possibilities = self.get_possibilities_for_rule(rhs_of_rule, len);
+        assert!(!possibilities.is_zero());
         let mut remaining_nts = Vec::new();
         remaining_nts.extend_from_slice(&rhs_of_rule[1..]);
         let nt = &rhs_of_rule[0];
-        let random = thread_rng().gen_range(0, possibilities);
+        //Every split of len is picked with the share of the trees it gives
+        let random = random(SPLIT_RESOLUTION) as f64 / SPLIT_RESOLUTION as f64;
+        let mut counter = 0.0;
+        let mut last_possible = None;
         for i in 0..len + 1 {
-            counter += self
-                .get_possibilities_for_rule(&remaining_nts.to_vec(), i)
-                .checked_mul(self.get_possibilities_for_nterm(nt, len - i))
-                .unwrap_or(u16::max_value()) as u32;
+            let count = self.get_possibilities_for_rule(&remaining_nts, i)
+                * self.get_possibilities_for_nterm(nt, len - i);
+            if count.is_zero() {
+                continue;
+            }
+            counter += count.ratio(possibilities);
             if counter > random {
                 return len - i;
             };
+            last_possible = Some(len - i);
+        }
+        //The shares add up to slightly less than 1 due to rounding
+        if let Some(res) = last_possible {
+            return res;
         }
-        println!(
-            "counter: {:?}, nterms: {:?}, random: {:?}, possibilities: {:?}",
-            counter, remaining_nts, random, possibilities
-        );
         panic!(
             "No random len for {} within {} steps found!",
             self.nt_ids_to_name[&nt], len
@@ -517,11 +747,19 @@ impl Context {
     //available size fairly to all nodes. (e.g. all children have the same expected size,
     //regardless of its index in the current rule. We use this version of the algorithm described
     //here: https://stackoverflow.com/a/8068956 to get the first value.
-    fn dumb_get_random_len(&self, number_of_children: usize, total_remaining_len: usize) -> usize {
+    fn dumb_get_random_len<F>(
+        &self,
+        number_of_children: usize,
+        total_remaining_len: usize,
+        random: &mut F,
+    ) -> usize
+    where
+        F: FnMut(usize) -> usize,
+    {
         let mut res = total_remaining_len;
         let iters = (number_of_children as i32) - 1;
         for _ in 0..iters {
-            let proposal = thread_rng().gen_range(0, total_remaining_len + 1);
+            let proposal = random(total_remaining_len + 1);
             if proposal < res {
                 res = proposal
             }
@@ -538,32 +776,30 @@ impl Context {
             return self.dumb_get_random_rule_for_nt(nt, len);
         }
         //println!("Deriving {} within {} steps", self.nt_ids_to_name[&nt], len);
-        match self.nts_to_rule_samplers.get(&nt).expect("RAND_4063454361")[len] {
-            Some(ref sampler) => {
-                let rule_id = self.nts_to_rules.get(&nt).expect("RAND_3202426897")
-                    [sampler.borrow_mut().sample()];
-                assert!(
-                    !self
-                        .get_possibilities_for_rule(
-                            self.get_rule(rule_id.clone()).nonterms(),
-                            len - 1
-                        )
-                        .is_zero()
-                );
-                return rule_id;
-            }
-            None => panic!(
-                "there is no way to derive {} within {} steps",
-                self.nt_ids_to_name[&nt], len
-            ),
-        }
+        let mut samplers = self.nts_to_rule_samplers.borrow_mut();
+        let sampler = match samplers.entry((nt, len)) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match self.rule_probabilities(nt, len) {
+                Some(probabilities) => {
+                    entry.insert(LoadedDiceSampler::new(probabilities, rng::rng()))
+                }
+                None => panic!(
+                    "there is no way to derive {} within {} steps",
+                    self.nt_ids_to_name[&nt], len
+                ),
+            },
+        };
+        let rule_id = self.nts_to_rules.get(&nt).expect("RAND_3202426897")[sampler.sample()];
+        assert!(
+            !self
+                .get_possibilities_for_rule(self.get_rule(rule_id.clone()).nonterms(), len - 1)
+                .is_zero()
+        );
+        return rule_id;
     }
 
     fn dumb_get_random_rule_for_nt(&self, nt: NTermID, max_len: usize) -> RuleID {
-        let applicable_rules = self.nts_to_rules[&nt]
-            .iter()
-            .take_while(|r| self.rules_to_min_size[r] <= max_len);
-        match sample(&mut thread_rng(), applicable_rules, 1).pop() {
+        match rng::rng().choose(&self.get_applicable_rules(nt, max_len)) {
             Some(rule) => return *rule,
             None => panic!(
                 "there is no way to derive {} within {} steps",
@@ -572,8 +808,38 @@ impl Context {
         }
     }
 
+    //Rules for nt that can be derived within len steps (exactly len steps if not dumb)
+    pub fn get_applicable_rules(&self, nt: NTermID, len: usize) -> Vec<RuleID> {
+        if self.dumb {
+            return self.nts_to_rules[&nt]
+                .iter()
+                .take_while(|r| self.rules_to_min_size[r] <= len)
+                .cloned()
+                .collect();
+        }
+        return self.nts_to_rules[&nt]
+            .iter()
+            .filter(|r| {
+                len > 0 && !self
+                    .get_possibilities_for_rule(self.get_rule(**r).nonterms(), len - 1)
+                    .is_zero()
+            })
+            .cloned()
+            .collect();
+    }
+
+    //Lengths get_random_len_for_nt can return for nt, in ascending order
+    pub fn get_possible_lens_for_nt(&self, nt: NTermID) -> Vec<usize> {
+        if self.dumb {
+            return vec![self.max_len];
+        }
+        return (1..self.max_len)
+            .filter(|len| !self.get_possibilities_for_nterm(&nt, *len).is_zero())
+            .collect();
+    }
+
     pub fn get_random_len_for_ruleid(&self, rule_id: &RuleID) -> usize {
-        return *thread_rng()
+        return *rng::rng()
             .choose(
                 &self
                     .rule_id_to_possible_lens
@@ -589,9 +855,9 @@ impl Context {
         }
         return self
             .nts_to_len_samplers
-            .get(&nt)
-            .expect("RAND_2940771921")
             .borrow_mut()
+            .entry(*nt)
+            .or_insert_with(|| LoadedDiceSampler::new(self.len_probabilities(*nt), rng::rng()))
             .sample();
     }
 
@@ -615,14 +881,47 @@ impl Context {
         tree.generate_from_rule(r, len, self);
         return tree;
     }
+
+    //Arbitrary style generation: the length and every rule of the tree are taken from the bytes of
+    //u. The same bytes always give the same tree and once they are used up the smallest length and
+    //the first applicable rules are picked. None if nt can't be derived within the maximum length.
+    pub fn generate_tree_from_unstructured(&self, nt: NTermID, u: &mut Unstructured) -> Option<Tree> {
+        let lens = self.get_possible_lens_for_nt(nt);
+        if lens.is_empty() {
+            return None;
+        }
+        let len = lens[u.below(lens.len())];
+        let r = u.rule_for_nt(self, nt, len);
+        let mut tree = Tree::from_rule_vec(vec![], self);
+        tree.generate_from_rule_with(r, len - 1, self, u);
+        return Some(tree);
+    }
+}
+
+//Lengths that were not counted are stored as NaN
+fn set_at(counts: &mut Vec<f64>, len: usize, ln: f64) {
+    if counts.len() <= len {
+        counts.resize(len + 1, f64::NAN);
+    }
+    counts[len] = ln;
+}
+
+//The probabilities of the samplers have to add up to 1, rounding errors are spread over all of them
+fn normalize(mut probabilities: Vec<f64>) -> Vec<f64> {
+    let sum: f64 = probabilities.iter().sum();
+    for p in probabilities.iter_mut() {
+        *p /= sum;
+    }
+    return probabilities;
 }
 
 #[cfg(test)]
 mod tests {
     use context::Context;
-    use newtypes::RuleID;
+    use newtypes::{NTermID, RuleID};
+    use rng;
     use rule::{NormalOrCustomRule, Rule, RuleChild};
-    use std::collections::HashSet;
+    use std::collections::{HashMap, HashSet};
     use tree::{Tree, TreeLike};
 
     #[test]
@@ -789,7 +1088,7 @@ mod tests {
         ctx.initialize(10, false);
 
         let serial_ctx = ctx.create_serializable_context(1);
-        let ctx2 = Context::from_serialized_context(serial_ctx, false, false);
+        let ctx2 = Context::from_serialized_context(serial_ctx, false, false, false);
         assert_eq!(ctx.rules, ctx2.rules);
         assert_eq!(ctx.nts_to_rules, ctx2.nts_to_rules);
         assert_eq!(ctx.nt_ids_to_name, ctx2.nt_ids_to_name);
@@ -798,8 +1097,186 @@ mod tests {
         assert_eq!(ctx.nts_to_min_size, ctx2.nts_to_min_size);
         assert_eq!(ctx.nt_and_n_to_count, ctx2.nt_and_n_to_count);
         assert_eq!(ctx.rhs_and_n_to_count, ctx2.rhs_and_n_to_count);
-        assert_eq!(ctx.rhs_and_n_to_count_u32, ctx2.rhs_and_n_to_count_u32);
         assert_eq!(ctx.rule_id_to_possible_lens, ctx2.rule_id_to_possible_lens);
         assert_eq!(ctx.max_len, ctx2.max_len);
+        assert!(!ctx2.nts_to_len_samplers.borrow().is_empty());
+
+        let lazy = Context::from_serialized_context(ctx.create_serializable_context(1), false, false, true);
+        assert!(lazy.has_lazy_samplers());
+        assert!(lazy.nts_to_len_samplers.borrow().is_empty());
+        assert!(lazy.nts_to_rule_samplers.borrow().is_empty());
+    }
+
+    #[test]
+    fn check_cache_roundtrip() {
+        let ctx = expression_context(30);
+        let mut data = vec![];
+        ctx.write_cache(&mut data).expect("RAND_1016484622");
+        let mut cached = expression_rules();
+        cached
+            .initialize_from_cache(30, &mut &data[..])
+            .expect("RAND_3566716291");
+        assert_eq!(ctx.nt_and_n_to_count, cached.nt_and_n_to_count);
+        assert_eq!(ctx.rhs_and_n_to_count, cached.rhs_and_n_to_count);
+        assert_eq!(ctx.rule_id_to_possible_lens, cached.rule_id_to_possible_lens);
+        assert_eq!(ctx.max_len, cached.max_len);
+        let e = ctx.nt_id("E");
+        for len in 0..ctx.max_len {
+            assert_eq!(ctx.rule_probabilities(e, len), cached.rule_probabilities(e, len));
+            assert_eq!(
+                ctx.nts_to_rule_samplers.borrow().contains_key(&(e, len)),
+                cached.nts_to_rule_samplers.borrow().contains_key(&(e, len))
+            );
+        }
+        assert_eq!(ctx.len_probabilities(e), cached.len_probabilities(e));
+        let len = cached.get_random_len_for_nt(&e);
+        cached.generate_tree_from_nt(e, len);
+        //Truncated data is rejected and leaves the context as it was
+        let mut truncated = expression_rules();
+        assert!(truncated
+            .initialize_from_cache(30, &mut &data[..data.len() / 2])
+            .is_err());
+        assert!(truncated.nt_and_n_to_count.is_empty());
+        assert!(truncated.nts_to_len_samplers.borrow().is_empty());
+    }
+
+    #[test]
+    fn check_lazy_samplers() {
+        let ctx = expression_context(30);
+        let mut lazy = expression_rules();
+        lazy.set_lazy_samplers(true);
+        lazy.initialize(30, false);
+        assert!(lazy.nts_to_rule_samplers.borrow().is_empty());
+        assert!(lazy.nts_to_len_samplers.borrow().is_empty());
+        assert_eq!(ctx.nt_and_n_to_count, lazy.nt_and_n_to_count);
+        assert_eq!(ctx.rhs_and_n_to_count, lazy.rhs_and_n_to_count);
+        //The samplers are built on first use and draw the same trees
+        let e = ctx.nt_id("E");
+        for i in 0..100 {
+            rng::seed(i);
+            let len = ctx.get_random_len_for_nt(&e);
+            let tree = ctx.generate_tree_from_nt(e, len);
+            rng::seed(i);
+            let lazy_len = lazy.get_random_len_for_nt(&e);
+            let lazy_tree = lazy.generate_tree_from_nt(e, lazy_len);
+            assert_eq!(tree.unparse_to_vec(&ctx), lazy_tree.unparse_to_vec(&lazy));
+        }
+        assert!(lazy.nts_to_len_samplers.borrow().contains_key(&e));
+        assert!(!lazy.nts_to_rule_samplers.borrow().is_empty());
+    }
+
+    //Exact number of trees with len nodes for the nonterminals in nts, independent of the counting
+    //of the context
+    fn exact_count(
+        ctx: &Context,
+        nts: &[NTermID],
+        len: usize,
+        memo: &mut HashMap<(Vec<NTermID>, usize), u128>,
+    ) -> u128 {
+        if nts.is_empty() {
+            return if len == 0 { 1 } else { 0 };
+        }
+        if let Some(count) = memo.get(&(nts.to_vec(), len)) {
+            return *count;
+        }
+        let mut res = 0;
+        for first_len in 1..len + 1 {
+            let rest = exact_count(ctx, &nts[1..], len - first_len, memo);
+            if rest == 0 {
+                continue;
+            }
+            for rule in ctx.get_rules_for_nt(nts[0]).iter() {
+                let nonterms = ctx.get_rule(*rule).nonterms().clone();
+                res += exact_count(ctx, &nonterms, first_len - 1, memo) * rest;
+            }
+        }
+        memo.insert((nts.to_vec(), len), res);
+        return res;
+    }
+
+    fn expression_rules() -> Context {
+        let mut ctx = Context::new();
+        ctx.add_rule("E", "({E}+{E})");
+        ctx.add_rule("E", "({E}*{E})");
+        ctx.add_rule("E", "{N}");
+        ctx.add_rule("N", "1");
+        ctx.add_rule("N", "2");
+        return ctx;
+    }
+
+    fn expression_context(max_len: usize) -> Context {
+        let mut ctx = expression_rules();
+        ctx.initialize(max_len, false);
+        return ctx;
+    }
+
+    #[test]
+    fn check_counts_are_exact() {
+        let ctx = expression_context(80);
+        let e = ctx.nt_id("E");
+        let mut memo = HashMap::new();
+        let mut saturated = false;
+        for len in 1..80 {
+            let exact = exact_count(&ctx, &[e], len, &mut memo);
+            let count = ctx.get_possibilities_for_nterm(&e, len);
+            assert_eq!(exact == 0, count.is_zero());
+            if exact > 0 {
+                assert!((count.ln() - (exact as f64).ln()).abs() < 1e-9);
+            }
+            saturated |= exact > u16::max_value() as u128;
+        }
+        //The counts of the old u16 counting would have saturated
+        assert!(saturated);
+    }
+
+    #[test]
+    fn check_trees_are_uniform() {
+        rng::seed(2);
+        let ctx = expression_context(7);
+        let e = ctx.nt_id("E");
+        let mut memo = HashMap::new();
+        let total = (1..ctx.max_len)
+            .map(|len| exact_count(&ctx, &[e], len, &mut memo))
+            .sum::<u128>() as usize;
+        let samples = total * 200;
+        let mut seen = HashMap::new();
+        for _ in 0..samples {
+            let len = ctx.get_random_len_for_nt(&e);
+            let tree = ctx.generate_tree_from_nt(e, len);
+            *seen.entry(tree.unparse_to_vec(&ctx)).or_insert(0) += 1;
+        }
+        //The grammar is unambiguous, so every tree has its own string
+        assert_eq!(seen.len(), total);
+        //Chi-squared test against the uniform distribution, total-1 degrees of freedom
+        let expected = samples as f64 / total as f64;
+        let chi2: f64 = seen
+            .values()
+            .map(|n| (*n as f64 - expected).powi(2) / expected)
+            .sum();
+        let dof = (total - 1) as f64;
+        assert!(chi2 < dof + 5.0 * (2.0 * dof).sqrt());
+    }
+
+    #[test]
+    fn check_len_distribution() {
+        rng::seed(3);
+        let ctx = expression_context(60);
+        let e = ctx.nt_id("E");
+        let mut memo = HashMap::new();
+        let counts = (0..ctx.max_len)
+            .map(|len| exact_count(&ctx, &[e], len, &mut memo) as f64)
+            .collect::<Vec<_>>();
+        let total: f64 = counts.iter().sum();
+        let samples = 20000;
+        let mut seen = vec![0; ctx.max_len];
+        for _ in 0..samples {
+            seen[ctx.get_random_len_for_nt(&e)] += 1;
+        }
+        for (len, count) in counts.iter().enumerate() {
+            let p = count / total;
+            let sigma = (p * (1.0 - p) / samples as f64).sqrt();
+            let freq = seen[len] as f64 / samples as f64;
+            assert!((freq - p).abs() <= 5.0 * sigma + 1e-9);
+        }
     }
 }
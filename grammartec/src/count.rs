@@ -0,0 +1,118 @@
+use std::f64;
+use std::ops::Mul;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+//Number of trees, stored as its natural logarithm so that it never overflows (the number of trees
+//with n nodes grows exponentially with n). Zero is exact (ln = -inf), the counts are only compared
+//relative to each other to build the samplers, where the rounding of f64 does not matter.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub struct LogCount(f64);
+
+impl LogCount {
+    pub fn zero() -> Self {
+        return LogCount(f64::NEG_INFINITY);
+    }
+
+    pub fn one() -> Self {
+        return LogCount(0.0);
+    }
+
+    pub fn from_ln(ln: f64) -> Self {
+        return LogCount(ln);
+    }
+
+    pub fn from_count(count: u64) -> Self {
+        return LogCount((count as f64).ln());
+    }
+
+    pub fn is_zero(&self) -> bool {
+        return self.0 == f64::NEG_INFINITY;
+    }
+
+    pub fn ln(&self) -> f64 {
+        return self.0;
+    }
+
+    //The count as float, infinite if it is larger than f64::MAX
+    pub fn to_f64(&self) -> f64 {
+        return self.0.exp();
+    }
+
+    //self / total as float without computing the counts
+    pub fn ratio(&self, total: LogCount) -> f64 {
+        if self.is_zero() {
+            return 0.0;
+        }
+        return (self.0 - total.0).exp();
+    }
+
+    //Sum of the counts. The terms are scaled by the largest one, which keeps the exponentials in
+    //range and takes one exp per term.
+    pub fn sum(terms: &[LogCount]) -> Self {
+        let max = terms.iter().fold(f64::NEG_INFINITY, |max, t| max.max(t.0));
+        if max == f64::NEG_INFINITY {
+            return LogCount::zero();
+        }
+        let scaled: f64 = terms.iter().map(|t| (t.0 - max).exp()).sum();
+        return LogCount(max + scaled.ln());
+    }
+}
+
+impl Mul for LogCount {
+    type Output = LogCount;
+
+    fn mul(self, other: LogCount) -> LogCount {
+        return LogCount(self.0 + other.0);
+    }
+}
+
+//-inf can't be written to RON, zero is stored as None
+impl Serialize for LogCount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let ln = if self.is_zero() { None } else { Some(self.0) };
+        return ln.serialize(serializer);
+    }
+}
+
+impl<'de> Deserialize<'de> for LogCount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let ln = Option::<f64>::deserialize(deserializer)?;
+        return Ok(ln.map(LogCount).unwrap_or(LogCount::zero()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use count::LogCount;
+    use ron;
+
+    #[test]
+    fn check_arithmetic() {
+        let a = LogCount::from_count(6);
+        let b = LogCount::from_count(7);
+        assert!(((a * b).to_f64() - 42.0).abs() < 1e-9);
+        assert!((LogCount::sum(&[a, b, LogCount::zero()]).to_f64() - 13.0).abs() < 1e-9);
+        assert!((a.ratio(LogCount::from_count(12)) - 0.5).abs() < 1e-12);
+        assert!((a * LogCount::zero()).is_zero());
+        assert!(LogCount::sum(&[]).is_zero());
+        assert_eq!(LogCount::sum(&[LogCount::one(), LogCount::zero()]), LogCount::one());
+        //Far beyond the range of f64
+        let big = LogCount::from_count(1 << 60);
+        let mut pow = LogCount::one();
+        for _ in 0..100 {
+            pow = pow * big;
+        }
+        assert!(pow.to_f64().is_infinite());
+        assert!((pow.ln() - 6000.0 * 2f64.ln()).abs() < 1e-6);
+        assert!((LogCount::sum(&[pow, pow]).ratio(pow) - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn check_serialization() {
+        let counts = vec![LogCount::zero(), LogCount::one(), LogCount::from_count(1337)];
+        let s = ron::ser::to_string(&counts).expect("RAND_2093771342");
+        let res: Vec<LogCount> = ron::de::from_str(&s).expect("RAND_3154790671");
+        assert_eq!(counts, res);
+    }
+}
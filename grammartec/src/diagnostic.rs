@@ -0,0 +1,364 @@
+use std::ascii;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str;
+
+use context::Context;
+use newtypes::{NTermID, RuleID};
+use rule::RuleChild;
+
+//Where a rule was defined
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub path: String,
+    //Position of the rule in the grammar file, counted from 0
+    pub index: usize,
+    //Line of the rule, counted from 1, if the format of the file keeps track of it
+    pub line: Option<usize>,
+}
+
+impl SourceLocation {
+    pub fn new(path: &str, index: usize, line: Option<usize>) -> Self {
+        return SourceLocation {
+            path: path.to_string(),
+            index,
+            line,
+        };
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self.line {
+            Some(line) => write!(f, "{}:{}", self.path, line),
+            None => write!(f, "{} (rule {})", self.path, self.index + 1),
+        };
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    //The context can't be initialized with this grammar
+    Error,
+    //The grammar works, but probably not as intended
+    Warning,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    //The rule uses a nonterminal that has no rules
+    UndefinedNonterminal(NTermID),
+    //None of the rules of the nonterminal (whose first rule is reported) derives a finite tree
+    UnproductiveNonterminal(NTermID),
+    //The nonterminal (whose first rule is reported) can't be derived from START
+    UnreachableNonterminal(NTermID),
+    //The rule is the same as the given earlier rule, so it is picked twice as often
+    DuplicateRule(RuleID),
+    //The smallest tree of the rule has more nodes than the largest tree that is generated
+    RuleTooLarge { min_size: usize, max_size: usize },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub rule: RuleID,
+    //None for rules that were not read from a file (e.g. the START rule added by the loaders)
+    pub location: Option<SourceLocation>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn new(ctx: &Context, kind: DiagnosticKind, rule: RuleID, message: String) -> Self {
+        return Diagnostic {
+            kind,
+            rule,
+            location: ctx.get_rule_location(rule).cloned(),
+            message,
+        };
+    }
+
+    pub fn severity(&self) -> Severity {
+        return match self.kind {
+            DiagnosticKind::UnreachableNonterminal(_) | DiagnosticKind::DuplicateRule(_) => {
+                Severity::Warning
+            }
+            _ => Severity::Error,
+        };
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref location) = self.location {
+            write!(f, "{}: ", location)?;
+        }
+        let severity = match self.severity() {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        return write!(f, "{}: {}", severity, self.message);
+    }
+}
+
+//The rule in the format of add_rule, e.g. E -> "({E}+{E})"
+pub fn describe_rule(ctx: &Context, r: RuleID) -> String {
+    let rule = ctx.get_rule(r);
+    let mut format = String::new();
+    for child in rule.children().iter() {
+        match child {
+            &RuleChild::Term(ref data) | &RuleChild::CustomTerm(ref data) => {
+                match str::from_utf8(data) {
+                    Ok(lit) => format.push_str(&lit.replace("{", "\\{").replace("}", "\\}")),
+                    //Binary terminals of structured grammars
+                    Err(_) => {
+                        let escaped = data.iter().flat_map(|b| ascii::escape_default(*b));
+                        format.push_str(&String::from_utf8(escaped.collect()).expect("RAND_3513672095"));
+                    }
+                }
+            }
+            &RuleChild::NTerm(nt) => {
+                format.push_str(&format!("{{{}}}", ctx.nt_id_to_s(nt)));
+            }
+        }
+    }
+    return format!("{} -> {:?}", ctx.nt_id_to_s(rule.nonterm()), format);
+}
+
+//Analyses the grammar of ctx without initializing it. The size of the rules is only checked if
+//max_tree_size is given. The diagnostics are ordered by rule.
+pub fn validate(ctx: &Context, max_tree_size: Option<usize>) -> Vec<Diagnostic> {
+    let rules = (0..ctx.get_num_rules()).map(RuleID::from).collect::<Vec<_>>();
+    let mut first_rule = HashMap::new();
+    for r in rules.iter() {
+        first_rule.entry(ctx.get_nt(*r)).or_insert(*r);
+    }
+    let mut res = vec![];
+
+    for r in rules.iter() {
+        let mut reported = HashSet::new();
+        for nt in ctx.get_rule(*r).nonterms().iter() {
+            if !first_rule.contains_key(nt) && reported.insert(*nt) {
+                let message = format!(
+                    "{} uses the undefined nonterminal {}",
+                    describe_rule(ctx, *r),
+                    ctx.nt_id_to_s(*nt)
+                );
+                res.push(Diagnostic::new(ctx, DiagnosticKind::UndefinedNonterminal(*nt), *r, message));
+            }
+        }
+    }
+
+    //Undefined nonterminals are already reported, they count as one node here so that only the
+    //nonterminals that really never terminate are reported as unproductive
+    let min_sizes = min_sizes(ctx, &rules, &first_rule);
+    let mut nts = first_rule.iter().map(|(nt, r)| (*r, *nt)).collect::<Vec<_>>();
+    nts.sort_by_key(|&(r, _)| r.to_i());
+    for &(r, nt) in nts.iter() {
+        if !min_sizes.contains_key(&nt) {
+            let message = format!(
+                "{} never derives a finite tree, each of its rules needs a nonterminal that never terminates",
+                ctx.nt_id_to_s(nt)
+            );
+            res.push(Diagnostic::new(ctx, DiagnosticKind::UnproductiveNonterminal(nt), r, message));
+        }
+    }
+
+    let start = nts
+        .iter()
+        .map(|&(_, nt)| nt)
+        .find(|nt| ctx.nt_id_to_s(*nt) == "START");
+    if let Some(start) = start {
+        let mut reachable = HashSet::new();
+        let mut todo = vec![start];
+        while let Some(nt) = todo.pop() {
+            if !reachable.insert(nt) || !first_rule.contains_key(&nt) {
+                continue;
+            }
+            for r in ctx.get_rules_for_nt(nt).iter() {
+                todo.extend(ctx.get_rule(*r).nonterms().iter().cloned());
+            }
+        }
+        for &(r, nt) in nts.iter() {
+            if !reachable.contains(&nt) {
+                let message = format!("{} can't be reached from START", ctx.nt_id_to_s(nt));
+                res.push(Diagnostic::new(ctx, DiagnosticKind::UnreachableNonterminal(nt), r, message));
+            }
+        }
+    }
+
+    //Rules are compared by their bytes, different terminals can have the same description
+    let mut seen = HashMap::new();
+    for r in rules.iter() {
+        let rule = ctx.get_rule(*r);
+        let key = (rule.nonterm(), rule.children());
+        if let Some(earlier) = seen.get(&key) {
+            let description = describe_rule(ctx, *r);
+            let message = match ctx.get_rule_location(*earlier) {
+                Some(location) => format!("{} is a duplicate of the rule at {}", description, location),
+                None => format!("{} is a duplicate of an earlier rule", description),
+            };
+            res.push(Diagnostic::new(ctx, DiagnosticKind::DuplicateRule(*earlier), *r, message));
+            continue;
+        }
+        seen.insert(key, *r);
+    }
+
+    //Trees have up to max_tree_size+1 nodes, see Context::initialize
+    if let Some(max_size) = max_tree_size.map(|size| size + 1) {
+        for r in rules.iter() {
+            if let Some(min_size) = rule_min_size(ctx, *r, &min_sizes, &first_rule) {
+                if min_size > max_size {
+                    let message = format!(
+                        "{} needs at least {} nodes, but the trees have at most {} nodes (max_tree_size + 1)",
+                        describe_rule(ctx, *r),
+                        min_size,
+                        max_size
+                    );
+                    let kind = DiagnosticKind::RuleTooLarge { min_size, max_size };
+                    res.push(Diagnostic::new(ctx, kind, *r, message));
+                }
+            }
+        }
+    }
+
+    res.sort_by_key(|d| d.rule.to_i());
+    return res;
+}
+
+//Smallest number of nodes of the trees of every productive nonterminal
+fn min_sizes(
+    ctx: &Context,
+    rules: &[RuleID],
+    defined: &HashMap<NTermID, RuleID>,
+) -> HashMap<NTermID, usize> {
+    let mut res: HashMap<NTermID, usize> = HashMap::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for r in rules.iter() {
+            if let Some(size) = rule_min_size(ctx, *r, &res, defined) {
+                let nt = ctx.get_nt(*r);
+                if res.get(&nt).map_or(true, |min| *min > size) {
+                    res.insert(nt, size);
+                    changed = true;
+                }
+            }
+        }
+    }
+    return res;
+}
+
+fn rule_min_size(
+    ctx: &Context,
+    r: RuleID,
+    min_sizes: &HashMap<NTermID, usize>,
+    defined: &HashMap<NTermID, RuleID>,
+) -> Option<usize> {
+    let mut res = 1;
+    for nt in ctx.get_rule(r).nonterms().iter() {
+        match min_sizes.get(nt) {
+            Some(size) => res += *size,
+            None if !defined.contains_key(nt) => res += 1,
+            None => return None,
+        }
+    }
+    return Some(res);
+}
+
+#[cfg(test)]
+mod tests {
+    use context::Context;
+    use diagnostic::{DiagnosticKind, Severity, SourceLocation};
+    use newtypes::RuleID;
+
+    #[test]
+    fn check_diagnostics() {
+        let mut ctx = Context::new();
+        let start = ctx.add_rule("START", "{A}{C}");
+        let a = ctx.add_rule("A", "a{B}");
+        let c = ctx.add_rule("C", "c{C}");
+        let d = ctx.add_rule("D", "d\\{\\}");
+        let dup = ctx.add_rule("A", "a{B}");
+        for (i, r) in [a, c, d, dup].iter().enumerate() {
+            ctx.set_rule_location(*r, SourceLocation::new("grammar.json", i, Some(i + 2)));
+        }
+        let b = ctx.nt_id("B");
+        let diagnostics = ctx.validate();
+        let kinds = diagnostics
+            .iter()
+            .map(|d| (d.rule, d.kind.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                //START needs C, which never terminates
+                (start, DiagnosticKind::UnproductiveNonterminal(ctx.nt_id("START"))),
+                (a, DiagnosticKind::UndefinedNonterminal(b)),
+                (c, DiagnosticKind::UnproductiveNonterminal(ctx.nt_id("C"))),
+                (d, DiagnosticKind::UnreachableNonterminal(ctx.nt_id("D"))),
+                (dup, DiagnosticKind::UndefinedNonterminal(b)),
+                (dup, DiagnosticKind::DuplicateRule(a)),
+            ]
+        );
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "error: START never derives a finite tree, each of its rules needs a nonterminal that never terminates"
+        );
+        assert_eq!(
+            diagnostics[1].to_string(),
+            "grammar.json:2: error: A -> \"a{B}\" uses the undefined nonterminal B"
+        );
+        assert_eq!(diagnostics[3].severity(), Severity::Warning);
+        assert_eq!(
+            diagnostics[5].to_string(),
+            "grammar.json:5: warning: A -> \"a{B}\" is a duplicate of the rule at grammar.json:2"
+        );
+    }
+
+    #[test]
+    fn check_duplicates_compare_bytes() {
+        let mut ctx = Context::new();
+        ctx.add_rule("START", "{A}");
+        ctx.add_term_rule("A", &vec![0x80]);
+        ctx.add_rule("A", "\u{80}");
+        assert!(ctx.validate().is_empty());
+        let dup = ctx.add_term_rule("A", &vec![0x80]);
+        let diagnostics = ctx.validate();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].rule, dup);
+        assert_eq!(diagnostics[0].kind, DiagnosticKind::DuplicateRule(RuleID::from(1)));
+    }
+
+    fn sized_context() -> Context {
+        let mut ctx = Context::new();
+        ctx.add_rule("START", "{E}");
+        ctx.add_rule("E", "{N}{N}{N}");
+        ctx.add_rule("E", "{N}");
+        ctx.add_rule("N", "n");
+        return ctx;
+    }
+
+    #[test]
+    fn check_rule_size() {
+        let ctx = sized_context();
+        assert!(ctx.validate().is_empty());
+        assert!(ctx.validate_for(3).is_empty());
+        let diagnostics = ctx.validate_for(2);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].rule, RuleID::from(1));
+        assert_eq!(
+            diagnostics[0].kind,
+            DiagnosticKind::RuleTooLarge {
+                min_size: 4,
+                max_size: 3,
+            }
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "needs at least 4 nodes")]
+    fn check_initialize_reports_errors() {
+        let mut ctx = sized_context();
+        ctx.initialize(2, false);
+    }
+}
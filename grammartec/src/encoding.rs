@@ -0,0 +1,137 @@
+use context::Context;
+use newtypes::{NTermID, RuleID};
+use rule::{NormalOrCustomRule, Rule, RuleChild};
+use tree::{Tree, TreeLike};
+
+//Compact binary encoding of trees: the rules in pre-order as LEB128 numbers. A normal rule is
+//stored as 2*id, a custom rule as 2*nonterminal+1 followed by the length and the bytes of its
+//terminal. Sizes and parents are not stored, they follow from the rules.
+pub fn encode(tree: &Tree) -> Vec<u8> {
+    let mut res = Vec::with_capacity(tree.size() * 2);
+    for rule in tree.rules.iter() {
+        match rule {
+            &NormalOrCustomRule::NormalRule(id) => write_number(id.to_i() << 1, &mut res),
+            &NormalOrCustomRule::CustomRule(ref rule) => {
+                write_number((rule.nonterm().to_i() << 1) | 1, &mut res);
+                let term = rule
+                    .children()
+                    .iter()
+                    .flat_map(|child| match child {
+                        &RuleChild::Term(ref data) | &RuleChild::CustomTerm(ref data) => data.iter(),
+                        &RuleChild::NTerm(_) => panic!("Custom rules contain only a terminal"),
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>();
+                write_number(term.len(), &mut res);
+                res.extend_from_slice(&term);
+            }
+        }
+    }
+    return res;
+}
+
+//Returns None unless data is exactly the encoding of a tree of ctx that derives start
+pub fn decode(data: &[u8], start: NTermID, ctx: &Context) -> Option<Tree> {
+    let mut data = data;
+    let mut rules = vec![];
+    let mut stack = vec![start];
+    while let Some(nt) = stack.pop() {
+        let number = read_number(&mut data)?;
+        let rule = if number & 1 == 0 {
+            let id = number >> 1;
+            if id >= ctx.get_num_rules() || ctx.get_nt(RuleID::from(id)) != nt {
+                return None;
+            }
+            NormalOrCustomRule::NormalRule(RuleID::from(id))
+        } else {
+            if number >> 1 != nt.to_i() {
+                return None;
+            }
+            let len = read_number(&mut data)?;
+            if len > data.len() {
+                return None;
+            }
+            let term = data[..len].to_vec();
+            data = &data[len..];
+            NormalOrCustomRule::CustomRule(Rule::from_custom_term(nt, term))
+        };
+        if let NormalOrCustomRule::NormalRule(id) = rule {
+            stack.extend(ctx.get_rule(id).nonterms().iter().rev());
+        }
+        rules.push(rule);
+    }
+    if !data.is_empty() {
+        return None;
+    }
+    return Some(Tree::from_rule_vec(rules, ctx));
+}
+
+fn write_number(mut n: usize, out: &mut Vec<u8>) {
+    while n >= 0x80 {
+        out.push((n & 0x7f) as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn read_number(data: &mut &[u8]) -> Option<usize> {
+    let mut res: usize = 0;
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+        if shift >= 64 {
+            return None;
+        }
+        res |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Some(res);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use context::Context;
+    use encoding::{decode, encode};
+    use newtypes::RuleID;
+    use rule::{NormalOrCustomRule, Rule};
+    use tree::{Tree, TreeLike};
+
+    #[test]
+    fn check_encode_decode() {
+        let mut ctx = Context::new();
+        ctx.add_rule("E", "({E}+{E})");
+        ctx.add_rule("E", "{N}");
+        ctx.add_rule("N", "1");
+        ctx.add_rule("N", "2");
+        ctx.initialize(50, false);
+        let nt = ctx.nt_id("E");
+        for _ in 0..100 {
+            let tree = ctx.generate_tree_from_nt(nt, 50);
+            let data = encode(&tree);
+            assert_eq!(data.len(), tree.size());
+            let decoded = decode(&data, nt, &ctx).expect("RAND_2130533829");
+            assert_eq!(decoded.rules, tree.rules);
+            assert_eq!(decoded.sizes, tree.sizes);
+            assert_eq!(decoded.paren, tree.paren);
+            //Truncated data, trailing data and the wrong start symbol are rejected
+            assert!(decode(&data[..data.len() - 1], nt, &ctx).is_none());
+            assert!(decode(&[&data[..], &[0]].concat(), nt, &ctx).is_none());
+            assert!(decode(&data, ctx.nt_id("N"), &ctx).is_none());
+        }
+        let custom = Rule::from_custom_term(ctx.nt_id("N"), b"42".to_vec());
+        let tree = Tree::from_rule_vec(
+            vec![
+                NormalOrCustomRule::NormalRule(RuleID::from(1)),
+                NormalOrCustomRule::CustomRule(custom),
+            ],
+            &ctx,
+        );
+        let decoded = decode(&encode(&tree), nt, &ctx).expect("RAND_3946385023");
+        assert_eq!(decoded.unparse_to_vec(&ctx), b"42");
+        assert!(decode(&[], nt, &ctx).is_none());
+        assert!(decode(&[0xff; 16], nt, &ctx).is_none());
+    }
+}
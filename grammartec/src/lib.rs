@@ -5,16 +5,37 @@ extern crate lazy_static;
 #[macro_use]
 extern crate serde_derive;
 extern crate afl_mutator;
+extern crate antlr_parser;
+extern crate byteorder;
 extern crate forksrv;
 extern crate loaded_dice;
-extern crate num;
+#[cfg(feature = "proptest")]
+extern crate proptest;
 extern crate rand;
+extern crate rayon;
 extern crate regex;
+#[cfg(test)]
+extern crate ron;
+extern crate serde;
+extern crate serde_json;
+extern crate tempfile;
 
+pub mod cache;
+pub mod choices;
 pub mod chunkstore;
 pub mod context;
+pub mod count;
+pub mod diagnostic;
+pub mod encoding;
+pub mod libfuzzer;
+pub mod loader;
 pub mod mutator;
 pub mod newtypes;
+pub mod parser;
 pub mod rule;
+#[cfg(feature = "proptest")]
+pub mod strategy;
+pub mod sync;
 pub mod tree;
 pub mod recursion_info;
+pub mod rng;
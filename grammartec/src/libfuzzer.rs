@@ -0,0 +1,244 @@
+use std::collections::HashSet;
+
+use rand::Rng;
+
+use choices::Unstructured;
+use chunkstore::ChunkStore;
+use context::Context;
+use encoding;
+use forksrv::error::SubprocessError;
+use mutator::Mutator;
+use newtypes::{NTermID, NodeID};
+use rng;
+use tree::{Tree, TreeLike, TreeMutation};
+
+//How often a mutation is retried if it didn't change the tree or its encoding didn't fit
+const MAX_MUTATION_ATTEMPTS: usize = 16;
+//The chunk store is emptied once it holds that many trees, it is filled again by the next mutations
+const MAX_CHUNK_TREES: usize = 4096;
+
+//Grammar aware LLVMFuzzerCustomMutator/LLVMFuzzerCustomCrossOver for libFuzzer and cargo-fuzz. The
+//libFuzzer input holds the tree in the encoding of encoding.rs. Inputs that are no valid encoding
+//(e.g. the empty input libFuzzer starts with) are turned into a tree Arbitrary style, so every
+//input stands for a tree of the grammar, unless the start nonterminal can't be derived within the
+//maximum tree size. Use fuzz_grammar! to define the libFuzzer callbacks.
+pub struct LibFuzzerMutator {
+    pub ctx: Context,
+    start: NTermID,
+    mutator: Mutator,
+    cks: ChunkStore,
+    //Encodings of the trees already in cks
+    chunks_added: HashSet<Vec<u8>>,
+}
+
+impl LibFuzzerMutator {
+    //ctx has to be initialized
+    pub fn new(ctx: Context, start: &str) -> Self {
+        let start = ctx.nt_id(start);
+        let mutator = Mutator::new(&ctx);
+        return LibFuzzerMutator {
+            ctx,
+            start,
+            mutator,
+            cks: ChunkStore::new(),
+            chunks_added: HashSet::new(),
+        };
+    }
+
+    pub fn tree(&self, data: &[u8]) -> Option<Tree> {
+        return encoding::decode(data, self.start, &self.ctx).or_else(|| {
+            self.ctx
+                .generate_tree_from_unstructured(self.start, &mut Unstructured::new(data))
+        });
+    }
+
+    //The input the fuzz target gets for data, empty if there is no tree for it
+    pub fn unparse(&self, data: &[u8]) -> Vec<u8> {
+        return self
+            .tree(data)
+            .map(|tree| tree.unparse_to_vec(&self.ctx))
+            .unwrap_or_default();
+    }
+
+    //Mutates the tree of data[..size] in place, returns the new size (at most data.len())
+    pub fn mutate(&mut self, data: &mut [u8], size: usize) -> usize {
+        let tree = match self.tree(&data[..size]) {
+            Some(tree) => tree,
+            None => return size,
+        };
+        //libFuzzer mostly mutates corpus entries, their trees are the chunks for splicing
+        let encoded = encoding::encode(&tree);
+        if !self.chunks_added.contains(&encoded) {
+            if self.cks.trees() >= MAX_CHUNK_TREES {
+                self.cks = ChunkStore::new();
+                self.chunks_added.clear();
+            }
+            self.cks.add_tree(tree.clone(), &self.ctx);
+            self.chunks_added.insert(encoded);
+        }
+        let recursions = tree.has_recursions(&self.ctx);
+        for _ in 0..MAX_MUTATION_ATTEMPTS {
+            let mut result = None;
+            {
+                let mut tester = |m: &TreeMutation, ctx: &Context| -> Result<(), SubprocessError> {
+                    result = Some(encoding::encode(&m.to_tree(ctx)));
+                    return Ok(());
+                };
+                let res = match (rng::rng().gen_range(0, 3), &recursions) {
+                    (0, &Some(ref recursions)) => {
+                        self.mutator
+                            .mut_random_recursion(&tree, recursions, &self.ctx, &mut tester)
+                    }
+                    (1, _) => self.mutator.mut_splice(&tree, &self.ctx, &self.cks, &mut tester),
+                    _ => self.mutator.mut_random(&tree, &self.ctx, &mut tester),
+                };
+                res.expect("RAND_1209858237");
+            }
+            if let Some(encoded) = result {
+                if encoded.len() <= data.len() {
+                    data[..encoded.len()].copy_from_slice(&encoded);
+                    return encoded.len();
+                }
+            }
+        }
+        return size;
+    }
+
+    //Replaces a random subtree of the tree of data1 with a subtree of the same nonterminal from the
+    //tree of data2. Returns the size written to out, 0 if there was no crossover that fits.
+    pub fn crossover(&mut self, data1: &[u8], data2: &[u8], out: &mut [u8]) -> usize {
+        let (tree1, tree2) = match (self.tree(data1), self.tree(data2)) {
+            (Some(tree1), Some(tree2)) => (tree1, tree2),
+            _ => return 0,
+        };
+        for _ in 0..MAX_MUTATION_ATTEMPTS {
+            let n1 = NodeID::from(rng::rng().gen_range(0, tree1.size()));
+            let nt = tree1.get_nonterm_id(n1, &self.ctx);
+            let candidates = (0..tree2.size())
+                .map(NodeID::from)
+                .filter(|n2| tree2.get_nonterm_id(*n2, &self.ctx) == nt)
+                .collect::<Vec<_>>();
+            if let Some(n2) = rng::rng().choose(&candidates) {
+                let repl = tree1.mutate_replace_from_tree(n1, &tree2, *n2);
+                let encoded = encoding::encode(&repl.to_tree(&self.ctx));
+                if encoded.len() <= out.len() {
+                    out[..encoded.len()].copy_from_slice(&encoded);
+                    return encoded.len();
+                }
+            }
+        }
+        return 0;
+    }
+}
+
+//Defines LLVMFuzzerCustomMutator and LLVMFuzzerCustomCrossOver with a LibFuzzerMutator in the thread
+//local $name, created from the initialized Context $ctx and the start nonterminal $start. The fuzz
+//target gets the input for the target with $name.with(|m| m.borrow().unparse(data)):
+//
+//fuzz_grammar!(GRAMMAR, my_context(), "START");
+//fuzz_target!(|data: &[u8]| {
+//    let input = GRAMMAR.with(|m| m.borrow().unparse(data));
+//    let _ = my_parser::parse(&input);
+//});
+//
+//The rng of the thread is reseeded with the seed libFuzzer passes to every call, so a run with a
+//fixed -seed is reproducible.
+#[macro_export]
+macro_rules! fuzz_grammar {
+    ($name:ident, $ctx:expr, $start:expr) => {
+        thread_local! {
+            static $name: ::std::cell::RefCell<$crate::libfuzzer::LibFuzzerMutator> =
+                ::std::cell::RefCell::new($crate::libfuzzer::LibFuzzerMutator::new($ctx, $start));
+        }
+
+        #[no_mangle]
+        pub extern "C" fn LLVMFuzzerCustomMutator(
+            data: *mut u8,
+            size: usize,
+            max_size: usize,
+            seed: u32,
+        ) -> usize {
+            $crate::rng::seed(seed as u64);
+            let data = unsafe { ::std::slice::from_raw_parts_mut(data, max_size) };
+            return $name.with(|m| m.borrow_mut().mutate(data, size));
+        }
+
+        #[no_mangle]
+        pub extern "C" fn LLVMFuzzerCustomCrossOver(
+            data1: *const u8,
+            size1: usize,
+            data2: *const u8,
+            size2: usize,
+            out: *mut u8,
+            max_out_size: usize,
+            seed: u32,
+        ) -> usize {
+            $crate::rng::seed(seed as u64);
+            let data1 = unsafe { ::std::slice::from_raw_parts(data1, size1) };
+            let data2 = unsafe { ::std::slice::from_raw_parts(data2, size2) };
+            let out = unsafe { ::std::slice::from_raw_parts_mut(out, max_out_size) };
+            return $name.with(|m| m.borrow_mut().crossover(data1, data2, out));
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use context::Context;
+    use encoding;
+    use libfuzzer::{LibFuzzerMutator, MAX_CHUNK_TREES};
+    use tree::TreeLike;
+
+    fn mutator() -> LibFuzzerMutator {
+        let mut ctx = Context::new();
+        ctx.add_rule("START", "{E}");
+        ctx.add_rule("E", "({E}+{E})");
+        ctx.add_rule("E", "{N}");
+        ctx.add_rule("N", "1");
+        ctx.add_rule("N", "2");
+        ctx.initialize(30, false);
+        return LibFuzzerMutator::new(ctx, "START");
+    }
+
+    #[test]
+    fn check_mutate() {
+        let mut m = mutator();
+        let mut data = vec![0u8; 64];
+        let mut size = 0;
+        for _ in 0..200 {
+            size = m.mutate(&mut data, size);
+            assert!(size <= data.len());
+            let tree = encoding::decode(&data[..size], m.ctx.nt_id("START"), &m.ctx)
+                .expect("RAND_2662924064");
+            assert_eq!(m.unparse(&data[..size]), tree.unparse_to_vec(&m.ctx));
+        }
+        //Nothing fits into an empty buffer, the input is left as it is
+        assert_eq!(m.mutate(&mut [], 0), 0);
+    }
+
+    #[test]
+    fn check_crossover() {
+        let mut m = mutator();
+        let data1 = encoding::encode(&m.tree(b"\x05\x01\x02").expect("RAND_3582714013"));
+        let data2 = encoding::encode(&m.tree(b"\x07\x03\x03\x01").expect("RAND_2280315346"));
+        let mut out = vec![0u8; 64];
+        for _ in 0..100 {
+            let size = m.crossover(&data1, &data2, &mut out);
+            assert!(size > 0);
+            assert!(encoding::decode(&out[..size], m.ctx.nt_id("START"), &m.ctx).is_some());
+        }
+        assert_eq!(m.crossover(&data1, &data2, &mut []), 0);
+    }
+
+    #[test]
+    fn check_chunks_are_capped() {
+        let mut m = mutator();
+        let mut data = vec![0u8; 64];
+        let mut size = 0;
+        for _ in 0..MAX_CHUNK_TREES + 100 {
+            size = m.mutate(&mut data, size);
+            assert!(m.cks.trees() <= MAX_CHUNK_TREES);
+            assert_eq!(m.cks.trees(), m.chunks_added.len());
+        }
+    }
+}
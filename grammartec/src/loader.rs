@@ -0,0 +1,532 @@
+use antlr_parser::AntlrParser;
+use serde_json::{self, Map, Value};
+
+use std::collections::hash_map::DefaultHasher;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read};
+
+use cache;
+use context::Context;
+use diagnostic::{Diagnostic, SourceLocation};
+use rule::RuleChild;
+
+//Grammars are loaded in two steps: a GrammarSource reads the rules of one grammar format, then
+//load adds the START rule, checks the grammar and initializes the context. New formats only have
+//to implement GrammarSource and be added to source_for_path.
+
+//Part of the body of a rule
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Symbol {
+    Term(Vec<u8>),
+    NTerm(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuleBody {
+    //In the format of Context::add_rule, e.g. "({E}+{E})"
+    Format(String),
+    Symbols(Vec<Symbol>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceRule {
+    pub nt: String,
+    pub body: RuleBody,
+    pub location: SourceLocation,
+}
+
+pub trait GrammarSource {
+    fn path(&self) -> &str;
+
+    //The rules in the order of the grammar file
+    fn read_rules(&self) -> Result<Vec<SourceRule>, LoadError>;
+
+    //Identifies the grammar in context caches and saved states
+    fn hash(&self) -> Result<u64, LoadError> {
+        let content = read_file(self.path())?;
+        let mut s = DefaultHasher::new();
+        content.hash(&mut s);
+        return Ok(s.finish());
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(String, io::Error),
+    UnknownFormat(String),
+    //The file is not valid in its format at all
+    Syntax(String, String),
+    InvalidRule(SourceLocation, String),
+    NoRules(String),
+    UnknownStart(String),
+    //The errors that keep the context from being initialized
+    InvalidGrammar(Vec<Diagnostic>),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match *self {
+            LoadError::Io(ref path, ref e) => write!(f, "cannot read {}: {}", path, e),
+            LoadError::UnknownFormat(ref path) => {
+                write!(f, "{}: unknown grammar type, expected .json or .g4", path)
+            }
+            LoadError::Syntax(ref path, ref msg) => write!(f, "{}: {}", path, msg),
+            LoadError::InvalidRule(ref location, ref msg) => {
+                write!(f, "{}: invalid rule: {}", location, msg)
+            }
+            LoadError::NoRules(ref path) => write!(f, "{}: the grammar has no rules", path),
+            LoadError::UnknownStart(ref nt) => {
+                write!(f, "the start symbol {} has no rules", nt)
+            }
+            LoadError::InvalidGrammar(ref errors) => {
+                write!(f, "invalid grammar:")?;
+                for e in errors.iter() {
+                    write!(f, "\n{}", e)?;
+                }
+                Ok(())
+            }
+        };
+    }
+}
+
+impl error::Error for LoadError {
+    fn description(&self) -> &str {
+        return "cannot load grammar";
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LoadOptions {
+    //Nonterminal that is derived by START, the nonterminal of the first rule if None
+    pub start: Option<String>,
+    pub max_tree_size: usize,
+    pub dumb: bool,
+    pub lazy_samplers: bool,
+    //Context cache, see cache::initialize_with_cache. The counts are computed every time if None.
+    pub cache_path: Option<String>,
+    pub verbose: bool,
+}
+
+impl LoadOptions {
+    pub fn new(max_tree_size: usize) -> Self {
+        return LoadOptions {
+            start: None,
+            max_tree_size,
+            dumb: false,
+            lazy_samplers: false,
+            cache_path: None,
+            verbose: false,
+        };
+    }
+}
+
+//The source for the format of the file at path, chosen by its extension
+pub fn source_for_path(path: &str) -> Result<Box<dyn GrammarSource>, LoadError> {
+    if path.ends_with(".json") {
+        return Ok(Box::new(JsonGrammar::new(path)));
+    }
+    if path.ends_with(".g4") {
+        return Ok(Box::new(AntlrGrammar::new(path)));
+    }
+    return Err(LoadError::UnknownFormat(path.to_string()));
+}
+
+//Context with START and the rules of the grammar, without checking or initializing it
+pub fn load_rules(source: &dyn GrammarSource, options: &LoadOptions) -> Result<Context, LoadError> {
+    let rules = source.read_rules()?;
+    let start = match options.start {
+        Some(ref nt) => {
+            if !rules.iter().any(|r| r.nt == *nt) {
+                return Err(LoadError::UnknownStart(nt.clone()));
+            }
+            nt.clone()
+        }
+        None => match rules.first() {
+            Some(r) => r.nt.clone(),
+            None => return Err(LoadError::NoRules(source.path().to_string())),
+        },
+    };
+    let mut ctx = Context::with_dump(options.dumb);
+    ctx.set_lazy_samplers(options.lazy_samplers);
+    //START is always the first nonterminal, i.e. NTermID 1
+    ctx.add_rule("START", &("{".to_string() + &start + "}"));
+    for rule in rules {
+        let r = match rule.body {
+            RuleBody::Format(format) => ctx.add_rule(&rule.nt, &format),
+            RuleBody::Symbols(symbols) => {
+                let children = symbols
+                    .into_iter()
+                    .map(|s| match s {
+                        Symbol::Term(data) => RuleChild::Term(data),
+                        Symbol::NTerm(nt) => RuleChild::NTerm(ctx.aquire_nt_id(&nt)),
+                    })
+                    .collect();
+                ctx.add_rule_with_children(&rule.nt, children)
+            }
+        };
+        ctx.set_rule_location(r, rule.location);
+    }
+    return Ok(ctx);
+}
+
+//Initialized context for the grammar of source
+pub fn load(source: &dyn GrammarSource, options: &LoadOptions) -> Result<Context, LoadError> {
+    let mut ctx = load_rules(source, options)?;
+    let errors = ctx.errors_for(options.max_tree_size);
+    if errors.len() > 0 {
+        return Err(LoadError::InvalidGrammar(errors));
+    }
+    match options.cache_path {
+        Some(ref path) => {
+            let mut hash = source.hash()?;
+            //Another start symbol gives another START rule, whose counts must not be reused
+            if let Some(ref start) = options.start {
+                let mut s = DefaultHasher::new();
+                (hash, start).hash(&mut s);
+                hash = s.finish();
+            }
+            cache::initialize_with_cache(&mut ctx, options.max_tree_size, options.verbose, path, hash);
+        }
+        None => ctx.initialize(options.max_tree_size, options.verbose),
+    }
+    return Ok(ctx);
+}
+
+//Initialized context for the grammar file at path
+pub fn load_path(path: &str, options: &LoadOptions) -> Result<Context, LoadError> {
+    return load(&*source_for_path(path)?, options);
+}
+
+fn read_file(path: &str) -> Result<String, LoadError> {
+    let mut content = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .map_err(|e| LoadError::Io(path.to_string(), e))?;
+    return Ok(content);
+}
+
+//JSON grammars contain
+//  - rules in the format of Context::add_rule: [["NT", "text {OTHER} text"], ...]
+//  - structured rules, whose terminals can contain any bytes:
+//    [{"nt": "NT", "body": [{"t": "text"}, {"hex": "00ff"}, {"nt": "OTHER"}], "meta": {...}}, ...]
+//    "meta" is optional and can hold anything (comments, sources, ...), it is not used here.
+//Both kinds of rules can be mixed in one file.
+pub struct JsonGrammar {
+    path: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StructuredRule {
+    nt: String,
+    body: Vec<Item>,
+    #[serde(default, rename = "meta")]
+    _meta: Option<Map<String, Value>>,
+}
+
+#[derive(Deserialize)]
+enum Item {
+    #[serde(rename = "t")]
+    Term(String),
+    #[serde(rename = "hex")]
+    Hex(String),
+    #[serde(rename = "nt")]
+    NTerm(String),
+}
+
+impl JsonGrammar {
+    pub fn new(path: &str) -> Self {
+        return JsonGrammar {
+            path: path.to_string(),
+        };
+    }
+}
+
+impl GrammarSource for JsonGrammar {
+    fn path(&self) -> &str {
+        return &self.path;
+    }
+
+    fn read_rules(&self) -> Result<Vec<SourceRule>, LoadError> {
+        return parse_json_rules(&self.path, &read_file(&self.path)?);
+    }
+}
+
+fn parse_json_rules(path: &str, content: &str) -> Result<Vec<SourceRule>, LoadError> {
+    let values: Vec<Value> = serde_json::from_str(content)
+        .map_err(|e| LoadError::Syntax(path.to_string(), e.to_string()))?;
+    let lines = rule_lines(content);
+    let mut res = vec![];
+    for (i, value) in values.into_iter().enumerate() {
+        let location = SourceLocation::new(path, i, lines.get(i).cloned());
+        let (nt, body) = match value {
+            Value::Object(_) => match serde_json::from_value::<StructuredRule>(value) {
+                Ok(rule) => match structured_symbols(rule.body) {
+                    Ok(symbols) => (rule.nt, RuleBody::Symbols(symbols)),
+                    Err(msg) => return Err(LoadError::InvalidRule(location, msg)),
+                },
+                Err(e) => return Err(LoadError::InvalidRule(location, e.to_string())),
+            },
+            _ => match serde_json::from_value::<(String, String)>(value) {
+                Ok((nt, format)) => (nt, RuleBody::Format(format)),
+                Err(e) => return Err(LoadError::InvalidRule(location, e.to_string())),
+            },
+        };
+        res.push(SourceRule { nt, body, location });
+    }
+    return Ok(res);
+}
+
+//Adjacent terminals are merged into one
+fn structured_symbols(body: Vec<Item>) -> Result<Vec<Symbol>, String> {
+    let mut res = vec![];
+    let mut term = vec![];
+    for item in body {
+        match item {
+            Item::Term(text) => term.extend_from_slice(text.as_bytes()),
+            Item::Hex(hex) => match decode_hex(&hex) {
+                Some(bytes) => term.extend(bytes),
+                None => return Err(format!("invalid hex string {:?}", hex)),
+            },
+            Item::NTerm(nt) => {
+                if term.len() > 0 {
+                    res.push(Symbol::Term(term));
+                    term = vec![];
+                }
+                res.push(Symbol::NTerm(nt));
+            }
+        }
+    }
+    if term.len() > 0 {
+        res.push(Symbol::Term(term));
+    }
+    return Ok(res);
+}
+
+//Pairs of hex digits, whitespace between the pairs is ignored
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    let digits = hex
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_digit(16))
+        .collect::<Option<Vec<_>>>()?;
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+    return Some(digits.chunks(2).map(|d| (d[0] * 16 + d[1]) as u8).collect());
+}
+
+//Line of every rule of a JSON grammar, i.e. of every array or object in the top level array
+fn rule_lines(content: &str) -> Vec<usize> {
+    let mut res = vec![];
+    let mut line = 1;
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in content.chars() {
+        if c == '\n' {
+            line += 1;
+        }
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '[' | '{' => {
+                depth += 1;
+                if depth == 2 {
+                    res.push(line);
+                }
+            }
+            ']' | '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    return res;
+}
+
+//ANTLR grammars (.g4), converted to rules by the antlr_parser crate. Its rules carry no line
+//numbers, so they are located by their index.
+pub struct AntlrGrammar {
+    path: String,
+}
+
+impl AntlrGrammar {
+    pub fn new(path: &str) -> Self {
+        return AntlrGrammar {
+            path: path.to_string(),
+        };
+    }
+}
+
+impl GrammarSource for AntlrGrammar {
+    fn path(&self) -> &str {
+        return &self.path;
+    }
+
+    fn read_rules(&self) -> Result<Vec<SourceRule>, LoadError> {
+        //The parser panics on files it can't open, so missing files are reported here first
+        File::open(&self.path).map_err(|e| LoadError::Io(self.path.clone(), e))?;
+        let mut parser = AntlrParser::new();
+        parser.parse_antlr_grammar(&self.path);
+        return Ok(parser
+            .rules
+            .into_iter()
+            .enumerate()
+            .map(|(i, (nt, format))| SourceRule {
+                nt,
+                body: RuleBody::Format(format),
+                location: SourceLocation::new(&self.path, i, None),
+            })
+            .collect());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use loader::{
+        decode_hex, load, load_rules, parse_json_rules, rule_lines, GrammarSource, LoadError,
+        LoadOptions, RuleBody, SourceRule, Symbol,
+    };
+    use diagnostic::{DiagnosticKind, SourceLocation};
+    use parser::Parser;
+    use tree::TreeLike;
+
+    //Grammar that is kept in memory instead of a file
+    struct InlineGrammar {
+        content: &'static str,
+    }
+
+    impl GrammarSource for InlineGrammar {
+        fn path(&self) -> &str {
+            return "grammar.json";
+        }
+
+        fn read_rules(&self) -> Result<Vec<SourceRule>, LoadError> {
+            return parse_json_rules(self.path(), self.content);
+        }
+
+        fn hash(&self) -> Result<u64, LoadError> {
+            return Ok(0);
+        }
+    }
+
+    #[test]
+    fn check_decode_hex() {
+        assert_eq!(decode_hex("00ff 7B"), Some(vec![0, 0xff, 0x7b]));
+        assert_eq!(decode_hex(""), Some(vec![]));
+        assert_eq!(decode_hex("0"), None);
+        assert_eq!(decode_hex("0g"), None);
+    }
+
+    #[test]
+    fn check_rule_lines() {
+        let content = "[\n  [\"A\", \"[{B}]\"],\n\n  {\"nt\": \"B\",\n   \"body\": [{\"t\": \"\\\"[\"}]}, [\"B\", \"b\"]\n]\n";
+        assert_eq!(rule_lines(content), vec![2, 4, 5]);
+    }
+
+    #[test]
+    fn check_structured_grammar() {
+        let source = InlineGrammar {
+            content: r#"[
+            {"nt": "MSG", "body": [{"hex": "00ff"}, {"t": "{"}, {"nt": "LEN"}, {"t": "}\\"}], "meta": {"comment": "header"}},
+            ["LEN", "{DIGIT}{LEN}"],
+            {"nt": "LEN", "body": [{"nt": "DIGIT"}]},
+            ["DIGIT", "1"],
+            {"nt": "DIGIT", "body": [{"hex": "80"}]}
+        ]"#,
+        };
+        let rules = source.read_rules().expect("RAND_3346204781");
+        assert_eq!(
+            rules[0].body,
+            RuleBody::Symbols(vec![
+                Symbol::Term(b"\x00\xff{".to_vec()),
+                Symbol::NTerm("LEN".to_string()),
+                Symbol::Term(b"}\\".to_vec()),
+            ])
+        );
+        let ctx = load(&source, &LoadOptions::new(10)).expect("RAND_1809436102");
+        assert_eq!(ctx.get_num_rules(), 6);
+        let location = ctx.get_rule_location(1.into()).expect("RAND_2470836159");
+        assert_eq!(location.to_string(), "grammar.json:2");
+        assert!(ctx.validate().is_empty());
+        let start = ctx.nt_id("START");
+        for _ in 0..100 {
+            let len = ctx.get_random_len_for_nt(&start);
+            let tree = ctx.generate_tree_from_nt(start, len);
+            let data = tree.unparse_to_vec(&ctx);
+            assert_eq!(&data[..3], b"\x00\xff{");
+            assert_eq!(&data[data.len() - 2..], b"}\\");
+            //The binary terminals are parsed like any other
+            let parsed = Parser::new(&ctx)
+                .parse(start, &data)
+                .expect("RAND_2861707291");
+            assert_eq!(parsed.unparse_to_vec(&ctx), data);
+        }
+    }
+
+    #[test]
+    fn check_start_symbol() {
+        let source = InlineGrammar {
+            content: r#"[["A", "a{B}"], ["B", "b"]]"#,
+        };
+        let mut options = LoadOptions::new(10);
+        let ctx = load_rules(&source, &options).expect("RAND_1440372215");
+        assert_eq!(ctx.get_rule(0.into()).nonterms(), &vec![ctx.nt_id("A")]);
+        options.start = Some("B".to_string());
+        let ctx = load_rules(&source, &options).expect("RAND_4203588117");
+        assert_eq!(ctx.get_rule(0.into()).nonterms(), &vec![ctx.nt_id("B")]);
+        options.start = Some("C".to_string());
+        match load_rules(&source, &options) {
+            Err(LoadError::UnknownStart(ref nt)) if nt == "C" => {}
+            _ => panic!("the unknown start symbol was not reported"),
+        }
+    }
+
+    #[test]
+    fn check_load_errors() {
+        let source = InlineGrammar {
+            content: "[\n  [\"A\", \"a\"],\n  {\"nt\": \"A\", \"body\": [{\"text\": \"a\"}]}\n]",
+        };
+        match load(&source, &LoadOptions::new(10)) {
+            Err(e @ LoadError::InvalidRule(..)) => {
+                assert!(e.to_string().starts_with("grammar.json:3: invalid rule"))
+            }
+            _ => panic!("the invalid rule was not reported"),
+        }
+
+        let source = InlineGrammar { content: "[]" };
+        match load(&source, &LoadOptions::new(10)) {
+            Err(LoadError::NoRules(_)) => {}
+            _ => panic!("the empty grammar was not reported"),
+        }
+
+        let source = InlineGrammar {
+            content: r#"[["A", "{B}"]]"#,
+        };
+        match load(&source, &LoadOptions::new(10)) {
+            Err(LoadError::InvalidGrammar(errors)) => {
+                assert_eq!(errors.len(), 1);
+                assert_eq!(
+                    errors[0].location,
+                    Some(SourceLocation::new("grammar.json", 0, Some(1)))
+                );
+                match errors[0].kind {
+                    DiagnosticKind::UndefinedNonterminal(_) => {}
+                    _ => panic!("unexpected diagnostic {}", errors[0]),
+                }
+            }
+            _ => panic!("the undefined nonterminal was not reported"),
+        }
+    }
+}
@@ -3,12 +3,14 @@ extern crate rand;
 
 use rand::Rng;
 
-use std::collections::HashSet;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
 use std::mem;
 
 use chunkstore::ChunkStore;
 use context::Context;
 use newtypes::NodeID;
+use rng;
 use rule::{NormalOrCustomRule, Rule};
 use tree::{Tree, TreeLike, TreeMutation};
 
@@ -97,6 +99,153 @@ impl Mutator {
         return Ok(true);
     }
 
+    //Shrinks a tree as far as possible while tester keeps returning true (e.g. for crashes). Runs
+    //hierarchical delta debugging, minimize_rec and minimize_tree until none of them makes progress.
+    pub fn minimize_crash<F>(
+        &mut self,
+        tree: &mut Tree,
+        ctx: &Context,
+        tester: &mut F,
+    ) -> Result<(), SubprocessError>
+    where
+        F: FnMut(&TreeMutation, &HashSet<usize>, &Context) -> Result<bool, SubprocessError>,
+    {
+        let no_bits = HashSet::new();
+        loop {
+            //Every accepted reduction makes the tree smaller, therefore this terminates
+            let old_size = tree.size();
+            self.minimize_hdd(tree, ctx, tester)?;
+            self.minimize_rec(tree, &no_bits, ctx, 0, usize::max_value(), tester)?;
+            self.minimize_tree(tree, &no_bits, ctx, 0, usize::max_value(), tester)?;
+            if tree.size() >= old_size {
+                return Ok(());
+            }
+        }
+    }
+
+    //Hierarchical delta debugging: goes through the tree level by level and uses ddmin to find a
+    //small set of nodes on that level that has to be kept. All other nodes of the level are
+    //replaced by minimal subtrees at once, which is a lot faster than trying them one by one.
+    pub fn minimize_hdd<F>(
+        &mut self,
+        tree: &mut Tree,
+        ctx: &Context,
+        tester: &mut F,
+    ) -> Result<(), SubprocessError>
+    where
+        F: FnMut(&TreeMutation, &HashSet<usize>, &Context) -> Result<bool, SubprocessError>,
+    {
+        let no_bits = HashSet::new();
+        let mut minimal_trees = HashMap::new();
+        let mut level = 0;
+        loop {
+            let mut depths = vec![0; tree.size()];
+            for i in 1..tree.size() {
+                depths[i] = depths[tree.paren[i].to_i()] + 1;
+            }
+            //Nodes of the same level are never nested, so they can be replaced independently
+            let candidates = (0..tree.size())
+                .filter(|i| depths[*i] == level)
+                .map(NodeID::from)
+                .collect::<Vec<_>>();
+            if candidates.is_empty() {
+                return Ok(());
+            }
+            let candidates = candidates
+                .into_iter()
+                .filter(|n| {
+                    tree.subtree_size(*n) > ctx.get_min_len_for_nt(tree.get_rule(*n, ctx).nonterm())
+                })
+                .collect::<Vec<_>>();
+            for n in candidates.iter() {
+                let nt = tree.get_rule(*n, ctx).nonterm();
+                minimal_trees
+                    .entry(nt)
+                    .or_insert_with(|| ctx.generate_tree_from_nt(nt, ctx.get_min_len_for_nt(nt)));
+            }
+
+            let mut test = |keep: &[NodeID]| -> Result<Option<Tree>, SubprocessError> {
+                let replace = candidates
+                    .iter()
+                    .filter(|n| !keep.contains(n))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let mut rules = Vec::with_capacity(tree.size());
+                let mut i = 0;
+                while i < tree.size() {
+                    let n = NodeID::from(i);
+                    if replace.contains(&n) {
+                        let nt = tree.get_rule(n, ctx).nonterm();
+                        rules.extend_from_slice(&minimal_trees[&nt].rules);
+                        i += tree.subtree_size(n);
+                    } else {
+                        rules.push(tree.get_normal_rule_or_custom_rule(n).clone());
+                        i += 1;
+                    }
+                }
+                let new_tree = Tree::from_rule_vec(rules, ctx);
+                let repl = TreeMutation {
+                    prefix: &new_tree.rules,
+                    repl: &[],
+                    postfix: &[],
+                };
+                if tester(&repl, &no_bits, ctx)? {
+                    return Ok(Some(new_tree));
+                }
+                return Ok(None);
+            };
+
+            //ddmin over the candidates, keep holds the nodes that could not be replaced (yet)
+            let mut keep = candidates.clone();
+            let mut best = None;
+            if let Some(t) = test(&[])? {
+                keep.clear();
+                best = Some(t);
+            }
+            let mut granularity = 2;
+            while keep.len() >= 2 {
+                let chunk_len = (keep.len() + granularity - 1) / granularity;
+                let chunks = keep.chunks(chunk_len).map(|c| c.to_vec()).collect::<Vec<_>>();
+                let mut reduced = false;
+                for chunk in chunks.iter() {
+                    if let Some(t) = test(chunk)? {
+                        keep = chunk.clone();
+                        best = Some(t);
+                        granularity = 2;
+                        reduced = true;
+                        break;
+                    }
+                }
+                if !reduced && chunks.len() > 2 {
+                    for chunk in chunks.iter() {
+                        let complement = keep
+                            .iter()
+                            .filter(|n| !chunk.contains(n))
+                            .cloned()
+                            .collect::<Vec<_>>();
+                        if let Some(t) = test(&complement)? {
+                            keep = complement;
+                            best = Some(t);
+                            granularity = cmp::max(granularity - 1, 2);
+                            reduced = true;
+                            break;
+                        }
+                    }
+                }
+                if !reduced {
+                    if granularity >= keep.len() {
+                        break;
+                    }
+                    granularity = cmp::min(granularity * 2, keep.len());
+                }
+            }
+            if let Some(t) = best {
+                *tree = t;
+            }
+            level += 1;
+        }
+    }
+
     pub fn mut_rules<F>(
         &mut self,
         tree: &Tree,
@@ -184,7 +333,7 @@ impl Mutator {
     where
         F: FnMut(&TreeMutation, &Context) -> Result<(), SubprocessError>,
     {
-        let n = NodeID::from(rand::thread_rng().gen_range(0, tree.size()));
+        let n = NodeID::from(rng::rng().gen_range(0, tree.size()));
         match tree.get_rule_id(n) {
             Some(old_rule_id) => {
                 if let Some((repl_tree, repl_node)) = cks.get_alternative_to(old_rule_id, ctx) {
@@ -208,7 +357,7 @@ impl Mutator {
     //where
     //    F: FnMut(&TreeMutation, &Context) -> Result<(), SubprocessError>,
     //{
-    //    let n = NodeID::from(rand::thread_rng().gen_range(0, tree.size()));
+    //    let n = NodeID::from(rng::rng().gen_range(0, tree.size()));
     //    if let Some(old_rule_id) = tree.get_rule_id(n){
     //        let nterm_id = ctx.get_rule(old_rule).nonterm();
     //        if let Some((repl_tree, repl_node)) = cks.get_alternative_to(old_rule_id, ctx) {
@@ -230,7 +379,7 @@ impl Mutator {
     where
         F: FnMut(&TreeMutation, &Context) -> Result<(), SubprocessError>,
     {
-        let n = NodeID::from(rand::thread_rng().gen_range(0, tree.size()));
+        let n = NodeID::from(rng::rng().gen_range(0, tree.size()));
         let nterm = tree.get_rule(n, ctx).nonterm();
         if ctx.check_if_nterm_has_multiple_possiblities(&nterm) {
             let len = ctx.get_random_len_for_nt(&nterm);
@@ -251,8 +400,8 @@ impl Mutator {
     where
         F: FnMut(&TreeMutation, &Context) -> Result<(), SubprocessError>,
     {
-        let max_len_of_recursions = 2 << rand::thread_rng().gen_range(1, 11);
-        if let Some(recursion) = rand::thread_rng().choose(&recursions) {
+        let max_len_of_recursions = 2 << rng::rng().gen_range(1, 11);
+        if let Some(recursion) = rng::rng().choose(&recursions) {
             let recursion_len_pre = recursion.1.to_i() - recursion.0.to_i();
             let recursion_len_total =
                 tree.subtree_size(recursion.0) - tree.subtree_size(recursion.1);
@@ -351,6 +500,7 @@ mod tests {
     use context::Context;
     use mutator::Mutator;
     use newtypes::{NodeID, RuleID};
+    use parser::Parser;
     use rule::NormalOrCustomRule;
     use std::collections::HashSet;
     use std::str;
@@ -529,6 +679,38 @@ mod tests {
         }
     }
 
+    #[test]
+    fn check_minimize_crash() {
+        let mut ctx = Context::new();
+        ctx.add_rule("S", "{L}");
+        ctx.add_rule("L", "{L};{A}{A}");
+        ctx.add_rule("L", "{A}");
+        ctx.add_rule("A", "a{B}");
+        ctx.add_rule("A", "x");
+        ctx.add_rule("A", "a");
+        ctx.add_rule("B", "b{B}");
+        ctx.add_rule("B", "b");
+        ctx.initialize(20, false);
+        let input = b"abbb;abx;aabbb;ax;abbbbbab;aa";
+        let mut tree = Parser::new(&ctx)
+            .parse(ctx.nt_id("S"), input)
+            .expect("RAND_3180316421");
+        let mut mutator = Mutator::new(&ctx);
+        let mut executions = 0;
+        {
+            let mut tester = |tree_mut: &TreeMutation, _bits: &HashSet<usize>, ctx: &Context| {
+                executions += 1;
+                return Ok(tree_mut.unparse_to_vec(ctx).contains(&b'x'));
+            };
+            mutator
+                .minimize_crash(&mut tree, &ctx, &mut tester)
+                .expect("RAND_1523072357");
+        }
+        let unparse = tree.unparse_to_vec(&ctx);
+        assert_eq!(unparse, b"x");
+        assert!(executions < 200);
+    }
+
     #[test]
     fn deterministic_rule() {
         let mut ctx = Context::new();
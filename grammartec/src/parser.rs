@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+
+use context::Context;
+use newtypes::{NTermID, RuleID};
+use rule::{NormalOrCustomRule, RuleChild};
+use tree::Tree;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    //Farthest byte offset up to which the input was a valid prefix
+    pub offset: usize,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "parse error at byte offset {}", self.offset);
+    }
+}
+
+impl error::Error for ParseError {
+    fn description(&self) -> &str {
+        return "input does not match the grammar";
+    }
+}
+
+type ItemIdx = usize;
+
+//How an item got its dot moved over the last child
+#[derive(Clone, Copy)]
+enum Step {
+    Start,
+    Term,
+    NTerm(ItemIdx),
+    Null(NTermID),
+}
+
+struct Item {
+    rule: RuleID,
+    dot: usize,
+    origin: usize,
+    prev: Option<ItemIdx>,
+    step: Step,
+}
+
+struct EarleySet {
+    items: Vec<ItemIdx>,
+    seen: HashMap<(RuleID, usize, usize), ItemIdx>,
+    //Items that wait for the given nonterminal to be completed
+    waiting: HashMap<NTermID, Vec<ItemIdx>>,
+}
+
+impl EarleySet {
+    fn new() -> Self {
+        return EarleySet {
+            items: vec![],
+            seen: HashMap::new(),
+            waiting: HashMap::new(),
+        };
+    }
+}
+
+//Earley parser that turns bytes back into trees of a given Context. Handles left recursion,
+//nullable nonterminals and ambiguous grammars (for the latter one derivation is picked).
+pub struct Parser<'a> {
+    ctx: &'a Context,
+    nts_to_rules: HashMap<NTermID, Vec<RuleID>>,
+    //For each nullable nonterminal a rule that derives the empty word without recursion
+    null_rules: HashMap<NTermID, RuleID>,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(ctx: &'a Context) -> Self {
+        let mut nts_to_rules = HashMap::new();
+        for i in 0..ctx.get_num_rules() {
+            let rid = RuleID::from(i);
+            nts_to_rules
+                .entry(ctx.get_nt(rid))
+                .or_insert_with(|| vec![])
+                .push(rid);
+        }
+        let mut null_rules = HashMap::new();
+        let mut something_changed = true;
+        while something_changed {
+            something_changed = false;
+            for i in 0..ctx.get_num_rules() {
+                let rid = RuleID::from(i);
+                let nt = ctx.get_nt(rid);
+                if null_rules.contains_key(&nt) {
+                    continue;
+                }
+                let nullable = ctx.get_rule(rid).children().iter().all(|c| match c {
+                    &RuleChild::Term(ref data) | &RuleChild::CustomTerm(ref data) => data.is_empty(),
+                    &RuleChild::NTerm(n) => null_rules.contains_key(&n),
+                });
+                if nullable {
+                    null_rules.insert(nt, rid);
+                    something_changed = true;
+                }
+            }
+        }
+        return Parser {
+            ctx,
+            nts_to_rules,
+            null_rules,
+        };
+    }
+
+    pub fn parse(&self, start: NTermID, input: &[u8]) -> Result<Tree, ParseError> {
+        let mut items: Vec<Item> = vec![];
+        let mut sets: Vec<EarleySet> = (0..input.len() + 1).map(|_| EarleySet::new()).collect();
+
+        if let Some(rules) = self.nts_to_rules.get(&start) {
+            for rid in rules.iter() {
+                add(
+                    &mut items,
+                    &mut sets[0],
+                    Item {
+                        rule: *rid,
+                        dot: 0,
+                        origin: 0,
+                        prev: None,
+                        step: Step::Start,
+                    },
+                );
+            }
+        }
+
+        let mut farthest = 0;
+        for pos in 0..sets.len() {
+            if !sets[pos].items.is_empty() {
+                farthest = pos;
+            }
+            //The set grows while it is processed, therefore iterate by index
+            let mut i = 0;
+            while i < sets[pos].items.len() {
+                let idx = sets[pos].items[i];
+                i += 1;
+                let (rule, dot, origin) = (items[idx].rule, items[idx].dot, items[idx].origin);
+                match self.ctx.get_rule(rule).children().get(dot) {
+                    //Scan
+                    Some(&RuleChild::Term(ref data)) | Some(&RuleChild::CustomTerm(ref data)) => {
+                        if input[pos..].starts_with(data) {
+                            let end = pos + data.len();
+                            add(
+                                &mut items,
+                                &mut sets[end],
+                                Item {
+                                    rule,
+                                    dot: dot + 1,
+                                    origin,
+                                    prev: Some(idx),
+                                    step: Step::Term,
+                                },
+                            );
+                        }
+                    }
+                    //Predict
+                    Some(&RuleChild::NTerm(nt)) => {
+                        sets[pos].waiting.entry(nt).or_insert_with(|| vec![]).push(idx);
+                        if let Some(rules) = self.nts_to_rules.get(&nt) {
+                            for rid in rules.iter() {
+                                add(
+                                    &mut items,
+                                    &mut sets[pos],
+                                    Item {
+                                        rule: *rid,
+                                        dot: 0,
+                                        origin: pos,
+                                        prev: None,
+                                        step: Step::Start,
+                                    },
+                                );
+                            }
+                        }
+                        //Completions of nullable nonterminals in this very set may already have
+                        //happened, so skip over them right away
+                        if self.null_rules.contains_key(&nt) {
+                            add(
+                                &mut items,
+                                &mut sets[pos],
+                                Item {
+                                    rule,
+                                    dot: dot + 1,
+                                    origin,
+                                    prev: Some(idx),
+                                    step: Step::Null(nt),
+                                },
+                            );
+                        }
+                    }
+                    //Complete
+                    None => {
+                        let nt = self.ctx.get_nt(rule);
+                        let parents = sets[origin].waiting.get(&nt).cloned().unwrap_or(vec![]);
+                        for parent in parents {
+                            let (prule, pdot, porigin) =
+                                (items[parent].rule, items[parent].dot, items[parent].origin);
+                            add(
+                                &mut items,
+                                &mut sets[pos],
+                                Item {
+                                    rule: prule,
+                                    dot: pdot + 1,
+                                    origin: porigin,
+                                    prev: Some(parent),
+                                    step: Step::NTerm(idx),
+                                },
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        let done = sets[input.len()].items.iter().cloned().find(|idx| {
+            let item = &items[*idx];
+            return item.origin == 0
+                && self.ctx.get_nt(item.rule) == start
+                && item.dot == self.ctx.get_rule(item.rule).children().len();
+        });
+        match done {
+            Some(idx) => return Ok(self.build_tree(&items, idx)),
+            None => return Err(ParseError { offset: farthest }),
+        }
+    }
+
+    //Back pointers always point to older items, so following them terminates even for cyclic
+    //grammars. Uses an explicit stack, deep inputs would overflow the call stack otherwise.
+    fn build_tree(&self, items: &Vec<Item>, root: ItemIdx) -> Tree {
+        let mut rules = vec![];
+        let mut stack = vec![Step::NTerm(root)];
+        while let Some(step) = stack.pop() {
+            match step {
+                Step::NTerm(idx) => {
+                    rules.push(NormalOrCustomRule::NormalRule(items[idx].rule));
+                    let mut cur = Some(idx);
+                    while let Some(c) = cur {
+                        match items[c].step {
+                            Step::NTerm(_) | Step::Null(_) => stack.push(items[c].step),
+                            Step::Term | Step::Start => {}
+                        }
+                        cur = items[c].prev;
+                    }
+                }
+                Step::Null(nt) => {
+                    let rid = self.null_rules[&nt];
+                    rules.push(NormalOrCustomRule::NormalRule(rid));
+                    for child in self.ctx.get_rule(rid).nonterms().iter().rev() {
+                        stack.push(Step::Null(*child));
+                    }
+                }
+                Step::Term | Step::Start => unreachable!(),
+            }
+        }
+        return Tree::from_rule_vec(rules, self.ctx);
+    }
+}
+
+fn add(items: &mut Vec<Item>, set: &mut EarleySet, item: Item) {
+    let key = (item.rule, item.dot, item.origin);
+    if set.seen.contains_key(&key) {
+        return;
+    }
+    set.seen.insert(key, items.len());
+    set.items.push(items.len());
+    items.push(item);
+}
+
+#[cfg(test)]
+mod tests {
+    use context::Context;
+    use parser::{ParseError, Parser};
+    use tree::TreeLike;
+
+    fn roundtrip(ctx: &Context, start: &str, input: &[u8]) {
+        let tree = Parser::new(ctx)
+            .parse(ctx.nt_id(start), input)
+            .expect("RAND_2961412736");
+        assert_eq!(tree.unparse_to_vec(ctx), input.to_vec());
+    }
+
+    #[test]
+    fn parse_left_recursion() {
+        let mut ctx = Context::new();
+        ctx.add_rule("E", "{E}+{T}");
+        ctx.add_rule("E", "{T}");
+        ctx.add_rule("T", "{T}*{F}");
+        ctx.add_rule("T", "{F}");
+        ctx.add_rule("F", "({E})");
+        ctx.add_rule("F", "x");
+        roundtrip(&ctx, "E", b"x");
+        roundtrip(&ctx, "E", b"x+x*x");
+        roundtrip(&ctx, "E", b"(x+x)*x+(x)");
+        let tree = Parser::new(&ctx)
+            .parse(ctx.nt_id("E"), b"x+x")
+            .expect("RAND_1526153011");
+        assert_eq!(tree.size(), 6);
+        assert_eq!(tree.sizes[0], 6);
+    }
+
+    #[test]
+    fn parse_ambiguous_and_nullable() {
+        let mut ctx = Context::new();
+        ctx.add_rule("S", "{S}{S}");
+        ctx.add_rule("S", "{A}a{A}");
+        ctx.add_rule("A", "");
+        ctx.add_rule("A", "{B}{B}");
+        ctx.add_rule("B", "{A}");
+        ctx.add_rule("B", "b");
+        roundtrip(&ctx, "S", b"a");
+        roundtrip(&ctx, "S", b"aaa");
+        roundtrip(&ctx, "S", b"bab");
+        roundtrip(&ctx, "S", b"abbaba");
+    }
+
+    #[test]
+    fn parse_multibyte_terminals() {
+        let mut ctx = Context::new();
+        ctx.add_rule("S", "if {C} then {S} end");
+        ctx.add_rule("S", "skip");
+        ctx.add_rule("C", "true");
+        ctx.add_rule("C", "tr");
+        roundtrip(&ctx, "S", b"if true then if tr then skip end end");
+    }
+
+    #[test]
+    fn parse_error_offset() {
+        let mut ctx = Context::new();
+        ctx.add_rule("S", "a{S}b");
+        ctx.add_rule("S", "c");
+        let parser = Parser::new(&ctx);
+        assert_eq!(parser.parse(ctx.nt_id("S"), b"aacbx").err(), Some(ParseError { offset: 4 }));
+        assert_eq!(parser.parse(ctx.nt_id("S"), b"aacb").err(), Some(ParseError { offset: 4 }));
+        assert_eq!(parser.parse(ctx.nt_id("S"), b"xacb").err(), Some(ParseError { offset: 0 }));
+        assert!(parser.parse(ctx.nt_id("S"), b"aacbb").is_ok());
+    }
+}
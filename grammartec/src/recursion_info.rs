@@ -1,15 +1,16 @@
 use std::collections::HashMap;
-use rand::{sample, thread_rng, Rng, StdRng};
+use rand::{sample, Rng};
 
 use loaded_dice::LoadedDiceSampler;
 use context::Context;
 use newtypes::{NodeID, NTermID};
 use tree::{Tree,TreeLike};
 use rule::NormalOrCustomRule;
+use rng::{self, NautilusRng};
 
 struct RecursionInfo {
     recursive_parents: HashMap<NodeID, NodeID>,
-    sampler: LoadedDiceSampler<StdRng>,
+    sampler: LoadedDiceSampler<NautilusRng>,
     depth_by_offset: Vec<usize>,
     node_by_offset: Vec<NodeID>,
 }
@@ -55,14 +56,14 @@ impl RecursionInfo {
         return res;
     }
 
-    pub fn build_sampler( depths: &Vec<usize> ) -> LoadedDiceSampler<StdRng>{
+    pub fn build_sampler( depths: &Vec<usize> ) -> LoadedDiceSampler<NautilusRng>{
         let mut weights = depths.iter().map(|x| *x as f64).collect::<Vec<_>>();
         let norm: f64 = weights.iter().sum();
         assert!(norm > 0.0);
         for v in weights.iter_mut(){
             *v /= norm;
         }
-        return LoadedDiceSampler::new(weights, StdRng::new().expect("RAND_1769941938"));
+        return LoadedDiceSampler::new(weights, rng::rng());
     }
 
 }
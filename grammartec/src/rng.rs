@@ -0,0 +1,77 @@
+use std::cell::RefCell;
+
+use rand::{Rng, SeedableRng, StdRng};
+
+//All random choices of grammartec (tree generation, mutations, chunk selection) are drawn from one
+//generator per thread. It is seeded from the OS unless seed() is called, so a run can be
+//reproduced by seeding every thread with the same value before it generates or mutates anything.
+thread_local! {
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::new().expect("RAND_3108544290"));
+}
+
+//Handle to the generator of the current thread. It has no state of its own, so it can be stored
+//(e.g. in the samplers of a Context) and cloned to other threads, where it uses their generator.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NautilusRng;
+
+impl Rng for NautilusRng {
+    fn next_u32(&mut self) -> u32 {
+        return RNG.with(|rng| rng.borrow_mut().next_u32());
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        return RNG.with(|rng| rng.borrow_mut().next_u64());
+    }
+}
+
+pub fn rng() -> NautilusRng {
+    return NautilusRng;
+}
+
+//Reseeds the generator of the current thread
+pub fn seed(seed: u64) {
+    let key = [seed as usize, (seed >> 32) as usize];
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::from_seed(&key[..]));
+}
+
+//Seed for the index-th thread of a run with the given seed (splitmix64), so that threads don't
+//share a sequence and adding threads doesn't change the seeds of the others
+pub fn derive_seed(seed: u64, index: u64) -> u64 {
+    let mut z = seed.wrapping_add(index.wrapping_add(1).wrapping_mul(0x9e3779b97f4a7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    return z ^ (z >> 31);
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rng::{derive_seed, rng, seed};
+    use std::thread;
+
+    #[test]
+    fn check_seed() {
+        seed(1337);
+        let first = (0..100).map(|_| rng().gen::<u64>()).collect::<Vec<_>>();
+        seed(1337);
+        let second = (0..100).map(|_| rng().gen::<u64>()).collect::<Vec<_>>();
+        assert_eq!(first, second);
+        seed(1338);
+        let other = (0..100).map(|_| rng().gen::<u64>()).collect::<Vec<_>>();
+        assert_ne!(first, other);
+        //Every thread has its own generator
+        let from_thread = thread::spawn(|| {
+            seed(1337);
+            return (0..100).map(|_| rng().gen::<u64>()).collect::<Vec<_>>();
+        }).join()
+            .expect("RAND_1542880931");
+        assert_eq!(first, from_thread);
+    }
+
+    #[test]
+    fn check_derive_seed() {
+        assert_eq!(derive_seed(1, 0), derive_seed(1, 0));
+        assert_ne!(derive_seed(1, 0), derive_seed(1, 1));
+        assert_ne!(derive_seed(1, 0), derive_seed(2, 0));
+    }
+}
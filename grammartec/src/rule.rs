@@ -1,14 +1,14 @@
 //use std::collections::HashMap;
-use num::Zero;
 use std::io::Write;
 
+use choices::{Choices, RandomChoices};
 use context::Context;
 use newtypes::{NTermID, NodeID, RuleID};
 use regex::Regex;
 use std::io::Error;
 use tree::{Tree, TreeLike};
 
-#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
 pub enum RuleChild {
     Term(Vec<u8>),
     CustomTerm(Vec<u8>),
@@ -100,6 +100,26 @@ impl Rule {
         };
     }
 
+    //For grammars that list the terminals and nonterminals of a rule instead of a format string, so
+    //terminals can contain any bytes
+    pub fn from_children(ctx: &mut Context, nonterm: &str, children: Vec<RuleChild>) -> Self {
+        let nonterms = children
+            .iter()
+            .filter_map(|c| {
+                if let &RuleChild::NTerm(n) = c {
+                    Some(n)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        return Rule {
+            nonterm: ctx.aquire_nt_id(nonterm),
+            children,
+            nonterms,
+        };
+    }
+
     pub fn from_term(ntermid: NTermID, term: &Vec<u8>) -> Self {
         let children = vec![RuleChild::Term(term.to_vec())];
         let nonterms = vec![];
@@ -172,6 +192,17 @@ impl Rule {
     }
 
     pub fn generate(&self, tree: &mut Tree, ctx: &Context, len: usize) -> usize {
+        return self.generate_with(tree, ctx, len, &mut RandomChoices);
+    }
+
+    //Like generate, but the length of every child and the rule for it are picked by choices
+    pub fn generate_with<C: Choices>(
+        &self,
+        tree: &mut Tree,
+        ctx: &Context,
+        len: usize,
+        choices: &mut C,
+    ) -> usize {
         // println!("Rhs: {:?}, len: {}", self.nonterms, len);
         // println!("Min needed len: {}", self.nonterms.iter().fold(0, |sum, nt| sum + ctx.get_min_len_for_nt(*nt) ));
         let minimal_needed_len = self
@@ -197,7 +228,7 @@ impl Rule {
             let mut new_nterms = Vec::new();
             new_nterms.extend_from_slice(&self.nonterms[i..]);
             if new_nterms.len() != 0 {
-                cur_child_max_len = ctx.get_random_len(remaining_len, &new_nterms);
+                cur_child_max_len = choices.len_for_first_nt(ctx, remaining_len, &new_nterms);
             } else {
                 cur_child_max_len = remaining_len;
             }
@@ -206,7 +237,7 @@ impl Rule {
             }
 
             //get a rule that can be used with the remaining length
-            let rid = ctx.get_random_rule_for_nt(*nt, cur_child_max_len);
+            let rid = choices.rule_for_nt(ctx, *nt, cur_child_max_len);
             assert!(
                 ctx.is_dumb()
                     || !ctx
@@ -225,7 +256,9 @@ impl Rule {
             tree.paren.push(NodeID::from(0));
 
             //generate the subtree for this rule, return the total consumed len
-            let consumed_len = ctx.get_rule(rid).generate(tree, ctx, cur_child_max_len - 1);
+            let consumed_len =
+                ctx.get_rule(rid)
+                    .generate_with(tree, ctx, cur_child_max_len - 1, choices);
             tree.sizes[offset] = consumed_len;
             tree.paren[offset] = paren;
 
@@ -0,0 +1,212 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::rc::Rc;
+
+use proptest::prelude::RngCore;
+use proptest::strategy::{NewTree, Strategy, ValueTree};
+use proptest::test_runner::TestRunner;
+
+use context::Context;
+use mutator::Mutator;
+use newtypes::NTermID;
+use rng;
+use tree::{Tree, TreeLike, TreeMutation};
+
+//proptest Strategy (feature "proptest") for the trees of a grammar and their unparsed bytes:
+//
+//let strategy = GrammarStrategy::new(ctx, "START");
+//proptest!(|((tree, input) in strategy)| { ... });
+//
+//Failing inputs are shrunk with the reductions of Mutator::minimize_rec (replace a subtree with a
+//subtree of the same nonterminal below it) and Mutator::minimize_tree (replace a subtree with a
+//minimal one) instead of byte wise. The grammartec rng of the thread is seeded from the rng of the
+//TestRunner before a tree is drawn, so a persisted failure seed replays the same tree.
+#[derive(Clone)]
+pub struct GrammarStrategy {
+    ctx: Rc<Context>,
+    nt: NTermID,
+}
+
+impl GrammarStrategy {
+    //ctx has to be initialized
+    pub fn new(ctx: Context, nt: &str) -> Self {
+        let nt = ctx.nt_id(nt);
+        return GrammarStrategy {
+            ctx: Rc::new(ctx),
+            nt,
+        };
+    }
+}
+
+impl fmt::Debug for GrammarStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "GrammarStrategy({})", self.ctx.nt_id_to_s(self.nt));
+    }
+}
+
+impl Strategy for GrammarStrategy {
+    type Tree = GrammarValueTree;
+    type Value = (Tree, Vec<u8>);
+
+    fn new_tree(&self, runner: &mut TestRunner) -> NewTree<Self> {
+        rng::seed(runner.rng().next_u64());
+        let len = self.ctx.get_random_len_for_nt(&self.nt);
+        let tree = self.ctx.generate_tree_from_nt(self.nt, len);
+        return Ok(GrammarValueTree {
+            mutator: Mutator::new(&self.ctx),
+            ctx: self.ctx.clone(),
+            current: tree,
+            candidate: None,
+            phase: ShrinkPhase::Rec,
+            index: 0,
+            progress: false,
+        });
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ShrinkPhase {
+    Rec,
+    Tree,
+    Done,
+}
+
+pub struct GrammarValueTree {
+    ctx: Rc<Context>,
+    mutator: Mutator,
+    //Smallest tree known to fail
+    current: Tree,
+    //Reduction of current that is tested next
+    candidate: Option<Tree>,
+    phase: ShrinkPhase,
+    //Next node of current to reduce
+    index: usize,
+    //Whether a reduction was accepted during the current pass over minimize_rec and minimize_tree
+    progress: bool,
+}
+
+impl GrammarValueTree {
+    //Runs the reduction of the current phase on one node after the other until one of them
+    //proposes a candidate. Like Mutator::minimize_crash the passes are repeated until one of them
+    //makes no progress.
+    fn next_candidate(&mut self) -> bool {
+        let no_bits = HashSet::new();
+        while self.phase != ShrinkPhase::Done {
+            if self.index >= self.current.size() {
+                self.index = 0;
+                self.phase = match self.phase {
+                    ShrinkPhase::Rec => ShrinkPhase::Tree,
+                    _ if self.progress => {
+                        self.progress = false;
+                        ShrinkPhase::Rec
+                    }
+                    _ => ShrinkPhase::Done,
+                };
+                continue;
+            }
+            let mut candidate = None;
+            {
+                //The tester only records the reduction, current stays as it is
+                let mut tester = |m: &TreeMutation, _: &HashSet<usize>, ctx: &Context| {
+                    candidate = Some(m.to_tree(ctx));
+                    return Ok(false);
+                };
+                let (start, end) = (self.index, self.index + 1);
+                let mut tree = self.current.clone();
+                let res = match self.phase {
+                    ShrinkPhase::Rec => self.mutator.minimize_rec(
+                        &mut tree,
+                        &no_bits,
+                        &self.ctx,
+                        start,
+                        end,
+                        &mut tester,
+                    ),
+                    _ => self.mutator.minimize_tree(
+                        &mut tree,
+                        &no_bits,
+                        &self.ctx,
+                        start,
+                        end,
+                        &mut tester,
+                    ),
+                };
+                res.expect("RAND_1803297548");
+            }
+            self.index += 1;
+            if let Some(candidate) = candidate {
+                if candidate.size() < self.current.size() {
+                    self.candidate = Some(candidate);
+                    return true;
+                }
+            }
+        }
+        return false;
+    }
+}
+
+impl fmt::Debug for GrammarValueTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "GrammarValueTree({:?})", self.current());
+    }
+}
+
+impl ValueTree for GrammarValueTree {
+    type Value = (Tree, Vec<u8>);
+
+    fn current(&self) -> Self::Value {
+        let tree = self.candidate.as_ref().unwrap_or(&self.current).clone();
+        let bytes = tree.unparse_to_vec(&self.ctx);
+        return (tree, bytes);
+    }
+
+    //The last value still failed: keep it and propose the next reduction
+    fn simplify(&mut self) -> bool {
+        if let Some(candidate) = self.candidate.take() {
+            self.current = candidate;
+            self.progress = true;
+        }
+        return self.next_candidate();
+    }
+
+    //The last reduction made the test pass: drop it and propose the next one
+    fn complicate(&mut self) -> bool {
+        if self.candidate.take().is_none() {
+            return false;
+        }
+        return self.next_candidate();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use context::Context;
+    use proptest::test_runner::{TestCaseError, TestError, TestRunner};
+    use strategy::GrammarStrategy;
+
+    #[test]
+    fn check_shrink_to_minimal_derivation() {
+        let mut ctx = Context::new();
+        ctx.add_rule("START", "{E}");
+        ctx.add_rule("E", "({E}+{E})");
+        ctx.add_rule("E", "{N}");
+        ctx.add_rule("N", "1");
+        ctx.add_rule("N", "2");
+        ctx.initialize(50, false);
+        let strategy = GrammarStrategy::new(ctx, "START");
+        let mut runner = TestRunner::default();
+        let res = runner.run(&strategy, |(_, input)| {
+            if input.contains(&b'2') {
+                return Err(TestCaseError::fail("contains 2"));
+            }
+            return Ok(());
+        });
+        match res {
+            Err(TestError::Fail(_, (tree, input))) => {
+                assert_eq!(input, b"2");
+                assert_eq!(tree.rules.len(), 3);
+            }
+            _ => panic!("the property should fail"),
+        }
+    }
+}
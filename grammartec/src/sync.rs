@@ -0,0 +1,60 @@
+use std::path::{Path, PathBuf};
+
+use tree::Tree;
+
+//Format of the .tree files gramophone writes next to the entries it exports. Trees refer to rule
+//ids, so an exported tree is only valid for the grammar it was created with.
+#[derive(Serialize, Deserialize)]
+pub struct SyncEntry {
+    pub hash_of_grammar: u64,
+    pub tree: Tree,
+}
+
+//Folder of the trees gramophone exports to the AFL sync folder <sync_dir>/<name>. It is kept
+//outside of the queue folder, afl-fuzz would import every file there that starts with id:.
+pub fn afl_trees_dir(sync_dir: &Path, name: &str) -> PathBuf {
+    return sync_dir.join(name).join(".trees");
+}
+
+//Tree of the entry id:<id> gramophone exported to the AFL sync folder <sync_dir>/<name>
+pub fn afl_tree_path(sync_dir: &Path, name: &str, id: usize) -> PathBuf {
+    return afl_trees_dir(sync_dir, name).join(format!("id:{:06}.tree", id));
+}
+
+//afl-fuzz copies an entry it imported from <sync_dir>/<name> to <sync_dir>/<own name>/queue/
+//id:NNNNNN,sync:<name>,src:<id>. Returns the .tree file of the original entry.
+pub fn afl_tree_path_of_copy(path: &Path) -> Option<PathBuf> {
+    let file_name = path.file_name()?.to_str()?;
+    let mut name = None;
+    let mut id = None;
+    for part in file_name.split(',') {
+        if part.starts_with("sync:") {
+            name = Some(&part["sync:".len()..]);
+        } else if part.starts_with("src:") {
+            id = part["src:".len()..].parse::<usize>().ok();
+        }
+    }
+    let sync_dir = path.parent()?.parent()?.parent()?;
+    return Some(afl_tree_path(sync_dir, name?, id?));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use sync::{afl_tree_path, afl_tree_path_of_copy};
+
+    #[test]
+    fn check_afl_tree_path_of_copy() {
+        let copy = Path::new("out/afl1/queue/id:000042,sync:gramophone,src:000007");
+        assert_eq!(
+            afl_tree_path_of_copy(copy),
+            Some(afl_tree_path(Path::new("out"), "gramophone", 7))
+        );
+        assert_eq!(
+            afl_tree_path(Path::new("out"), "gramophone", 7),
+            Path::new("out/gramophone/.trees/id:000007.tree")
+        );
+        let own = Path::new("out/afl1/queue/id:000043,src:000042,op:havoc,rep:2,+cov");
+        assert_eq!(afl_tree_path_of_copy(own), None);
+    }
+}
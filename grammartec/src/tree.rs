@@ -4,6 +4,7 @@ use std::io::Error;
 use std::io::Write;
 use std::marker::Sized;
 
+use choices::{Choices, RandomChoices};
 use context::Context;
 use newtypes::{NTermID, NodeID, RuleID};
 use rule::{NormalOrCustomRule, Rule, RuleChild};
@@ -253,11 +254,21 @@ impl Tree {
     }
 
     pub fn generate_from_rule(&mut self, ruleid: RuleID, max_len: usize, ctx: &Context) {
+        self.generate_from_rule_with(ruleid, max_len, ctx, &mut RandomChoices);
+    }
+
+    pub fn generate_from_rule_with<C: Choices>(
+        &mut self,
+        ruleid: RuleID,
+        max_len: usize,
+        ctx: &Context,
+        choices: &mut C,
+    ) {
         self.truncate();
         self.rules.push(NormalOrCustomRule::NormalRule(ruleid));
         self.sizes.push(0);
         self.paren.push(NodeID::from(0));
-        ctx.get_rule(ruleid).generate(self, &ctx, max_len);
+        ctx.get_rule(ruleid).generate_with(self, &ctx, max_len, choices);
         self.sizes[0] = self.rules.len();
     }
 
@@ -0,0 +1,79 @@
+#![feature(test)]
+
+extern crate forksrv;
+extern crate grammartec;
+extern crate rand;
+#[macro_use]
+extern crate serde_derive;
+extern crate test;
+
+//gramophone has no library target, the modules the queue needs are included directly
+#[allow(dead_code)]
+#[path = "../src/bitmap.rs"]
+mod bitmap;
+#[allow(dead_code)]
+#[path = "../src/config.rs"]
+mod config;
+#[allow(dead_code)]
+#[path = "../src/queue.rs"]
+mod queue;
+#[allow(dead_code)]
+#[path = "../src/schedule.rs"]
+mod schedule;
+
+use config::PowerSchedule;
+use forksrv::exitreason::ExitReason;
+use grammartec::context::Context;
+use queue::{Queue, QueueItem};
+use rand::Rng;
+use std::collections::HashSet;
+use test::Bencher;
+
+const NUMBER_OF_ENTRIES: usize = 50000;
+const BITS_PER_ENTRY: usize = 200;
+
+//Every entry has one key of its own and shares the others with the rest of the queue
+fn queue_with_entries() -> Queue {
+    let mut ctx = Context::new();
+    ctx.add_rule("START", "{A}");
+    ctx.add_rule("A", "a{A}");
+    ctx.add_rule("A", "a");
+    ctx.initialize(10, false);
+    let tree = ctx.generate_tree_from_nt(ctx.nt_id("START"), 10);
+    let mut rng = rand::thread_rng();
+    let mut queue = Queue::new("/nonexistent/".to_string());
+    for id in 0..NUMBER_OF_ENTRIES {
+        let mut bits = (0..BITS_PER_ENTRY)
+            .map(|_| rng.gen_range(NUMBER_OF_ENTRIES, 1 << 18))
+            .collect::<Vec<_>>();
+        bits.push(id);
+        bits.sort();
+        bits.dedup();
+        queue.inputs.push(QueueItem::new(
+            id,
+            tree.clone(),
+            HashSet::new(),
+            bits,
+            ExitReason::Normal(0),
+            rng.gen_range(1000, 100000),
+            0,
+        ));
+    }
+    queue.rebuild_index();
+    return queue;
+}
+
+#[bench]
+fn pop_and_finish(b: &mut Bencher) {
+    let mut queue = queue_with_entries();
+    let path_frequency = vec![0; schedule::PATH_FREQUENCY_SIZE];
+    b.iter(|| {
+        if queue.len() == 0 {
+            queue.inputs.append(&mut queue.processed);
+        }
+        let item = queue
+            .pop(PowerSchedule::Fixed, &path_frequency)
+            .expect("RAND_1960183361");
+        queue.finished(item);
+    });
+}
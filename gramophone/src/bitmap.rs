@@ -0,0 +1,127 @@
+//AFL style hit count buckets. Each raw edge count is replaced by a single bit that describes its
+//bucket (1, 2, 3, 4-7, 8-15, 16-31, 32-127, 128+), so that e.g. running a loop 10 instead of 2
+//times counts as new coverage, while 10 and 11 iterations look the same.
+pub fn classify_count(count: u8) -> u8 {
+    match count {
+        0 => return 0,
+        1 => return 1,
+        2 => return 2,
+        3 => return 4,
+        4..=7 => return 8,
+        8..=15 => return 16,
+        16..=31 => return 32,
+        32..=127 => return 64,
+        _ => return 128,
+    }
+}
+
+pub fn classify_counts(bitmap: &mut [u8]) {
+    for elem in bitmap.iter_mut() {
+        if *elem != 0 {
+            *elem = classify_count(*elem);
+        }
+    }
+}
+
+//Coverage is tracked per (edge, bucket) pair. A classified byte has exactly one bit set, therefore
+//the key of an edge in a classified bitmap is its index times 8 plus the number of the set bit.
+pub fn bucket_key(index: usize, class: u8) -> usize {
+    return index * 8 + class.trailing_zeros() as usize;
+}
+
+pub fn has_bucket(bitmap: &[u8], key: usize) -> bool {
+    return bitmap[key / 8] == 1 << (key % 8);
+}
+
+pub fn bucket_keys<'a>(bitmap: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+    return bitmap
+        .iter()
+        .enumerate()
+        .filter(|&(_, class)| *class != 0)
+        .map(|(i, class)| bucket_key(i, *class));
+}
+
+//Up to 8 bytes of a bitmap as one word, missing bytes at the end are zero
+pub fn word(bytes: &[u8]) -> u64 {
+    let mut word = [0u8; 8];
+    word[..bytes.len()].copy_from_slice(bytes);
+    return u64::from_ne_bytes(word);
+}
+
+//True if run has a bucket bit that is not set in known. Compares a word at a time, which is much
+//faster than bytes for the mostly empty bitmaps.
+pub fn has_new_bits(run: &[u8], known: &[u8]) -> bool {
+    return run
+        .chunks(8)
+        .zip(known.chunks(8))
+        .any(|(run, known)| word(run) & !word(known) != 0);
+}
+
+//Sets every bit of other in bitmap
+pub fn merge(bitmap: &mut [u8], other: &[u8]) {
+    for (chunk, other) in bitmap.chunks_mut(8).zip(other.chunks(8)) {
+        let merged = word(chunk) | word(other);
+        if merged != word(chunk) {
+            chunk.copy_from_slice(&merged.to_ne_bytes()[..chunk.len()]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitmap::{
+        bucket_key, bucket_keys, classify_count, classify_counts, has_bucket, has_new_bits, merge,
+    };
+
+    #[test]
+    fn check_classify_count() {
+        let expected = [
+            (0, 0),
+            (1, 1),
+            (2, 2),
+            (3, 4),
+            (4, 8),
+            (7, 8),
+            (8, 16),
+            (15, 16),
+            (16, 32),
+            (31, 32),
+            (32, 64),
+            (127, 64),
+            (128, 128),
+            (255, 128),
+        ];
+        for &(count, class) in expected.iter() {
+            assert_eq!(classify_count(count), class);
+        }
+    }
+
+    #[test]
+    fn check_bucket_keys() {
+        let mut bitmap = vec![0, 1, 10, 200];
+        classify_counts(&mut bitmap);
+        assert_eq!(bitmap, vec![0, 1, 16, 128]);
+        let keys = bucket_keys(&bitmap).collect::<Vec<_>>();
+        assert_eq!(keys, vec![8, 20, 31]);
+        assert!(has_bucket(&bitmap, bucket_key(2, 16)));
+        assert!(!has_bucket(&bitmap, bucket_key(2, 32)));
+        assert!(!has_bucket(&bitmap, bucket_key(0, 1)));
+    }
+
+    #[test]
+    fn check_new_bits_and_merge() {
+        let mut known = vec![0u8; 20];
+        let mut run = vec![0u8; 20];
+        assert!(!has_new_bits(&run, &known));
+        run[19] = 4;
+        known[19] = 2;
+        assert!(has_new_bits(&run, &known));
+        merge(&mut known, &run);
+        assert_eq!(known[19], 6);
+        assert!(!has_new_bits(&run, &known));
+        run[3] = 1;
+        assert!(has_new_bits(&run, &known));
+        merge(&mut known, &run);
+        assert_eq!(known.iter().filter(|&&class| class != 0).count(), 2);
+    }
+}
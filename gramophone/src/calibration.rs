@@ -0,0 +1,224 @@
+use std::cmp;
+use std::time::Duration;
+
+use config::Config;
+
+//The timeout is this multiple of the 95th percentile of the measured execution times
+pub const TIMEOUT_FACTOR: u64 = 5;
+//Executions measured at startup (seeds first, then generated inputs) before the first timeout is picked
+pub const STARTUP_CALIBRATION_RUNS: usize = 100;
+//How often a new queue entry is re-run to measure its execution time
+pub const QUEUE_CALIBRATION_RUNS: usize = 5;
+
+//Samples below this get a bucket of their own, above it every power of two has SUB_BUCKETS buckets
+const EXACT_SAMPLES: u32 = 16;
+const SUB_BUCKETS: u32 = 8;
+const NUMBER_OF_BUCKETS: usize = 240;
+
+//Execution times counted in buckets that grow exponentially, so that the memory and the time to
+//get a percentile don't grow with the number of samples
+pub struct Histogram {
+    counts: Vec<u64>,
+    //Largest sample of every bucket, this is what a percentile in the bucket returns
+    max: Vec<u32>,
+    total: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        return Histogram {
+            counts: vec![0; NUMBER_OF_BUCKETS],
+            max: vec![0; NUMBER_OF_BUCKETS],
+            total: 0,
+        };
+    }
+
+    pub fn add(&mut self, sample: u32) {
+        let i = bucket(sample);
+        self.counts[i] += 1;
+        self.max[i] = cmp::max(self.max[i], sample);
+        self.total += 1;
+    }
+
+    pub fn count(&self) -> usize {
+        return self.total as usize;
+    }
+
+    //At least percent of all samples are less or equal. The result is at most one bucket (1/8 of
+    //its power of two) larger than the exact percentile.
+    pub fn percentile(&self, percent: usize) -> u32 {
+        if self.total == 0 {
+            return 0;
+        }
+        let rank = cmp::max((self.total * percent as u64 + 99) / 100, 1);
+        let mut seen = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return self.max[i];
+            }
+        }
+        unreachable!("the ranks are bounded by the number of samples");
+    }
+}
+
+fn bucket(sample: u32) -> usize {
+    if sample < EXACT_SAMPLES {
+        return sample as usize;
+    }
+    let exponent = 31 - sample.leading_zeros();
+    let sub_bucket = (sample >> (exponent - 3)) & (SUB_BUCKETS - 1);
+    return (EXACT_SAMPLES + (exponent - 4) * SUB_BUCKETS + sub_bucket) as usize;
+}
+
+//Indices whose (classified) hit count differs between two runs of the same input
+pub fn variable_indices<'a>(first: &'a [u8], run: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+    return first
+        .iter()
+        .zip(run.iter())
+        .enumerate()
+        .filter(|&(_, (a, b))| a != b)
+        .map(|(i, _)| i);
+}
+
+//Percentage of the covered bitmap indices that behave deterministically, as in AFL
+pub fn stability(variable: usize, covered: usize) -> f64 {
+    if covered == 0 {
+        return 100.0;
+    }
+    return 100.0 - (variable as f64 * 100.0 / covered as f64);
+}
+
+pub fn duration_to_millis(duration: Duration) -> u64 {
+    return duration.as_secs() * 1000 + duration.subsec_millis() as u64;
+}
+
+pub fn duration_to_nanos(duration: Duration) -> u32 {
+    let nanos = duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64;
+    return cmp::min(nanos, u32::max_value() as u64) as u32;
+}
+
+pub struct TimeoutCalibration {
+    //If false the timeout stays at timeout_in_millis, the execution times are only measured
+    pub enabled: bool,
+    pub timeout: Duration,
+    pub min_timeout: Duration,
+    pub max_timeout: Duration,
+    //95th percentile of the execution times in nanoseconds
+    pub p95: u32,
+    samples: Histogram,
+}
+
+impl TimeoutCalibration {
+    pub fn new(config: &Config) -> Self {
+        let min_timeout = Duration::from_millis(config.min_timeout_in_millis);
+        let max_timeout = Duration::from_millis(config.max_timeout_in_millis);
+        //Until enough executions were measured only hangs are cut off
+        let timeout = if config.calibrate_timeout {
+            max_timeout
+        } else {
+            Duration::from_millis(config.timeout_in_millis)
+        };
+        return TimeoutCalibration {
+            enabled: config.calibrate_timeout,
+            timeout,
+            min_timeout,
+            max_timeout,
+            p95: 0,
+            samples: Histogram::new(),
+        };
+    }
+
+    pub fn add_samples(&mut self, samples: &[u32]) {
+        //Without any measurement the timeout stays where it is
+        if samples.is_empty() {
+            return;
+        }
+        for sample in samples.iter() {
+            self.samples.add(*sample);
+        }
+        self.p95 = self.samples.percentile(95);
+        if self.enabled {
+            let timeout = Duration::from_nanos(self.p95 as u64 * TIMEOUT_FACTOR);
+            self.timeout = cmp::min(cmp::max(timeout, self.min_timeout), self.max_timeout);
+        }
+    }
+
+    pub fn number_of_samples(&self) -> usize {
+        return self.samples.count();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use calibration::{bucket, stability, variable_indices, Histogram, TimeoutCalibration, NUMBER_OF_BUCKETS};
+    use std::time::Duration;
+
+    fn percentile(samples: &[u32], percent: usize) -> u32 {
+        let mut histogram = Histogram::new();
+        for sample in samples.iter() {
+            histogram.add(*sample);
+        }
+        return histogram.percentile(percent);
+    }
+
+    #[test]
+    fn check_percentile() {
+        let samples = (1..101).rev().collect::<Vec<u32>>();
+        assert_eq!(percentile(&samples, 95), 95);
+        assert_eq!(percentile(&samples, 100), 100);
+        assert_eq!(percentile(&samples, 0), 1);
+        assert_eq!(percentile(&[7], 95), 7);
+        assert_eq!(percentile(&[], 95), 0);
+        //Samples in the same bucket can't be told apart
+        let samples = (1000..2000).collect::<Vec<u32>>();
+        let p50 = percentile(&samples, 50);
+        assert!(p50 >= 1500 && p50 <= 1500 + 1024 / 8);
+    }
+
+    #[test]
+    fn check_buckets() {
+        let mut last = 0;
+        for sample in (0..1 << 16).chain(vec![1 << 31, u32::max_value()]) {
+            let i = bucket(sample);
+            assert!(i >= last && i < NUMBER_OF_BUCKETS);
+            last = i;
+        }
+        assert_eq!(bucket(u32::max_value()), NUMBER_OF_BUCKETS - 1);
+    }
+
+    #[test]
+    fn check_variable_indices() {
+        let first = vec![0, 1, 2, 0, 8];
+        let run = vec![0, 1, 4, 1, 8];
+        assert_eq!(variable_indices(&first, &run).collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(variable_indices(&first, &first).count(), 0);
+        assert_eq!(stability(0, 0), 100.0);
+        assert_eq!(stability(1, 4), 75.0);
+    }
+
+    #[test]
+    fn timeout_is_clamped() {
+        let mut calibration = TimeoutCalibration {
+            enabled: true,
+            timeout: Duration::from_millis(1000),
+            min_timeout: Duration::from_millis(20),
+            max_timeout: Duration::from_millis(1000),
+            p95: 0,
+            samples: Histogram::new(),
+        };
+        calibration.add_samples(&[]);
+        assert_eq!(calibration.timeout, Duration::from_millis(1000));
+        //5 * 10ms
+        calibration.add_samples(&[10_000_000; 20]);
+        assert_eq!(calibration.timeout, Duration::from_millis(50));
+        calibration.add_samples(&[1_000; 1000]);
+        assert_eq!(calibration.timeout, Duration::from_millis(20));
+        calibration.add_samples(&[900_000_000; 10000]);
+        assert_eq!(calibration.timeout, Duration::from_millis(1000));
+        calibration.enabled = false;
+        calibration.add_samples(&[1_000; 200000]);
+        assert_eq!(calibration.p95, 1_000);
+        assert_eq!(calibration.timeout, Duration::from_millis(1000));
+    }
+}
@@ -0,0 +1,199 @@
+extern crate time as othertime;
+use othertime::strftime;
+
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use grammartec::chunkstore::ChunkStoreWrapper;
+use grammartec::context::{Context, SerializableContext};
+use ron;
+use shared_state::GlobalSharedState;
+
+//Paths of all files that together form a resumable fuzzing state
+pub struct Checkpoint {
+    queue_path: String,
+    bitmaps_path: String,
+    chunkstore_path: String,
+    context_path: String,
+}
+
+impl Checkpoint {
+    pub fn new(work_dir: &str) -> Self {
+        return Checkpoint {
+            queue_path: work_dir.to_owned() + "saved_queue.ron",
+            bitmaps_path: work_dir.to_owned() + "saved_bitmaps.ron",
+            chunkstore_path: work_dir.to_owned() + "saved_chunkstore.ron",
+            context_path: work_dir.to_owned() + "saved_context.ron",
+        };
+    }
+
+    pub fn exists(&self) -> bool {
+        return Path::new(&self.queue_path).is_file()
+            && Path::new(&self.bitmaps_path).is_file()
+            && Path::new(&self.chunkstore_path).is_file()
+            && Path::new(&self.context_path).is_file();
+    }
+
+    //The context only changes if the grammar changes, therefore it is written once at startup
+    pub fn save_context(&self, ctx: &Context, hash_of_grammar: u64) {
+        let serializable_context: SerializableContext = ctx.create_serializable_context(hash_of_grammar);
+        write_atomic(
+            &self.context_path,
+            &ron::ser::to_string(&serializable_context).expect("Serialization of Context failed!"),
+        );
+    }
+
+    //Returns the saved context if it was created from the grammar with the given hash and can be
+    //read by this version
+    pub fn load_context(&self, hash_of_grammar: u64) -> Option<SerializableContext> {
+        let serialized_context: SerializableContext =
+            ron::de::from_str(&read_file(&self.context_path)).ok()?;
+        if serialized_context.hash_of_original != hash_of_grammar {
+            return None;
+        }
+        return Some(serialized_context);
+    }
+
+    pub fn save(&self, global_state: &Mutex<GlobalSharedState>, cks: &ChunkStoreWrapper) {
+        //Serialize queue and bitmaps under the same lock so that they describe the same moment
+        let (queue, bitmaps) = {
+            let shared = global_state.lock().expect("RAND_372393424");
+            (
+                ron::ser::to_string(&shared.queue).expect("Serialization of Queue failed!"),
+                ron::ser::to_string(&shared.bitmaps).expect("Serialization of Bitmaps failed!"),
+            )
+        };
+        let chunkstore = ron::ser::to_string(&(*cks.chunkstore.read().expect("RAND_4283477146")))
+            .expect("Serialization of Chunkstore failed!");
+
+        write_atomic(&self.queue_path, &queue);
+        write_atomic(&self.bitmaps_path, &bitmaps);
+        write_atomic(&self.chunkstore_path, &chunkstore);
+
+        global_state.lock().expect("RAND_3289262969").state_saved =
+            strftime("[%Y-%m-%d] %H:%M:%S", &othertime::now()).expect("RAND_3227256997");
+    }
+
+    //Restores queue, bitmaps and chunkstore. Queue items keep their InputState, so each one
+    //continues with the stage it was in when the checkpoint was written. Entries that were being
+    //fuzzed continue with the stage they were in when they were popped.
+    pub fn resume(
+        &self,
+        global_state: &Mutex<GlobalSharedState>,
+        cks: &ChunkStoreWrapper,
+        work_dir: &str,
+    ) {
+        let mut shared = global_state.lock().expect("RAND_815997224");
+        shared.queue =
+            ron::de::from_str(&read_file(&self.queue_path)).expect("Failed to deserialize queue");
+        shared.queue.work_dir = work_dir.to_owned();
+        shared.queue.requeue_in_flight();
+        shared.queue.rebuild_index();
+        shared.bitmaps = ron::de::from_str(&read_file(&self.bitmaps_path))
+            .expect("Failed to deserialize bitmap");
+        *cks.chunkstore.write().expect("RAND_1421615953") =
+            ron::de::from_str(&read_file(&self.chunkstore_path))
+                .expect("Failed to deserialize chunkstore");
+    }
+}
+
+//Writes into a temporary file first and renames it afterwards, so that a process killed while
+//saving leaves the previous state intact instead of a truncated file
+pub fn write_atomic(path: &str, content: &str) {
+    let tmp_path = path.to_owned() + ".tmp";
+    {
+        let mut file = File::create(&tmp_path).expect(&format!("cannot create {}", tmp_path));
+        file.write_all(content.as_bytes())
+            .expect(&format!("cannot write {}", tmp_path));
+        file.sync_all().expect(&format!("cannot sync {}", tmp_path));
+    }
+    fs::rename(&tmp_path, path).expect(&format!("cannot rename {} to {}", tmp_path, path));
+}
+
+fn read_file(path: &str) -> String {
+    let mut file = File::open(path).expect(&format!("cannot read {}", path));
+    let mut content = String::new();
+    file.read_to_string(&mut content)
+        .expect(&format!("cannot read {}", path));
+    return content;
+}
+
+#[cfg(test)]
+mod tests {
+    use calibration::TimeoutCalibration;
+    use checkpoint::Checkpoint;
+    use config::Config;
+    use forksrv::exitreason::ExitReason;
+    use grammartec::chunkstore::ChunkStoreWrapper;
+    use grammartec::context::Context;
+    use queue::InputState;
+    use ron;
+    use shared_state::GlobalSharedState;
+    use std::fs;
+    use std::process;
+    use std::sync::Mutex;
+
+    #[test]
+    fn check_checkpoint_keeps_popped_entries() {
+        let work_dir = format!("/tmp/gramophone_checkpoint_test_{}/", process::id());
+        fs::create_dir_all(work_dir.clone() + "outputs/queue").expect("RAND_3155190416");
+        let config: Config = ron::de::from_str(include_str!("../../config.ron")).expect("RAND_1011386823");
+        let mut ctx = Context::new();
+        ctx.add_rule("START", "{A}");
+        ctx.add_rule("A", "a{A}");
+        ctx.add_rule("A", "a");
+        ctx.initialize(10, false);
+        let start = ctx.nt_id("START");
+
+        let shared = Mutex::new(GlobalSharedState::new(
+            work_dir.clone(),
+            TimeoutCalibration::new(&config),
+        ));
+        let cks = ChunkStoreWrapper::new();
+        {
+            let mut state = shared.lock().expect("RAND_2870512329");
+            for i in 0..2 {
+                let tree = ctx.generate_tree_from_nt(start, 2 + i);
+                state.queue.add(tree, vec![i], ExitReason::Normal(0), &ctx, 1000, 0);
+            }
+        }
+        let mut popped = {
+            let mut state = shared.lock().expect("RAND_1705519040");
+            let state = &mut *state;
+            state.queue.pop(config.power_schedule, &state.path_frequency).expect("RAND_3915911072")
+        };
+        popped.state = InputState::Random;
+
+        let checkpoint = Checkpoint::new(&work_dir);
+        checkpoint.save(&shared, &cks);
+        let resumed = Mutex::new(GlobalSharedState::new(
+            work_dir.clone(),
+            TimeoutCalibration::new(&config),
+        ));
+        checkpoint.resume(&resumed, &cks, &work_dir);
+        {
+            let state = resumed.lock().expect("RAND_2436098128");
+            assert_eq!(state.queue.len(), 2);
+            assert!(state.queue.in_flight.is_empty());
+            let requeued = state
+                .queue
+                .inputs
+                .iter()
+                .find(|item| item.id == popped.id)
+                .expect("RAND_1285367394");
+            //The progress made after the checkpoint is not in it
+            match requeued.state {
+                InputState::Init(0) => {}
+                _ => panic!("the popped entry lost its state"),
+            }
+        }
+
+        //Entries that were finished before the checkpoint are not in flight anymore
+        shared.lock().expect("RAND_4180925183").queue.finished(popped);
+        assert!(shared.lock().expect("RAND_2218826300").queue.in_flight.is_empty());
+        fs::remove_dir_all(&work_dir).expect("RAND_1586239461");
+    }
+}
@@ -1,5 +1,59 @@
 pub const BITMAP_SIZE: usize = 1 << 15;
 
+//Which forkserver protocol the target speaks
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    //Targets built with the redqueen-clang wrapper and the runtime in forksrv/instrument
+    Rofl,
+    //Targets built with afl-gcc, afl-clang(-fast) or AFL++
+    Afl,
+}
+
+//How much havoc, splice and havoc_recursion a queue entry gets each time it is picked
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum PowerSchedule {
+    //Every entry gets the same number of iterations
+    Fixed,
+    //Entries that cover (edge, bucket) pairs few other entries cover get more iterations
+    Coverage,
+    //AFLFast: the energy doubles every time an entry is picked and is divided by the number of
+    //executions that took the same path
+    Fast,
+}
+
+//The old runtime used a fixed timeout of 70ms
+fn default_timeout_in_millis() -> u64 {
+    return 70;
+}
+
+fn default_calibrate_timeout() -> bool {
+    return true;
+}
+
+fn default_min_timeout_in_millis() -> u64 {
+    return 20;
+}
+
+fn default_max_timeout_in_millis() -> u64 {
+    return 1000;
+}
+
+fn default_sync_intervall() -> u64 {
+    return 30;
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        return Backend::Rofl;
+    }
+}
+
+impl Default for PowerSchedule {
+    fn default() -> Self {
+        return PowerSchedule::Fixed;
+    }
+}
+
 #[derive(Deserialize, Clone)]
 pub struct Config {
     pub number_of_threads: u8,
@@ -17,4 +71,22 @@ pub struct Config {
     pub no_feedback_mode: bool, //When true the fuzzer only uses the generation method and no mutations
     pub dump_mode: bool, //When true the fuzzer saves every input that is tested (up to a maximum of 5000 and then cycling)
     pub arguments: Vec<String>,
+    #[serde(default)]
+    pub backend: Backend,
+    #[serde(default = "default_timeout_in_millis")]
+    pub timeout_in_millis: u64, //Wall clock time after which an execution is killed and counted as timeout
+    #[serde(default = "default_calibrate_timeout")]
+    pub calibrate_timeout: bool, //When true the timeout is picked from the measured execution times instead of timeout_in_millis
+    #[serde(default = "default_min_timeout_in_millis")]
+    pub min_timeout_in_millis: u64,
+    #[serde(default = "default_max_timeout_in_millis")]
+    pub max_timeout_in_millis: u64,
+    #[serde(default)]
+    pub power_schedule: PowerSchedule,
+    #[serde(default = "default_sync_intervall")]
+    pub sync_intervall: u64, //Seconds between two imports of the entries of the other instances (-M/-S)
+    #[serde(default)]
+    pub seed: Option<u64>, //Seed for the random number generators, None seeds them from the OS
+    #[serde(default)]
+    pub lazy_samplers: bool, //When true the samplers of the grammar are built the first time they are needed instead of at startup
 }
@@ -0,0 +1,104 @@
+use std::fmt;
+use std::time::Duration;
+
+use config::{Backend, BITMAP_SIZE};
+use forksrv::afl::AflForkServer;
+use forksrv::error::{descr_err, SubprocessError};
+use forksrv::exitreason::ExitReason;
+use forksrv::ForkServer;
+
+//Stage of the fuzzer that produced an input
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ExecutionReason {
+    Havoc,
+    HavocRec,
+    Min,
+    MinRec,
+    Splice,
+    Det,
+    DetAFL,
+    Gen,
+    Seed,
+    Sync,
+}
+
+#[repr(C)]
+pub struct FeedbackData {
+    pub run_bitmap: [u8; BITMAP_SIZE],
+    pub magic: u64,
+    pub status: i32,
+}
+
+impl fmt::Debug for FeedbackData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Feedback {{ run_bitmap [...] magic: {}, status: {} }}",
+            self.magic, self.status
+        )
+    }
+}
+
+//Runs inputs with the forkserver the config asks for. The size of the bitmap depends on the backend.
+pub enum Executor {
+    Rofl(ForkServer<FeedbackData>),
+    Afl(AflForkServer),
+}
+
+impl Executor {
+    pub fn new(
+        backend: Backend,
+        path: &String,
+        args: &Vec<String>,
+        out_path: String,
+        err_path: String,
+    ) -> Result<Self, SubprocessError> {
+        match backend {
+            Backend::Rofl => {
+                return Ok(Executor::Rofl(ForkServer::<FeedbackData>::new(
+                    path, args, out_path, err_path,
+                )?))
+            }
+            Backend::Afl => {
+                return Ok(Executor::Afl(AflForkServer::new(
+                    path, args, out_path, err_path,
+                )?))
+            }
+        }
+    }
+
+    pub fn run_on(&mut self, code: &[u8], timeout: Duration) -> Result<ExitReason, SubprocessError> {
+        match self {
+            Executor::Rofl(forksrv) => {
+                forksrv.get_shared_mut().magic = 0x1337133713371337;
+                let exitreason = forksrv.run_on(&code, timeout)?;
+                if forksrv.get_shared().magic != 0x5a5a55464c464f52 {
+                    return descr_err("Failed to get magic value from subprocess");
+                }
+                return Ok(exitreason);
+            }
+            Executor::Afl(forksrv) => return forksrv.run_on(&code, timeout),
+        }
+    }
+
+    pub fn bitmap<'a>(&'a self) -> &'a [u8] {
+        match self {
+            Executor::Rofl(forksrv) => return &forksrv.get_shared().run_bitmap,
+            Executor::Afl(forksrv) => return forksrv.get_bitmap(),
+        }
+    }
+
+    pub fn bitmap_mut<'a>(&'a mut self) -> &'a mut [u8] {
+        match self {
+            Executor::Rofl(forksrv) => return &mut forksrv.get_shared_mut().run_bitmap,
+            Executor::Afl(forksrv) => return forksrv.get_bitmap_mut(),
+        }
+    }
+
+    pub fn err_path<'a>(&'a self) -> &'a str {
+        match self {
+            Executor::Rofl(forksrv) => return &forksrv.err_path,
+            Executor::Afl(forksrv) => return &forksrv.err_path,
+        }
+    }
+}
@@ -3,7 +3,6 @@ use othertime::strftime;
 
 use std::collections::HashSet;
 use std::collections::VecDeque;
-use std::fmt;
 use std::fs;
 use std::fs::File;
 use std::io::stdout;
@@ -11,55 +10,42 @@ use std::io::ErrorKind;
 use std::io::Write;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use forksrv::error::{descr_err, SubprocessError};
+use forksrv::error::SubprocessError;
 use forksrv::exitreason::ExitReason;
-use forksrv::ForkServer;
 use grammartec::context::Context;
 use grammartec::tree::TreeLike;
 use shared_state::GlobalSharedState;
- use std::collections::HashMap;
+use triage::{Triager, DEFAULT_NUMBER_OF_FRAMES};
+use std::collections::HashMap;
 
-use config::BITMAP_SIZE;
-
-#[repr(C)]
-struct FeedbackData {
-    run_bitmap: [u8; BITMAP_SIZE],
-    magic: u64,
-    status: i32,
-}
-
-pub enum ExecutionReason {
-    Havoc,
-    HavocRec,
-    Min,
-    MinRec,
-    Splice,
-    Det,
-    DetAFL,
-    Gen,
-}
-
-impl fmt::Debug for FeedbackData {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "Feedback {{ run_bitmap [...] magic: {}, status: {} }}",
-            self.magic, self.status
-        )
-    }
-}
+use bitmap;
+use calibration;
+use config::Backend;
+use executor::{ExecutionReason, Executor};
+use schedule;
 
 pub struct Fuzzer {
-    forksrv: ForkServer<FeedbackData>,
+    forksrv: Executor,
+    backend: Backend,
+    pub timeout: Duration,
+    //Second forkserver that captures stderr, only started once the first crash is found
+    triager: Option<Triager>,
     last_tried_inputs: HashSet<Vec<u8>>,
+    //Copies of the global bitmaps that also hold the bits of this thread. Runs without a bit missing
+    //here can't have new coverage, so only the others lock the global state.
+    local_bitmaps: HashMap<bool, Vec<u8>>,
+    //Executions per path frequency index that are not yet added to the global counters
+    pub path_frequency: HashMap<usize, u32>,
     last_inputs_ring_buffer: VecDeque<Vec<u8>>,
     pub global_state: Arc<Mutex<GlobalSharedState>>,
     pub target_path: String,
     pub target_args: Vec<String>,
     pub execution_count: u64,
     pub average_executions_per_sec: f32,
+    //In nanoseconds, the re-runs of new queue entries don't count
+    pub last_execution_time: u32,
     pub bits_found_by_havoc: u64,
     pub bits_found_by_havoc_rec: u64,
     pub bits_found_by_min: u64,
@@ -68,6 +54,8 @@ pub struct Fuzzer {
     pub bits_found_by_det: u64,
     pub bits_found_by_det_afl: u64,
     pub bits_found_by_gen: u64,
+    pub bits_found_by_seed: u64,
+    pub bits_found_by_sync: u64,
     pub asan_found_by_havoc: u64,
     pub asan_found_by_havoc_rec: u64,
     pub asan_found_by_min: u64,
@@ -88,18 +76,25 @@ impl Fuzzer {
         global_state: Arc<Mutex<GlobalSharedState>>,
         dump_mode: bool,
         work_dir: String,
+        backend: Backend,
+        timeout: Duration,
     ) -> Result<Self, SubprocessError> {
-        let fs =
-            ForkServer::<FeedbackData>::new(&path, &args, "/dev/null".into(), "/dev/null".into())?;
+        let fs = Executor::new(backend, &path, &args, "/dev/null".into(), "/dev/null".into())?;
         return Ok(Fuzzer {
             forksrv: fs,
+            backend,
+            timeout,
+            triager: None,
             last_tried_inputs: HashSet::new(),
+            local_bitmaps: HashMap::new(),
+            path_frequency: HashMap::new(),
             last_inputs_ring_buffer: VecDeque::new(),
             global_state,
             target_path: path,
             target_args: args,
             execution_count: 0,
             average_executions_per_sec: 0.0,
+            last_execution_time: 0,
             bits_found_by_havoc: 0,
             bits_found_by_havoc_rec: 0,
             bits_found_by_min: 0,
@@ -108,6 +103,8 @@ impl Fuzzer {
             bits_found_by_det: 0,
             bits_found_by_det_afl: 0,
             bits_found_by_gen: 0,
+            bits_found_by_seed: 0,
+            bits_found_by_sync: 0,
             asan_found_by_havoc: 0,
             asan_found_by_havoc_rec: 0,
             asan_found_by_min: 0,
@@ -164,6 +161,7 @@ impl Fuzzer {
                         thread::current().name().expect("RAND_4086695190")
                     )).expect("RAND_3096222153");
                     tree.unparse_to(ctx, &mut file).expect("RAND_585073586");
+                    self.triage(code, tree, exec_reason, ctx);
                 }
                 ExitReason::Normal(_) => {
                     match exec_reason {
@@ -191,6 +189,12 @@ impl Fuzzer {
                         ExecutionReason::Gen => {
                             self.bits_found_by_gen += 1; /*print!("Gen+")*/
                         }
+                        ExecutionReason::Seed => {
+                            self.bits_found_by_seed += 1;
+                        }
+                        ExecutionReason::Sync => {
+                            self.bits_found_by_sync += 1;
+                        }
                     }
                 }
                 ExitReason::Timeouted => {
@@ -220,6 +224,7 @@ impl Fuzzer {
                         self.work_dir, sig, self.execution_count
                     )).expect("RAND_3690294970");
                     tree.unparse_to(ctx, &mut file).expect("RAND_3072663268");
+                    self.triage(code, tree, exec_reason, ctx);
                 }
                 ExitReason::Stopped(_sig) => {}
             }
@@ -228,6 +233,42 @@ impl Fuzzer {
         return Ok(());
     }
 
+    //Failing to triage must not stop the fuzzing, the crash is still saved in outputs/signaled
+    fn triage<T: TreeLike>(&mut self, code: &[u8], tree: &T, exec_reason: ExecutionReason, ctx: &Context) {
+        let mut triager = match self.triager.take() {
+            Some(triager) => triager,
+            None => match Triager::new(
+                self.backend,
+                self.target_path.clone(),
+                self.target_args.clone(),
+                self.work_dir.clone(),
+                self.timeout,
+                DEFAULT_NUMBER_OF_FRAMES,
+            ) {
+                Ok(triager) => triager,
+                Err(e) => {
+                    println!("Could not start triage forkserver: {:?}", e);
+                    return;
+                }
+            },
+        };
+        match triager.triage(code, Some(exec_reason)) {
+            Ok(res) => {
+                //The first crash of every bucket is minimized by the minimizer thread, so that this
+                //thread can go on fuzzing
+                if let Some((bucket, true)) = res {
+                    self.global_state
+                        .lock()
+                        .expect("RAND_1270374829")
+                        .crashes_to_minimize
+                        .push_back((bucket, tree.to_tree(ctx)));
+                }
+                self.triager = Some(triager);
+            }
+            Err(e) => println!("Triage failed: {:?}", e),
+        }
+    }
+
     pub fn has_bits<T: TreeLike>(
         &mut self,
         tree: &T,
@@ -236,41 +277,31 @@ impl Fuzzer {
         ctx: &Context,
     ) -> Result<bool, SubprocessError> {
         self.run_on_without_dedup(tree, exec_reason, ctx)?;
-        let run_bitmap = self.forksrv.get_shared().run_bitmap;
-        let mut found_all = true;
-        for bit in bits.iter() {
-            if run_bitmap[*bit] == 0 {
-                //TODO: handle edge counts properly
-                found_all = false;
-            }
-        }
-        return Ok(found_all);
+        let run_bitmap = self.forksrv.bitmap();
+        return Ok(bits.iter().all(|key| bitmap::has_bucket(run_bitmap, *key)));
     }
 
 
     pub fn last_bitmap<'a>(&'a self) -> &'a [u8]{
-            return &self.forksrv.get_shared().run_bitmap;
+            return self.forksrv.bitmap();
     }
 
     pub fn exec_raw<'a>(&'a mut self, code: &[u8])-> Result<(ExitReason, u32), SubprocessError> {
 
             self.execution_count += 1;
 
-            self.forksrv.get_shared_mut().magic = 0x1337133713371337;
-
             let start = Instant::now();
             
-            self.forksrv.run_on(&code)?;
+            let exitreason = self.forksrv.run_on(&code, self.timeout)?;
+
+            let execution_time = calibration::duration_to_nanos(start.elapsed());
 
-            let execution_time = start.elapsed().subsec_nanos();
+            //The bitmap is cleared before each run, so it can be classified in place
+            bitmap::classify_counts(self.forksrv.bitmap_mut());
 
             self.average_executions_per_sec = self.average_executions_per_sec * 0.9
                 + ((1.0 / (execution_time as f32)) * 1000000000.0) * 0.1;
 
-            if self.forksrv.get_shared().magic != 0x5a5a55464c464f52 {
-                return descr_err("Failed to get magic value from subprocess");
-            }
-            let exitreason = ExitReason::from_int(self.forksrv.get_shared().status);
             return Ok((exitreason,execution_time));
     }
 
@@ -340,6 +371,7 @@ impl Fuzzer {
             }
 
             let (exitreason,execution_time) = self.exec_raw(&code)?;
+            self.last_execution_time = execution_time;
 
             let is_crash = match exitreason {
                 ExitReason::Normal(223) => true,
@@ -347,24 +379,29 @@ impl Fuzzer {
                 _ => false,
             };
 
+            let path_hash = schedule::path_hash(self.forksrv.bitmap());
             let mut final_bits = None;
-            if let Some(mut new_bits) = self.new_bits(is_crash) {
+            if let Some(mut new_bits) = self.new_bits(is_crash, path_hash) {
                 //Only if not Timeout
                 if exitreason != ExitReason::Timeouted {
                     //Check for non deterministic bits
-                    let old_bitmap: Vec<u8> = self.forksrv.get_shared().run_bitmap.to_vec();
-                    self.check_deterministic_behaviour(&old_bitmap, &mut new_bits,&code)?;
+                    let old_bitmap: Vec<u8> = self.forksrv.bitmap().to_vec();
+                    let execution_times = self.calibrate(&old_bitmap, &mut new_bits, &code)?;
                     if new_bits.len() > 0 {
-                        let new_bits_clone = new_bits.clone();
                         final_bits = Some(new_bits);
 
                         if exitreason != ExitReason::Normal(223) {
                             let tree = tree_like.to_tree(ctx);
-                            self.global_state
-                                .lock()
-                                .expect("RAND_2835014626")
-                                .queue
-                                .add(tree, old_bitmap, new_bits_clone, exitreason, ctx, execution_time);
+                            //The re-runs give a better estimate than the first execution
+                            let average_time = execution_times.iter().map(|t| *t as u64).sum::<u64>()
+                                / execution_times.len() as u64;
+                            let mut bits = bitmap::bucket_keys(&old_bitmap).collect::<Vec<_>>();
+                            let mut gstate = self.global_state.lock().expect("RAND_2835014626");
+                            //Variable bits would make the minimization of this entry flaky
+                            bits.retain(|key| !gstate.variable_bits.contains(&(key / 8)));
+                            gstate.queue.add(tree, bits, exitreason, ctx, average_time as u32, path_hash);
+                            gstate.timeouts.add_samples(&execution_times);
+                            self.timeout = gstate.timeouts.timeout;
                             //println!("Entry added to queue! New bits: {:?}", bits.clone().expect("RAND_2243482569"));
                         }
                     }
@@ -374,35 +411,67 @@ impl Fuzzer {
         //}
     }
 
-    fn check_deterministic_behaviour(&mut self, old_bitmap: &[u8], new_bits: &mut Vec<usize>, code: &[u8]) -> Result<(), SubprocessError>{
-        for _ in (0..5){
-            let (exit_reason,time) = self.exec_raw(code)?;
-            let run_bitmap = self.forksrv.get_shared().run_bitmap;
-            for (i,&v) in old_bitmap.iter().enumerate(){
-                if run_bitmap[i] != v {
-                    println!("found fucky bit {}", i);
-                }
-            }
-            new_bits.retain(|&i| run_bitmap[i] != 0);
+    //Calibration stage of inputs with new coverage: the input is run again and every bitmap index
+    //that differs from the first run is variable. New bits on variable indices are not counted, here
+    //and in all later runs. Returns the execution times of the re-runs.
+    fn calibrate(&mut self, first_bitmap: &[u8], new_bits: &mut Vec<usize>, code: &[u8]) -> Result<Vec<u32>, SubprocessError> {
+        let mut execution_times = vec![];
+        let mut variable = HashSet::new();
+        for _ in 0..calibration::QUEUE_CALIBRATION_RUNS {
+            let (_, time) = self.exec_raw(code)?;
+            execution_times.push(time);
+            variable.extend(calibration::variable_indices(first_bitmap, self.forksrv.bitmap()));
+        }
+        if !variable.is_empty() {
+            new_bits.retain(|key| !variable.contains(&(key / 8)));
+            self.global_state
+                .lock()
+                .expect("RAND_1645981707")
+                .variable_bits
+                .extend(variable);
         }
-        return Ok(())
+        return Ok(execution_times);
     }
 
-    pub fn new_bits(&mut self, is_crash: bool) -> Option<Vec<usize>> {
+    //Also counts the execution for the path frequency of path_hash
+    pub fn new_bits(&mut self, is_crash: bool, path_hash: u64) -> Option<Vec<usize>> {
+        *self
+            .path_frequency
+            .entry(schedule::path_frequency_index(path_hash))
+            .or_insert(0) += 1;
+        let run_bitmap = self.forksrv.bitmap();
+        let local_bitmap = self
+            .local_bitmaps
+            .entry(is_crash)
+            .or_insert_with(|| vec![0; run_bitmap.len()]);
+        if !bitmap::has_new_bits(run_bitmap, local_bitmap) {
+            return None;
+        }
+
         let mut res = vec!();
-        let run_bitmap = self.forksrv.get_shared().run_bitmap;
         let mut gstate_lock = self.global_state.lock().expect("RAND_2040280272");
-        let shared_bitmap = gstate_lock
+        let gstate = &mut *gstate_lock;
+        let shared_bitmap = gstate
             .bitmaps
             .entry(is_crash)
-            .or_insert_with(|| vec![0; BITMAP_SIZE]);
-        for (i, elem) in shared_bitmap.iter_mut().enumerate() {
-            if (run_bitmap[i] != 0) && (*elem == 0) {
-                *elem |= run_bitmap[i];
-                res.push(i);
-                //println!("Added new bit to bitmap. Is Crash: {:?}; Added bit: {:?}", is_crash, i);
+            .or_insert_with(|| vec![0; run_bitmap.len()]);
+        //Both bitmaps hold bucket bits, every bucket not seen before for an edge is new coverage
+        for (offset, (shared, run)) in shared_bitmap.chunks_mut(8).zip(run_bitmap.chunks(8)).enumerate() {
+            if bitmap::word(run) & !bitmap::word(shared) == 0 {
+                continue;
+            }
+            for (j, elem) in shared.iter_mut().enumerate() {
+                let i = offset * 8 + j;
+                if (run_bitmap[i] & !*elem) != 0 && !gstate.variable_bits.contains(&i) {
+                    *elem |= run_bitmap[i];
+                    res.push(bitmap::bucket_key(i, run_bitmap[i]));
+                    //println!("Added new bit to bitmap. Is Crash: {:?}; Added bit: {:?}", is_crash, i);
+                }
             }
         }
+        //Variable bits stay in the local bitmap, they never count as new coverage anyway
+        bitmap::merge(local_bitmap, shared_bitmap);
+        bitmap::merge(local_bitmap, run_bitmap);
 
         if res.len() > 0 {
             //print!("New path found:\nNew bits: {:?}\n", res);
@@ -1,22 +1,19 @@
-extern crate antlr_parser;
 #[macro_use]
 extern crate clap;
 extern crate grammartec;
 extern crate ron;
-extern crate serde_json;
 
-use grammartec::context::Context;
-use grammartec::context::SerializableContext;
+use grammartec::loader::{self, LoadOptions};
 use grammartec::newtypes::NTermID;
+use grammartec::rng;
 use grammartec::tree::TreeLike;
 
 use clap::{App, Arg};
-use std::collections::hash_map::DefaultHasher;
 use std::fs;
 use std::fs::File;
-use std::hash::{Hash, Hasher};
-use std::io::{self, Read, Write};
+use std::io::{self, Write};
 use std::path::Path;
+use std::process;
 
 fn main() {
     //Parse parameters
@@ -45,9 +42,22 @@ fn main() {
         .arg(Arg::with_name("dumb")
              .short("d")
              .help("Don't use fancy calculations to generate trees (dumb mode)"))
+        .arg(Arg::with_name("lazy")
+             .short("l")
+             .help("Build the samplers only when they are needed, which starts faster if only a few trees are generated"))
         .arg(Arg::with_name("verbose")
              .short("v")
              .help("Be verbose"))
+        .arg(Arg::with_name("seed")
+             .long("seed")
+             .value_name("SEED")
+             .takes_value(true)
+             .help("Seed for the random number generator, the same seed generates the same trees"))
+        .arg(Arg::with_name("start")
+             .long("start")
+             .value_name("NONTERMINAL")
+             .takes_value(true)
+             .help("Nonterminal the trees are generated from [default: the nonterminal of the first rule]"))
         .get_matches();
 
 
@@ -61,71 +71,22 @@ fn main() {
     let store = matches.is_present("store");
     let dumb = matches.is_present("dumb");
     let verbose = matches.is_present("verbose");
-
-    let mut ctx;
-    let serialized_context_path = grammar_path.clone() + ".gfc";
-    let mut maybe_serialized_context = None;
-    //Calculate string of grammar file
-    let mut gf = File::open(grammar_path.clone()).expect("cannot open grammar file");
-    let mut content = String::new();
-    gf.read_to_string(&mut content)
-        .expect("cannot read grammar file");
-    let mut s = DefaultHasher::new();
-    content.hash(&mut s);
-    let hash = s.finish();
-    //Deserialize saved context if the granmmar did not change (hash value still the same)
-    if Path::new(&serialized_context_path).is_file() {
-        let mut cf = File::open(&serialized_context_path).expect("cannot read saved context file");
-        let mut context_as_string = String::new();
-        cf.read_to_string(&mut context_as_string)
-            .expect("RAND_2280042516");
-        let serialized_context: SerializableContext =
-            ron::de::from_str(&context_as_string).expect("Failed to deserialize context");
-        //Check if file changed
-        if hash != serialized_context.hash_of_original {
-        } else {
-            maybe_serialized_context = Some(serialized_context);
-        }
-    }
-    if let Some(serialized_context) = maybe_serialized_context {
-        ctx = Context::from_serialized_context(serialized_context, false, dumb);
-    }
-    //Create new Context and saved it
-    else {
-        let mut my_parser = antlr_parser::AntlrParser::new();
-        ctx = Context::with_dump(dumb);
-        if grammar_path.ends_with(".json") {
-            let gf = File::open(grammar_path).expect("cannot read grammar file");
-            let rules: Vec<Vec<String>> =
-                serde_json::from_reader(&gf).expect("cannot parse grammar file");
-            assert!(rules.len() > 0, "rule file didn_t include any rules");
-            let root = "{".to_string() + &rules[0][0] + "}";
-            ctx.add_rule("START", &root);
-            for rule in rules {
-                
-                ctx.add_rule(&rule[0], &rule[1]);
-            }
-        } else if grammar_path.ends_with(".g4") {
-            my_parser.parse_antlr_grammar(&grammar_path);
-            let root = "{".to_string() + &my_parser.rules[0].0 + "}";
-            ctx.add_rule("START", &root);
-            for rule in my_parser.rules {
-                ctx.add_rule(&rule.0, &rule.1);
-            }
-        } else {
-            panic!("Unknown grammar type");
-        }
-        ctx.initialize(tree_depth, verbose);
-        //Save context
-        let mut cf = File::create(&serialized_context_path).expect("cannot create context file");
-        let serializable_context: SerializableContext = ctx.create_serializable_context(hash);
-        cf.write_all(
-            ron::ser::to_string(&serializable_context)
-                .expect("Serialization of Context failed!")
-                .as_bytes(),
-        ).expect("Writing to context file failed");
+    if matches.is_present("seed") {
+        rng::seed(value_t!(matches, "seed", u64).expect("the seed has to be a number"));
     }
 
+    let mut options = LoadOptions::new(tree_depth);
+    options.start = matches.value_of("start").map(|nt| nt.to_string());
+    options.dumb = dumb;
+    options.lazy_samplers = matches.is_present("lazy");
+    options.verbose = verbose;
+    //Takes the counts from the cache if the grammar did not change (hash value still the same)
+    options.cache_path = Some(grammar_path.clone() + ".gfc");
+    let ctx = loader::load_path(&grammar_path, &options).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
     //Generate Tree
     if store {
         if Path::new("corpus").exists() {
@@ -0,0 +1,58 @@
+#[macro_use]
+extern crate clap;
+extern crate grammartec;
+
+use grammartec::loader::{self, LoadOptions};
+use grammartec::diagnostic::Severity;
+
+use clap::{App, Arg};
+use std::process;
+
+fn main() {
+    //Parse parameters
+    let matches = App::new("grammar-lint")
+        .about("Report undefined, unproductive and unreachable nonterminals, duplicate rules and rules that are too large for the tree size")
+        .arg(Arg::with_name("tree_size")
+             .short("t")
+             .value_name("SIZE")
+             .takes_value(true)
+             .help("Report rules that don't fit in trees of this size (max_tree_size of the fuzzer)"))
+        .arg(Arg::with_name("grammar_path")
+             .value_name("GRAMMAR")
+             .required(true)
+             .help("Path to grammar"))
+        .get_matches();
+
+    let grammar_path = matches.value_of("grammar_path")
+        .expect("grammar_path is a required parameter")
+        .to_string();
+
+    //The rules are checked here, so that warnings are reported as well
+    let ctx = loader::source_for_path(&grammar_path)
+        .and_then(|source| loader::load_rules(&*source, &LoadOptions::new(0)))
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
+
+    let diagnostics = if matches.is_present("tree_size") {
+        ctx.validate_for(value_t_or_exit!(matches, "tree_size", usize))
+    } else {
+        ctx.validate()
+    };
+    for diagnostic in diagnostics.iter() {
+        println!("{}", diagnostic);
+    }
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.severity() == Severity::Error)
+        .count();
+    println!(
+        "{} errors, {} warnings",
+        errors,
+        diagnostics.len() - errors
+    );
+    if errors > 0 {
+        process::exit(1);
+    }
+}
@@ -1,7 +1,8 @@
 #![feature(vec_remove_item)]
-extern crate antlr_parser;
 extern crate forksrv;
 extern crate grammartec;
+extern crate nix;
+extern crate rand;
 extern crate serde_json;
 extern crate time as othertime;
 #[macro_use]
@@ -9,46 +10,68 @@ extern crate serde_derive;
 extern crate clap;
 extern crate ron;
 
+mod bitmap;
+mod calibration;
+mod checkpoint;
 mod config;
+mod executor;
 mod fuzzer;
 mod queue;
 mod rules;
+mod schedule;
 mod shared_state;
 mod state;
+mod sync;
+mod triage;
 
+use calibration::{duration_to_millis, TimeoutCalibration, STARTUP_CALIBRATION_RUNS};
+use checkpoint::{write_atomic, Checkpoint};
 use config::Config;
+use executor::ExecutionReason;
 use forksrv::error::SubprocessError;
 use fuzzer::Fuzzer;
 use grammartec::chunkstore::ChunkStoreWrapper;
-use grammartec::context::{Context, SerializableContext};
+use grammartec::context::Context;
+use grammartec::loader::{self, LoadOptions};
+use grammartec::mutator::Mutator;
+use grammartec::parser::Parser;
+use grammartec::rng;
+use grammartec::tree::{TreeLike, TreeMutation};
+use nix::sys::signal::{SigSet, SIGINT, SIGTERM};
 use queue::{InputState, QueueItem};
 use shared_state::GlobalSharedState;
 use state::FuzzingState;
+use sync::{AflSync, Instance};
+use triage::{Triager, DEFAULT_NUMBER_OF_FRAMES};
 
 use clap::{App, Arg};
-use othertime::strftime;
-use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
 use std::fs;
 use std::fs::File;
-use std::hash::{Hash, Hasher};
-use std::io::{Read, Write};
-use std::path::Path;
+use std::io::Read;
+use std::process;
 use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use std::{thread, time};
 
+//Secondary instances (-S) skip the deterministic stages, the main instance runs them
 fn process_input(
     state: &mut FuzzingState,
     inp: &mut QueueItem,
     config: &Config,
+    skip_deterministic: bool,
 ) -> Result<(), SubprocessError> {
     match inp.state {
         InputState::Init(start_index) => {
             let end_index = start_index + 200;
 
             if state.minimize(inp, start_index, end_index)? {
-                inp.state = InputState::Det((0, 0));
+                if skip_deterministic {
+                    inp.state = InputState::Random;
+                } else {
+                    inp.state = InputState::Det((0, 0));
+                }
             } else {
                 inp.state = InputState::Init(end_index);
             }
@@ -93,6 +116,7 @@ fn fuzzing_thread(
     config: Config,
     ctx: Context,
     cks: Arc<ChunkStoreWrapper>,
+    skip_deterministic: bool,
 ) {
     let path_to_bin_target = config.path_to_bin_target.to_owned();
     let args = config.arguments.clone();
@@ -103,6 +127,8 @@ fn fuzzing_thread(
         global_state.clone(),
         config.dump_mode,
         config.path_to_workdir.clone(),
+        config.backend,
+        global_state.lock().expect("RAND_2365913134").timeouts.timeout,
     ).expect("RAND_3617502350");
     let mut state = FuzzingState::new(fuzzer, config.clone(), cks.clone());
     state.ctx = ctx.clone();
@@ -110,11 +136,15 @@ fn fuzzing_thread(
     let mut old_executions_per_sec = 0;
     //Normal mode
     if config.no_feedback_mode == false {
-        loop {
-            let inp = global_state.lock().expect("RAND_2191486322").queue.pop();
+        while !global_state.lock().expect("RAND_3190285476").shutdown {
+            let inp = {
+                let mut gstate_lock = global_state.lock().expect("RAND_2191486322");
+                let gstate = &mut *gstate_lock;
+                gstate.queue.pop(config.power_schedule, &gstate.path_frequency)
+            };
             if let Some(mut inp) = inp {
                 //If subprocess died restart forkserver
-                if process_input(&mut state, &mut inp, &config).is_err() {
+                if process_input(&mut state, &mut inp, &config, skip_deterministic).is_err() {
                     let args = vec![];
                     let fuzzer = Fuzzer::new(
                         path_to_bin_target.clone(),
@@ -122,6 +152,8 @@ fn fuzzing_thread(
                         global_state.clone(),
                         config.dump_mode,
                         config.path_to_workdir.clone(),
+                        config.backend,
+                        global_state.lock().expect("RAND_2247824631").timeouts.timeout,
                     ).expect("RAND_3077320530");
                     state = FuzzingState::new(fuzzer, config.clone(), cks.clone());
                     state.ctx = ctx.clone();
@@ -135,6 +167,9 @@ fn fuzzing_thread(
                     .finished(inp);
             } else {
                 for _ in 0..config.number_of_generate_inputs {
+                    if global_state.lock().expect("RAND_2714809375").shutdown {
+                        break;
+                    }
                     //If subprocess dies restart forkserver
                     if state.generate_random("START").is_err() {
                         let args = vec![];
@@ -144,6 +179,8 @@ fn fuzzing_thread(
                             global_state.clone(),
                             config.dump_mode,
                             config.path_to_workdir.clone(),
+                            config.backend,
+                            global_state.lock().expect("RAND_1313683859").timeouts.timeout,
                         ).expect("RAND_357619639");
                         state = FuzzingState::new(fuzzer, config.clone(), cks.clone());
                         state.ctx = ctx.clone();
@@ -163,6 +200,9 @@ fn fuzzing_thread(
             stats.average_executions_per_sec += state.fuzzer.average_executions_per_sec as u32;
             stats.average_executions_per_sec -= old_executions_per_sec;
             old_executions_per_sec = state.fuzzer.average_executions_per_sec as u32;
+            //Other threads recalibrate the timeout when they add queue entries
+            state.fuzzer.timeout = stats.timeouts.timeout;
+            stats.add_path_frequency(&mut state.fuzzer.path_frequency);
             if state.fuzzer.bits_found_by_havoc > 0 {
                 stats.bits_found_by_havoc += state.fuzzer.bits_found_by_havoc;
                 state.fuzzer.bits_found_by_havoc = 0;
@@ -199,7 +239,7 @@ fn fuzzing_thread(
     }
     //Else only use generation and no feedback
     else {
-        loop {
+        while !global_state.lock().expect("RAND_1862463150").shutdown {
             //If subprocess dies restart forkserver
             if state.generate_random("START").is_err() {
                 let args = vec![];
@@ -209,6 +249,8 @@ fn fuzzing_thread(
                     global_state.clone(),
                     config.dump_mode,
                     config.path_to_workdir.clone(),
+                    config.backend,
+                    global_state.lock().expect("RAND_1880054620").timeouts.timeout,
                 ).expect("RAND_574815774");
                 state = FuzzingState::new(fuzzer, config.clone(), cks.clone());
                 state.ctx = ctx.clone();
@@ -221,6 +263,9 @@ fn fuzzing_thread(
             stats.average_executions_per_sec += state.fuzzer.average_executions_per_sec as u32;
             stats.average_executions_per_sec -= old_executions_per_sec;
             old_executions_per_sec = state.fuzzer.average_executions_per_sec as u32;
+            //Other threads recalibrate the timeout when they add queue entries
+            state.fuzzer.timeout = stats.timeouts.timeout;
+            stats.add_path_frequency(&mut state.fuzzer.path_frequency);
             if state.fuzzer.bits_found_by_gen > 0 {
                 stats.bits_found_by_gen += state.fuzzer.bits_found_by_gen;
                 state.fuzzer.bits_found_by_gen = 0;
@@ -229,6 +274,281 @@ fn fuzzing_thread(
     }
 }
 
+//Parses every file in seed_dir and runs it once, so that the inputs with new coverage end up in
+//the queue before the fuzzing threads start generating. Returns the execution times of the seeds.
+fn import_seeds(
+    seed_dir: &str,
+    global_state: Arc<Mutex<GlobalSharedState>>,
+    config: &Config,
+    ctx: &Context,
+) -> Vec<u32> {
+    let new_fuzzer = || {
+        return Fuzzer::new(
+            config.path_to_bin_target.clone(),
+            config.arguments.clone(),
+            global_state.clone(),
+            config.dump_mode,
+            config.path_to_workdir.clone(),
+            config.backend,
+            global_state.lock().expect("RAND_1173510866").timeouts.timeout,
+        ).expect("RAND_1491318036");
+    };
+    let add_stats = |fuzzer: &mut Fuzzer| {
+        let mut stats = global_state.lock().expect("RAND_3008217096");
+        stats.execution_count += fuzzer.execution_count;
+        stats.bits_found_by_seed += fuzzer.bits_found_by_seed;
+        stats.add_path_frequency(&mut fuzzer.path_frequency);
+        return fuzzer.bits_found_by_seed;
+    };
+    let mut fuzzer = new_fuzzer();
+    let mut bits_found_by_seed = 0;
+    let mut execution_times = vec![];
+    let parser = Parser::new(ctx);
+    let start = ctx.nt_id("START");
+    let mut paths = fs::read_dir(seed_dir)
+        .expect("cannot read seed directory")
+        .map(|entry| entry.expect("RAND_2270913410").path())
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    paths.sort();
+    let mut imported = 0;
+    for path in paths.iter() {
+        if global_state.lock().expect("RAND_3853127262").shutdown {
+            break;
+        }
+        let mut content = vec![];
+        File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut content))
+            .expect("cannot read seed file");
+        match parser.parse(start, &content) {
+            Ok(tree) => match fuzzer.run_on_without_dedup(&tree, ExecutionReason::Seed, ctx) {
+                Ok(_) => {
+                    imported += 1;
+                    execution_times.push(fuzzer.last_execution_time);
+                }
+                //Restart the forkserver like the fuzzing threads do
+                Err(e) => {
+                    println!("Skipping seed {}: {:?}", path.display(), e);
+                    bits_found_by_seed += add_stats(&mut fuzzer);
+                    fuzzer = new_fuzzer();
+                }
+            },
+            Err(e) => println!("Skipping seed {}: {}", path.display(), e),
+        }
+    }
+    println!(
+        "{} Imported {} of {} seeds, {} with new coverage",
+        othertime::now()
+            .strftime("[%Y-%m-%d] %H:%M:%S")
+            .expect("RAND_1290482355"),
+        imported,
+        paths.len(),
+        bits_found_by_seed + add_stats(&mut fuzzer)
+    );
+    return execution_times;
+}
+
+//Picks the timeout from the execution times of the seeds, topped up with generated inputs
+fn calibrate_timeout(
+    global_state: Arc<Mutex<GlobalSharedState>>,
+    config: &Config,
+    ctx: &Context,
+    mut execution_times: Vec<u32>,
+) {
+    if config.calibrate_timeout && execution_times.len() < STARTUP_CALIBRATION_RUNS {
+        let timeout = global_state.lock().expect("RAND_2384146049").timeouts.timeout;
+        let mut fuzzer = Fuzzer::new(
+            config.path_to_bin_target.clone(),
+            config.arguments.clone(),
+            global_state.clone(),
+            config.dump_mode,
+            config.path_to_workdir.clone(),
+            config.backend,
+            timeout,
+        ).expect("RAND_3306105702");
+        //The timeout is picked from the executions up to the one that failed
+        if let Err(e) = run_calibration_inputs(&global_state, &mut fuzzer, ctx, &mut execution_times) {
+            println!(
+                "Calibration stopped after {} executions: {:?}",
+                execution_times.len(),
+                e
+            );
+        }
+        let mut stats = global_state.lock().expect("RAND_2875870551");
+        stats.execution_count += fuzzer.execution_count;
+        stats.bits_found_by_gen += fuzzer.bits_found_by_gen;
+        stats.add_path_frequency(&mut fuzzer.path_frequency);
+    }
+    let mut stats = global_state.lock().expect("RAND_1049571520");
+    stats.timeouts.add_samples(&execution_times);
+    println!(
+        "{} Timeout: {} ms (p95 execution time {} us over {} executions)",
+        othertime::now()
+            .strftime("[%Y-%m-%d] %H:%M:%S")
+            .expect("RAND_2166329514"),
+        duration_to_millis(stats.timeouts.timeout),
+        stats.timeouts.p95 / 1000,
+        stats.timeouts.number_of_samples()
+    );
+}
+
+//Minimizes the first crash of every bucket with the grammar, see Fuzzer::triage
+fn minimizer_thread(global_state: Arc<Mutex<GlobalSharedState>>, config: Config, ctx: Context) {
+    let mut triager = None;
+    loop {
+        let crash = {
+            let mut state = global_state.lock().expect("RAND_2941370388");
+            if state.shutdown {
+                break;
+            }
+            state.crashes_to_minimize.pop_front()
+        };
+        let (bucket, mut tree) = match crash {
+            Some(crash) => crash,
+            None => {
+                thread::sleep(time::Duration::from_secs(1));
+                continue;
+            }
+        };
+        if triager.is_none() {
+            let timeout = global_state.lock().expect("RAND_3714587522").timeouts.timeout;
+            match Triager::new(
+                config.backend,
+                config.path_to_bin_target.clone(),
+                config.arguments.clone(),
+                config.path_to_workdir.clone(),
+                timeout,
+                DEFAULT_NUMBER_OF_FRAMES,
+            ) {
+                Ok(new_triager) => triager = Some(new_triager),
+                Err(e) => {
+                    //The crash is tried again once the forkserver starts
+                    println!("Could not start minimization forkserver: {:?}", e);
+                    global_state
+                        .lock()
+                        .expect("RAND_1173524096")
+                        .crashes_to_minimize
+                        .push_front((bucket, tree));
+                    thread::sleep(time::Duration::from_secs(5));
+                    continue;
+                }
+            }
+        }
+        let res = {
+            let triager = triager.as_mut().expect("RAND_1655939417");
+            triager.classify(&tree.unparse_to_vec(&ctx)).and_then(|crash| {
+                //Crashes that don't reproduce are left as they are
+                if crash.is_none() {
+                    return Ok(());
+                }
+                Mutator::new(&ctx).minimize_crash(
+                    &mut tree,
+                    &ctx,
+                    &mut |t: &TreeMutation, _: &HashSet<usize>, ctx: &Context| {
+                        return Ok(triager.classify(&t.unparse_to_vec(ctx))? == crash);
+                    },
+                )?;
+                triager.save_minimized(&bucket, &tree.unparse_to_vec(&ctx));
+                return Ok(());
+            })
+        };
+        //The forkserver is restarted for the next crash
+        if let Err(e) = res {
+            println!("Minimization of crash bucket {} failed: {:?}", bucket, e);
+            triager = None;
+        }
+    }
+}
+
+//Runs generated inputs until STARTUP_CALIBRATION_RUNS execution times were measured
+fn run_calibration_inputs(
+    global_state: &Mutex<GlobalSharedState>,
+    fuzzer: &mut Fuzzer,
+    ctx: &Context,
+    execution_times: &mut Vec<u32>,
+) -> Result<(), SubprocessError> {
+    let start = ctx.nt_id("START");
+    while execution_times.len() < STARTUP_CALIBRATION_RUNS {
+        if global_state.lock().expect("RAND_1567003339").shutdown {
+            break;
+        }
+        let len = ctx.get_random_len_for_nt(&start);
+        let tree = ctx.generate_tree_from_nt(start, len);
+        fuzzer.run_on_without_dedup(&tree, ExecutionReason::Gen, ctx)?;
+        execution_times.push(fuzzer.last_execution_time);
+    }
+    return Ok(());
+}
+
+//Exports the own queue entries and runs the ones of the other gramophone and AFL instances every
+//sync_intervall
+fn sync_thread(
+    global_state: Arc<Mutex<GlobalSharedState>>,
+    config: Config,
+    ctx: Context,
+    mut instance: Option<Instance>,
+    mut afl_sync: Option<AflSync>,
+) {
+    let new_fuzzer = || {
+        return Fuzzer::new(
+            config.path_to_bin_target.clone(),
+            config.arguments.clone(),
+            global_state.clone(),
+            config.dump_mode,
+            config.path_to_workdir.clone(),
+            config.backend,
+            global_state.lock().expect("RAND_3962416318").timeouts.timeout,
+        ).expect("RAND_2802353700");
+    };
+    let mut fuzzer = new_fuzzer();
+    let mut old_execution_count = 0;
+    let mut secs_since_sync = config.sync_intervall;
+    loop {
+        if secs_since_sync >= config.sync_intervall {
+            let mut res = Ok(0);
+            if let Some(ref mut instance) = instance {
+                instance.export(&global_state, &ctx);
+                res = instance.import(&ctx, &mut |tree| {
+                    fuzzer.run_on_without_dedup(tree, ExecutionReason::Sync, &ctx)
+                });
+            }
+            if let Some(ref mut afl_sync) = afl_sync {
+                afl_sync.export(&global_state, &ctx);
+                res = res.and_then(|_| {
+                    afl_sync.import(&ctx, &mut |tree| {
+                        fuzzer.run_on_without_dedup(tree, ExecutionReason::Sync, &ctx)
+                    })
+                });
+            }
+            if let Err(e) = res {
+                println!("Sync failed: {:?}", e);
+                fuzzer = new_fuzzer();
+                old_execution_count = 0;
+            }
+            let mut stats = global_state.lock().expect("RAND_2617419683");
+            stats.execution_count += fuzzer.execution_count - old_execution_count;
+            old_execution_count = fuzzer.execution_count;
+            stats.bits_found_by_sync += fuzzer.bits_found_by_sync;
+            fuzzer.bits_found_by_sync = 0;
+            stats.add_path_frequency(&mut fuzzer.path_frequency);
+            fuzzer.timeout = stats.timeouts.timeout;
+            secs_since_sync = 0;
+        }
+        thread::sleep(time::Duration::from_secs(1));
+        if global_state.lock().expect("RAND_1146394712").shutdown {
+            break;
+        }
+        secs_since_sync += 1;
+    }
+    //The entries found since the last sync are still useful for the other instances
+    if let Some(ref mut instance) = instance {
+        instance.export(&global_state, &ctx);
+    }
+    if let Some(ref mut afl_sync) = afl_sync {
+        afl_sync.export(&global_state, &ctx);
+    }
+}
+
 fn main() {
     //Parse parameters
     let matches = App::new("gramfuzz")
@@ -244,9 +564,46 @@ fn main() {
              .help("Don't use fancy calculations to generate trees (dumb mode)"))
         .arg(Arg::with_name("grammar")
              .help("Overwrite the grammar file specified in the CONFIG"))
+        .arg(Arg::with_name("resume")
+             .short("r")
+             .long("resume")
+             .help("Continue the campaign from the state saved in the working directory"))
+        .arg(Arg::with_name("seeds")
+             .short("s")
+             .long("seeds")
+             .value_name("DIR")
+             .takes_value(true)
+             .help("Parse the inputs in DIR with the grammar and use them as initial queue entries"))
+        .arg(Arg::with_name("main")
+             .short("M")
+             .value_name("NAME")
+             .takes_value(true)
+             .conflicts_with("secondary")
+             .help("Sync with other instances as the main instance NAME"))
+        .arg(Arg::with_name("secondary")
+             .short("S")
+             .value_name("NAME")
+             .takes_value(true)
+             .help("Sync with other instances as the secondary instance NAME, skips the deterministic stages"))
+        .arg(Arg::with_name("sync_dir")
+             .long("sync-dir")
+             .value_name("DIR")
+             .takes_value(true)
+             .help("Folder shared by all instances [default: sync in the working directory]"))
+        .arg(Arg::with_name("afl_sync")
+             .long("afl-sync")
+             .value_name("DIR")
+             .takes_value(true)
+             .help("Exchange queue entries with the AFL/AFL++ instances that use DIR as output folder (afl-fuzz -o DIR)"))
+        .arg(Arg::with_name("seed")
+             .long("seed")
+             .value_name("SEED")
+             .takes_value(true)
+             .help("Seed for the random number generators, overwrites the seed in the CONFIG"))
         .get_matches();
 
     let dumb = matches.is_present("dumb");
+    let resume = matches.is_present("resume");
     let config_file_path = matches.value_of("config")
         .expect("the path to the configuration file has a default value");
 
@@ -263,140 +620,60 @@ fn main() {
     config_file
         .read_to_string(&mut config_file_contents)
         .expect("RAND_1413661228");
-    let config: Config = ron::de::from_str(&config_file_contents).expect("Failed to deserialize");
+    let mut config: Config = ron::de::from_str(&config_file_contents).expect("Failed to deserialize");
+    if let Some(seed) = matches.value_of("seed") {
+        config.seed = Some(seed.parse().expect("the seed has to be a number"));
+    }
+    //Every thread gets its own seed derived from the seed of the run, this one is used for the
+    //seeds and the calibration on the main thread
+    if let Some(seed) = config.seed {
+        rng::seed(rng::derive_seed(seed, 0));
+    }
 
     let shared = Arc::new(Mutex::new(GlobalSharedState::new(
         config.path_to_workdir.clone(),
+        TimeoutCalibration::new(&config),
     )));
     let shared_chunkstore = Arc::new(ChunkStoreWrapper::new());
 
-    //Deserialize old State
-    let queue_file_path = config.path_to_workdir.to_owned() + "saved_queue.ron";
-    let bitmaps_file_path = config.path_to_workdir.to_owned() + "saved_bitmaps.ron";
-    let chunkstore_file_path = config.path_to_workdir.to_owned() + "saved_chunkstore.ron";
-    //if Path::new(&queue_file_path).is_file() {
-    //    print!(
-    //        "{} Found old Queue...",
-    //        othertime::now()
-    //            .strftime("[%Y-%m-%d] %H:%M:%S")
-    //            .expect("RAND_386392372")
-    //    );
-    //    let mut sf = File::open(&queue_file_path).expect("cannot read saved queue file");
-    //    let mut queue_as_string = String::new();
-    //    sf.read_to_string(&mut queue_as_string)
-    //        .expect("RAND_3228814687");
-    //    shared.lock().expect("RAND_815997224").queue =
-    //        ron::de::from_str(&queue_as_string).expect("Failed to deserialize queue");
-    //    shared.lock().expect("RAND_1787917030").queue.work_dir = config.path_to_workdir.clone();
-    //    println!("queue loaded");
-    //}
-    //if Path::new(&bitmaps_file_path).is_file() {
-    //    print!(
-    //        "{} Found old Bitmaps...",
-    //        othertime::now()
-    //            .strftime("[%Y-%m-%d] %H:%M:%S")
-    //            .expect("RAND_3341541186")
-    //    );
-    //    let mut sf_bitmaps =
-    //        File::open(&bitmaps_file_path).expect("cannot read saved bitmaps file");
-    //    let mut bitmap_as_string = String::new();
-    //    sf_bitmaps
-    //        .read_to_string(&mut bitmap_as_string)
-    //        .expect("RAND_1230001140");
-    //    shared.lock().expect("RAND_4074891543").bitmaps =
-    //        ron::de::from_str(&bitmap_as_string).expect("Failed to deserialize bitmap");
-    //    println!("bitmaps loaded");
-    //}
-    //if Path::new(&chunkstore_file_path).is_file() {
-    //    print!(
-    //        "{} Found old Chunkstore...",
-    //        othertime::now()
-    //            .strftime("[%Y-%m-%d] %H:%M:%S")
-    //            .expect("RAND_334262000")
-    //    );
-    //    let mut sf_chunkstore =
-    //        File::open(&chunkstore_file_path).expect("cannot read saved chunkstore file");
-    //    let mut chunkstore_as_string = String::new();
-    //    sf_chunkstore
-    //        .read_to_string(&mut chunkstore_as_string)
-    //        .expect("RAND_3791229501");
-    //    *shared_chunkstore
-    //        .chunkstore
-    //        .write()
-    //        .expect("RAND_1421615953") =
-    //        ron::de::from_str(&chunkstore_as_string).expect("Failed to deserialize chunkstore");
-    //    println!("chunkstore loaded");
-    //}
-
     //Generate rules using a grammar or deserialize saved context
-    let mut my_context;
+    let my_context;
     let grammar_path = matches.value_of("grammar")
         .unwrap_or(&config.path_to_grammar)
         .to_owned();
-    //let serialized_context_path = grammar_path.clone() + ".gfc";
-    //let mut maybe_serialized_context = None;
-    //Calculate string of grammar file
-    let mut gf = File::open(grammar_path.clone()).expect("cannot open grammar file");
-    let mut content = String::new();
-    gf.read_to_string(&mut content)
-        .expect("cannot read grammar file");
-    let mut s = DefaultHasher::new();
-    content.hash(&mut s);
-    let hash = s.finish();
-    //Deserialize saved context if the granmmar did not change (hash value still the same)
-    //if Path::new(&serialized_context_path).is_file() {
-    //    println!("Found saved context...");
-    //    let mut cf = File::open(&serialized_context_path).expect("cannot read saved context file");
-    //    let mut context_as_string = String::new();
-    //    cf.read_to_string(&mut context_as_string)
-    //        .expect("RAND_33259161");
-    //    let serialized_context: SerializableContext =
-    //        ron::de::from_str(&context_as_string).expect("Failed to deserialize context");
-    //    //Check if file changed
-    //    if hash != serialized_context.hash_of_original {
-    //        println!("Grammar changed! Generating new context...");
-    //    } else {
-    //        maybe_serialized_context = Some(serialized_context);
-    //    }
-    //}
-    //if let Some(serialized_context) = maybe_serialized_context {
-    //    my_context = Context::from_serialized_context(serialized_context, true, dumb);
-    //    println!("imported saved context!")
-    //}
-    //Create new Context and saved it
-    //else {
-        let mut my_parser = antlr_parser::AntlrParser::new();
-        my_context = Context::with_dump(dumb);
-        if grammar_path.ends_with(".json") {
-            let gf = File::open(grammar_path).expect("cannot read grammar file");
-            let rules: Vec<Vec<String>> =
-                serde_json::from_reader(&gf).expect("cannot parse grammar file");
-            let root = "{".to_string() + &rules[0][0] + "}";
-            my_context.add_rule("START", &root);
-            for rule in rules {
-                my_context.add_rule(&rule[0], &rule[1]);
-            }
-        } else if grammar_path.ends_with(".g4") {
-            my_parser.parse_antlr_grammar(&grammar_path);
-            let root = "{".to_string() + &my_parser.rules[0].0 + "}";
-            my_context.add_rule("START", &root);
-            for rule in my_parser.rules {
-                my_context.add_rule(&rule.0, &rule.1);
-            }
-        } else {
-            panic!("Unknown grammar type");
+    let source = loader::source_for_path(&grammar_path).unwrap_or_else(|e| panic!("{}", e));
+    let hash = source.hash().unwrap_or_else(|e| panic!("{}", e));
+
+    let checkpoint = Arc::new(Checkpoint::new(&config.path_to_workdir));
+    if resume {
+        if !checkpoint.exists() {
+            panic!("Cannot resume: no saved state found in {}", config.path_to_workdir);
+        }
+        //Trees in the saved queue refer to rule ids, so they are only valid for the same grammar
+        let serialized_context = checkpoint
+            .load_context(hash)
+            .expect("Cannot resume: the grammar or the format of the context changed since the state was saved");
+        my_context = Context::from_serialized_context(serialized_context, true, dumb, config.lazy_samplers);
+        checkpoint.resume(&shared, &shared_chunkstore, &config.path_to_workdir);
+        println!(
+            "{} Resumed with {} queue entries",
+            othertime::now()
+                .strftime("[%Y-%m-%d] %H:%M:%S")
+                .expect("RAND_386392372"),
+            shared.lock().expect("RAND_1787917030").queue.len()
+        );
+    } else {
+        let mut options = LoadOptions::new(config.max_tree_size);
+        options.dumb = dumb;
+        options.lazy_samplers = config.lazy_samplers;
+        options.verbose = true;
+        //The counts are shared with the generator through the cache next to the grammar
+        options.cache_path = Some(grammar_path.clone() + ".gfc");
+        my_context = loader::load(&*source, &options).unwrap_or_else(|e| panic!("{}", e));
+        if config.save_state {
+            checkpoint.save_context(&my_context, hash);
         }
-        my_context.initialize(config.max_tree_size, true);
-        //Save context
-        //let mut cf = File::create(&serialized_context_path).expect("cannot create context file");
-        //let serializable_context: SerializableContext =
-        //    my_context.create_serializable_context(hash);
-        //cf.write_all(
-        //    ron::ser::to_string(&serializable_context)
-        //        .expect("Serialization of Context failed!")
-        //        .as_bytes(),
-        //).expect("Writing to context file failed");
-    //}
+    }
 
     //Create output folder
     fs::create_dir_all(format!("{}/outputs", config.path_to_workdir)).expect("Could not create outputs folder");
@@ -411,7 +688,51 @@ fn main() {
         fs::create_dir_all(dump_dir).expect("Could not create queue folder");
     }
 
-    let clone = shared.clone();
+    //Block SIGINT/SIGTERM before any thread is spawned so that all of them inherit the mask and
+    //only the signal thread below receives those signals
+    let mut signals = SigSet::empty();
+    signals.add(SIGINT);
+    signals.add(SIGTERM);
+    signals.thread_block().expect("RAND_2401583245");
+    {
+        let global_state = shared.clone();
+        thread::Builder::new()
+            .name("signal_handler".to_string())
+            .spawn(move || {
+                signals.wait().expect("RAND_3566092517");
+                global_state.lock().expect("RAND_1132860577").shutdown = true;
+                //A second signal skips waiting for the fuzzing threads and the final checkpoint
+                signals.wait().expect("RAND_1930474106");
+                process::exit(1);
+            })
+            .expect("RAND_2150613089");
+    }
+
+    let mut execution_times = vec![];
+    if let Some(seed_dir) = matches.value_of("seeds") {
+        execution_times = import_seeds(seed_dir, shared.clone(), &config, &my_context);
+    }
+    calibrate_timeout(shared.clone(), &config, &my_context, execution_times);
+
+    //Every instance exports to <sync_dir>/<name>/queue and imports from the other folders there
+    let instance = matches.value_of("main")
+        .map(|name| (name, true))
+        .or(matches.value_of("secondary").map(|name| (name, false)))
+        .map(|(name, main)| {
+            let sync_dir = matches.value_of("sync_dir")
+                .map(|dir| dir.to_string())
+                .unwrap_or(config.path_to_workdir.clone() + "sync");
+            Instance::new(name.to_string(), main, sync_dir, hash, resume)
+        });
+    let skip_deterministic = instance.as_ref().map(|instance| !instance.main).unwrap_or(false);
+    let afl_sync = matches.value_of("afl_sync").map(|dir| {
+        let name = instance.as_ref()
+            .map(|instance| instance.name.clone())
+            .unwrap_or("gramophone".to_string());
+        AflSync::new(dir.to_string(), name, hash, resume)
+    });
+
+    let start_time = Instant::now();
     let clone_of_chunkstore = shared_chunkstore.clone();
     let config_clone = config.clone();
     //Start fuzzing threads
@@ -422,12 +743,44 @@ fn main() {
         let ctx = my_context.clone();
         let cks = shared_chunkstore.clone();
         thread_number += 1;
+        let seed = config.seed.map(|seed| rng::derive_seed(seed, thread_number));
         thread::Builder::new()
             .name(format!("fuzzer_{}", thread_number))
             .stack_size(config.thread_size)
-            .spawn(move || fuzzing_thread(state, config, ctx, cks))
+            .spawn(move || {
+                if let Some(seed) = seed {
+                    rng::seed(seed);
+                }
+                fuzzing_thread(state, config, ctx, cks, skip_deterministic)
+            })
     });
 
+    let sync_thread = if instance.is_some() || afl_sync.is_some() {
+        let global_state = shared.clone();
+        let config = config.clone();
+        let ctx = my_context.clone();
+        Some(
+            thread::Builder::new()
+                .name("sync".to_string())
+                .stack_size(config.thread_size)
+                .spawn(move || sync_thread(global_state, config, ctx, instance, afl_sync))
+                .expect("RAND_1525186170"),
+        )
+    } else {
+        None
+    };
+
+    let minimizer_thread = {
+        let global_state = shared.clone();
+        let config = config.clone();
+        let ctx = my_context.clone();
+        thread::Builder::new()
+            .name("minimizer".to_string())
+            .stack_size(config.thread_size)
+            .spawn(move || minimizer_thread(global_state, config, ctx))
+            .expect("RAND_2089441093")
+    };
+
     //Start status thread
     let status_thread = {
         let config = config.clone();
@@ -436,6 +789,8 @@ fn main() {
         thread::Builder::new()
             .name("status_thread".to_string())
             .spawn(move || {
+                //Same numbers as on screen for scripts, rewritten every second
+                let stats_path = config.path_to_workdir.clone() + "outputs/fuzzer_stats";
                 let start_time = Instant::now();
                 thread::sleep(time::Duration::from_secs(1));
                 print!("{}[2J", 27 as char);
@@ -444,7 +799,10 @@ fn main() {
                     let execution_count;
                     let average_executions_per_sec;
                     let queue_len;
+                    let favored;
                     let bits_found_by_gen;
+                    let bits_found_by_seed;
+                    let bits_found_by_sync;
                     let bits_found_by_min;
                     let bits_found_by_min_rec;
                     let bits_found_by_det;
@@ -458,12 +816,23 @@ fn main() {
                     let total_found_asan;
                     let total_found_sig;
                     let state_saved;
+                    let timeout;
+                    let execution_time_p95;
+                    let calibration_samples;
+                    let variable_bits;
+                    let stability;
                     {
                         let shared_state = global_state.lock().expect("RAND_597319831");
+                        if shared_state.shutdown {
+                            break;
+                        }
                         execution_count = shared_state.execution_count;
                         average_executions_per_sec = shared_state.average_executions_per_sec;
                         queue_len = shared_state.queue.len();
+                        favored = shared_state.queue.favored_len();
                         bits_found_by_gen = shared_state.bits_found_by_gen;
+                        bits_found_by_seed = shared_state.bits_found_by_seed;
+                        bits_found_by_sync = shared_state.bits_found_by_sync;
                         bits_found_by_min = shared_state.bits_found_by_min;
                         bits_found_by_min_rec = shared_state.bits_found_by_min_rec;
                         bits_found_by_det = shared_state.bits_found_by_det;
@@ -477,6 +846,16 @@ fn main() {
                         total_found_asan = shared_state.total_found_asan;
                         total_found_sig = shared_state.total_found_sig;
                         state_saved = shared_state.state_saved.clone();
+                        timeout = duration_to_millis(shared_state.timeouts.timeout);
+                        execution_time_p95 = shared_state.timeouts.p95 / 1000;
+                        calibration_samples = shared_state.timeouts.number_of_samples();
+                        variable_bits = shared_state.variable_bits.len();
+                        let covered = shared_state
+                            .bitmaps
+                            .get(&false)
+                            .map(|bitmap| bitmap.iter().filter(|&&class| class != 0).count())
+                            .unwrap_or(0);
+                        stability = calibration::stability(variable_bits, covered);
                     }
                     let secs = start_time.elapsed().as_secs();
                     let minutes = secs / 60;
@@ -506,6 +885,10 @@ fn main() {
                             "Left in queue:            {}                              ",
                             queue_len
                         );
+                        println!(
+                            "Favored entries:          {}                              ",
+                            favored
+                        );
                         let now = Instant::now();
                         while shared_cks.is_locked.load(Ordering::SeqCst) {
                             if now.elapsed().as_secs() > 30 {
@@ -534,6 +917,14 @@ fn main() {
                         "Last Timeout:             {}                              ",
                         last_timeout
                     );
+                    println!(
+                        "Timeout:                  {} ms (p95 execution time {} us)        ",
+                        timeout, execution_time_p95
+                    );
+                    println!(
+                        "Stability:                {:.2}% ({} variable bits)              ",
+                        stability, variable_bits
+                    );
                     println!(
                         "Total ASAN crashes:       {}                              ",
                         total_found_asan
@@ -548,6 +939,14 @@ fn main() {
                         bits_found_by_gen
                     );
                     if config.no_feedback_mode == false {
+                        println!(
+                            "New paths found by Seed:         {}                       ",
+                            bits_found_by_seed
+                        );
+                        println!(
+                            "New paths found by Sync:         {}                       ",
+                            bits_found_by_sync
+                        );
                         println!(
                             "New paths found by Min:          {}                       ",
                             bits_found_by_min
@@ -584,6 +983,35 @@ fn main() {
                     );
                     println!("------------------------------------------------------    ");
                     //println!("Global bitmap: {:?}", global_state.lock().expect("RAND_1887203473").bitmaps.get(&false).expect("RAND_1887203473"));
+                    write_atomic(
+                        &stats_path,
+                        &format!(
+                            "run_time_secs            : {}\n\
+                             execution_count          : {}\n\
+                             executions_per_sec       : {}\n\
+                             queue_len                : {}\n\
+                             favored                  : {}\n\
+                             total_found_asan         : {}\n\
+                             total_found_sig          : {}\n\
+                             timeout_ms               : {}\n\
+                             execution_time_p95_us    : {}\n\
+                             calibration_executions   : {}\n\
+                             stability                : {:.2}\n\
+                             variable_bits            : {}\n",
+                            secs,
+                            execution_count,
+                            average_executions_per_sec,
+                            queue_len,
+                            favored,
+                            total_found_asan,
+                            total_found_sig,
+                            timeout,
+                            execution_time_p95,
+                            calibration_samples,
+                            stability,
+                            variable_bits
+                        ),
+                    );
                     thread::sleep(time::Duration::from_secs(1));
                 }
             })
@@ -591,69 +1019,68 @@ fn main() {
     };
 
     //Start saving thread
-    if config_clone.save_state {
-        let save_thread = {
-            let global_state = shared.clone();
+    let save_thread = if config_clone.save_state {
+        let global_state = shared.clone();
+        let checkpoint = checkpoint.clone();
+        let cks = clone_of_chunkstore.clone();
+        Some(
             thread::Builder::new()
                 .name("state_saver".to_string())
                 .stack_size(config_clone.save_thread_size)
                 .spawn(move || {
-                    //let mut id = "1";
+                    //Sleep in small steps to notice a shutdown without waiting a whole intervall
+                    let mut secs_since_save = 0;
                     loop {
-                        thread::sleep(time::Duration::from_secs(config_clone.save_intervall));
-
-                        let mut of =
-                            File::create(&queue_file_path).expect("cannot create output file");
-                        of.write_all(
-                            ron::ser::to_string(&clone.lock().expect("RAND_372393424").queue)
-                                .expect("Serialization of Queue failed!")
-                                .as_bytes(),
-                        ).expect("Writing to queue file failed");
-
-                        let mut of_bitmap =
-                            File::create(&bitmaps_file_path).expect("cannot create output file");
-                        of_bitmap
-                            .write_all(
-                                ron::ser::to_string(
-                                    &clone.lock().expect("RAND_1525717184").bitmaps,
-                                ).expect("Serialization of Bitmaps failed!")
-                                    .as_bytes(),
-                            )
-                            .expect("Writing to bitmap file failed");
-
-                        let mut of_chunkstore = File::create(
-                            &/*(*/chunkstore_file_path, /*.to_owned()+id)*/
-                        ).expect("cannot create output file");
-                        of_chunkstore
-                            .write_all(
-                                ron::ser::to_string(
-                                    &(*clone_of_chunkstore
-                                        .chunkstore
-                                        .read()
-                                        .expect("RAND_4283477146")),
-                                ).expect("Serialization of Chunkstore failed!")
-                                    .as_bytes(),
-                            )
-                            .expect("Writing to Chunkstore file failed");
-                        //id = if id == "1" { "0" } else { "1" };
-                        {
-                            global_state.lock().expect("RAND_3289262969").state_saved =
-                                strftime("[%Y-%m-%d] %H:%M:%S", &othertime::now())
-                                    .expect("RAND_3227256997");
+                        thread::sleep(time::Duration::from_secs(1));
+                        if global_state.lock().expect("RAND_2727450127").shutdown {
+                            break;
+                        }
+                        secs_since_save += 1;
+                        if secs_since_save >= config_clone.save_intervall {
+                            checkpoint.save(&global_state, &cks);
+                            secs_since_save = 0;
                         }
                     }
                 })
-                .expect("RAND_2513095620")
-        };
+                .expect("RAND_2513095620"),
+        )
+    } else {
+        None
+    };
 
-        for t in threads.collect::<Vec<_>>().into_iter() {
-            t.expect("RAND_1599964266").join().expect("RAND_1599964266");
-        }
+    for t in threads.collect::<Vec<_>>().into_iter() {
+        t.expect("RAND_1599964266").join().expect("RAND_1599964266");
+    }
+    if let Some(save_thread) = save_thread {
         save_thread.join().expect("RAND_2798744238");
-    } else {
-        for t in threads.collect::<Vec<_>>().into_iter() {
-            t.expect("RAND_2698731594").join().expect("RAND_2698731594");
-        }
     }
+    if let Some(sync_thread) = sync_thread {
+        sync_thread.join().expect("RAND_3328812571");
+    }
+    minimizer_thread.join().expect("RAND_2312978350");
     status_thread.join().expect("RAND_399292929");
+
+    //All fuzzing threads finished their last input, so this checkpoint contains everything found
+    if config.save_state {
+        checkpoint.save(&shared, &clone_of_chunkstore);
+    }
+    let shared_state = shared.lock().expect("RAND_4157738215");
+    let secs = start_time.elapsed().as_secs();
+    println!(
+        "{} Fuzzing stopped after {} hours, {} minutes, {} seconds",
+        othertime::now()
+            .strftime("[%Y-%m-%d] %H:%M:%S")
+            .expect("RAND_2884172563"),
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60
+    );
+    println!("Execution Count:          {}", shared_state.execution_count);
+    println!("Queue entries:            {}", shared_state.queue.len());
+    println!("Total ASAN crashes:       {}", shared_state.total_found_asan);
+    println!("Total SIG crashes:        {}", shared_state.total_found_sig);
+    println!("Last Timeout:             {}", shared_state.last_timeout);
+    if config.save_state {
+        println!("State saved:              {}", shared_state.state_saved);
+    }
 }
@@ -4,11 +4,16 @@ use std::fs;
 use std::fs::File;
 use std::io::ErrorKind;
 
+use bitmap;
+use config::PowerSchedule;
 use forksrv::exitreason::ExitReason;
 use grammartec::context::Context;
 use grammartec::newtypes::NodeID;
+use grammartec::rng;
 use grammartec::tree::Tree;
 use grammartec::tree::TreeLike;
+use rand::Rng;
+use schedule;
 
 #[derive(Serialize, Clone, Deserialize)]
 pub enum InputState {
@@ -23,11 +28,32 @@ pub struct QueueItem {
     pub id: usize,
     pub tree: Tree,
     pub fresh_bits: HashSet<usize>,
-    pub all_bits: Vec<u8>,
+    //Sorted (edge, bucket) keys of the classified bitmap, see bitmap::bucket_keys
+    #[serde(default)]
+    pub bits: Vec<usize>,
+    //Whole bitmap as stored by older versions, converted to bits by Queue::rebuild_index
+    #[serde(default, skip_serializing)]
+    all_bits: Vec<u8>,
     pub exitreason: ExitReason,
     pub state: InputState,
     pub recursions: Option<Vec<(NodeID, NodeID)>>,
+    //Average of the calibration runs in nanoseconds
     pub execution_time: u32,
+    //Number of covered bitmap indices
+    #[serde(default)]
+    pub bitmap_size: usize,
+    //Part of the minimal set of entries that covers all known bits, see Queue::cull
+    #[serde(default)]
+    pub favored: bool,
+    //How often the entry was picked by pop
+    #[serde(default)]
+    pub times_fuzzed: u32,
+    //Hash of the classified bitmap, identifies the path for the fast power schedule
+    #[serde(default)]
+    pub path_hash: u64,
+    //Multiple of the normal havoc, splice and havoc_recursion iterations, set by pop
+    #[serde(skip)]
+    pub energy: f64,
 }
 
 impl QueueItem {
@@ -35,19 +61,27 @@ impl QueueItem {
         id: usize,
         tree: Tree,
         fresh_bits: HashSet<usize>,
-        all_bits: Vec<u8>,
+        bits: Vec<usize>,
         exitreason: ExitReason,
         execution_time: u32,
+        path_hash: u64,
     ) -> Self {
+        let bitmap_size = bits.len();
         return QueueItem {
             id,
             tree,
             fresh_bits,
-            all_bits,
+            bits,
+            all_bits: vec![],
             exitreason,
             state: InputState::Init(0),
             recursions: None,
             execution_time,
+            bitmap_size,
+            favored: false,
+            times_fuzzed: 0,
+            path_hash,
+            energy: 1.0,
         };
     }
 }
@@ -56,41 +90,42 @@ impl QueueItem {
 pub struct Queue {
     pub inputs: Vec<QueueItem>,
     pub processed: Vec<QueueItem>,
-    pub bit_to_inputs: HashMap<usize, Vec<usize>>,
+    //Copies of the entries the fuzzing threads are working on, as they were when pop returned them.
+    //They are saved with the queue and put back into inputs on resume.
+    #[serde(default)]
+    pub in_flight: HashMap<usize, QueueItem>,
+    //Number of entries in inputs and processed that cover each (edge, bucket) key. Entries that
+    //are being processed don't count, their bits are released by pop and taken again by finished.
+    #[serde(skip)]
+    pub bit_owners: HashMap<usize, u32>,
     pub current_id: usize,
     pub work_dir: String,
+    //Favored entries in inputs, while there are any the other entries are skipped even more often
+    #[serde(default)]
+    pub pending_favored: usize,
 }
 
 impl Queue {
+    //bits are the sorted (edge, bucket) keys of the entry
     pub fn add(
         &mut self,
         tree: Tree,
-        all_bits: Vec<u8>,
-        mut new_bits: Vec<usize>,
+        bits: Vec<usize>,
         exitreason: ExitReason,
         ctx: &Context,
         execution_time: u32,
+        path_hash: u64,
     ) {
-        if all_bits
-            .iter()
-            .enumerate()
-            .all(|(i, elem)| (*elem == 0) || self.bit_to_inputs.contains_key(&i))
-        {
+        if self.is_covered(&bits) {
             return;
         }
-        let mut fresh_bits = HashSet::new();
-        //Check which bits are new and insert them into fresh_bits
-        for (i, elem) in all_bits.iter().enumerate() {
-            if *elem != 0 {
-                if !self.bit_to_inputs.contains_key(&i) {
-                    fresh_bits.insert(i.clone());
-                }
-                self.bit_to_inputs
-                    .entry(i)
-                    .or_insert(vec![])
-                    .push(self.current_id);
-            }
-        }
+        //Check which (edge, bucket) pairs are new and insert them into fresh_bits
+        let fresh_bits = bits
+            .iter()
+            .filter(|key| !self.bit_owners.contains_key(key))
+            .cloned()
+            .collect::<HashSet<_>>();
+        self.take_bits(&bits);
 
         //Create File for entry
         let mut file = File::create(format!(
@@ -99,15 +134,20 @@ impl Queue {
         )).expect("RAND_259979732");
         tree.unparse_to(&ctx, &mut file).expect("RAND_3408190314");
 
-        //Add entry to queue
-        self.inputs.push(QueueItem::new(
+        //Add entry to queue. It is the only entry with its fresh bits, so the next culling would
+        //favor it anyway.
+        let mut item = QueueItem::new(
             self.current_id,
             tree,
             fresh_bits,
-            all_bits,
+            bits,
             exitreason,
             execution_time,
-        ));
+            path_hash,
+        );
+        item.favored = true;
+        self.pending_favored += 1;
+        self.inputs.push(item);
 
         //Increase current_id
         if self.current_id == usize::max_value() {
@@ -121,42 +161,65 @@ impl Queue {
         return Queue {
             inputs: vec![],
             processed: vec![],
-            bit_to_inputs: HashMap::new(),
+            in_flight: HashMap::new(),
+            bit_owners: HashMap::new(),
             current_id: 0,
             work_dir: work_dir,
+            pending_favored: 0,
         };
     }
 
-    pub fn pop(&mut self) -> Option<QueueItem> {
-        let option = self.inputs.pop();
-        if option.is_some() {
-            let item = option.expect("RAND_607640468");
-            let id = item.id;
-            let mut keys = Vec::with_capacity(self.bit_to_inputs.keys().len()); //TODO: Find a better solution for this
-            {
-                for k in self.bit_to_inputs.keys() {
-                    keys.push(*k);
-                }
+    //Entries that are not favored and already went through the deterministic stages are skipped
+    //with high probability. The energy of the returned entry is set according to the schedule.
+    pub fn pop(&mut self, power_schedule: PowerSchedule, path_frequency: &[u32]) -> Option<QueueItem> {
+        while let Some(mut item) = self.inputs.pop() {
+            if item.favored {
+                self.pending_favored = self.pending_favored.saturating_sub(1);
             }
-            for k in keys {
-                let mut v = self.bit_to_inputs.remove(&k).expect("RAND_2593710501");
-                v.remove_item(&id);
-                if !v.is_empty() {
-                    self.bit_to_inputs.insert(k, v);
-                }
+            if self.skip(&item) {
+                self.processed.push(item);
+                continue;
             }
+            let rarest_key_count = item
+                .bits
+                .iter()
+                .filter_map(|key| self.bit_owners.get(key).cloned())
+                .min()
+                .unwrap_or(1);
+            item.energy = schedule::energy(
+                power_schedule,
+                item.times_fuzzed,
+                path_frequency[schedule::path_frequency_index(item.path_hash)],
+                rarest_key_count as usize,
+            );
+            item.times_fuzzed += 1;
+            self.release_bits(&item.bits);
+            self.in_flight.insert(item.id, item.clone());
             return Some(item);
         }
         return None;
     }
 
+    fn skip(&self, item: &QueueItem) -> bool {
+        if item.favored {
+            return false;
+        }
+        match item.state {
+            InputState::Random => {}
+            _ => return false,
+        }
+        let prob = if self.pending_favored > 0 {
+            schedule::SKIP_NON_FAVORED_PENDING_PROB
+        } else {
+            schedule::SKIP_NON_FAVORED_PROB
+        };
+        return rng::rng().gen_range(0, 100) < prob;
+    }
+
+    //Entries whose bits are all covered by other entries are dropped
     pub fn finished(&mut self, item: QueueItem) {
-        if item
-            .all_bits
-            .iter()
-            .enumerate()
-            .all(|(i, elem)| (*elem == 0) || self.bit_to_inputs.contains_key(&i))
-        {
+        self.in_flight.remove(&item.id);
+        if self.is_covered(&item.bits) {
             //If file was created for this entry, delete it.
             match fs::remove_file(format!(
                 "{}outputs/queue/id:{:09},er:{:?}",
@@ -169,18 +232,57 @@ impl Queue {
             }
             return;
         }
+        self.take_bits(&item.bits);
+        self.processed.push(item);
+    }
 
-        //Check which bits are new and insert them into fresh_bits
-        let mut fresh_bits = HashSet::new();
-        for (i, elem) in item.all_bits.iter().enumerate() {
-            if *elem != 0 {
-                if !self.bit_to_inputs.contains_key(&i) {
-                    fresh_bits.insert(i.clone());
-                }
-                self.bit_to_inputs.entry(i).or_insert(vec![]).push(item.id);
+    //Puts the entries that were in flight when the queue was saved back into inputs
+    pub fn requeue_in_flight(&mut self) {
+        let mut items = self.in_flight.drain().map(|(_, item)| item).collect::<Vec<_>>();
+        items.sort_by_key(|item| item.id);
+        for item in items {
+            if item.favored {
+                self.pending_favored += 1;
+            }
+            self.inputs.push(item);
+        }
+    }
+
+    //Recomputes the owners of all bits, e.g. after the queue was deserialized
+    pub fn rebuild_index(&mut self) {
+        self.bit_owners.clear();
+        for item in self.inputs.iter_mut().chain(self.processed.iter_mut()) {
+            if item.bits.is_empty() && !item.all_bits.is_empty() {
+                item.bits = bitmap::bucket_keys(&item.all_bits).collect();
+                item.all_bits = vec![];
+            }
+            for key in item.bits.iter() {
+                *self.bit_owners.entry(*key).or_insert(0) += 1;
+            }
+        }
+    }
+
+    fn is_covered(&self, bits: &[usize]) -> bool {
+        return bits.iter().all(|key| self.bit_owners.contains_key(key));
+    }
+
+    fn take_bits(&mut self, bits: &[usize]) {
+        for key in bits.iter() {
+            *self.bit_owners.entry(*key).or_insert(0) += 1;
+        }
+    }
+
+    fn release_bits(&mut self, bits: &[usize]) {
+        for key in bits.iter() {
+            let last = {
+                let owners = self.bit_owners.get_mut(key).expect("RAND_2593710501");
+                *owners -= 1;
+                *owners == 0
+            };
+            if last {
+                self.bit_owners.remove(key);
             }
         }
-        self.processed.push(item);
     }
 
     pub fn len(&self) -> usize {
@@ -189,5 +291,35 @@ impl Queue {
 
     pub fn new_round(&mut self) {
         self.inputs.append(&mut self.processed);
+        self.cull();
+    }
+
+    //Marks the minimal set of entries that covers all known bits as favored. Fast and small
+    //entries are preferred.
+    pub fn cull(&mut self) {
+        let scores = self
+            .inputs
+            .iter()
+            .map(|item| item.execution_time as u64 * item.tree.size() as u64)
+            .collect::<Vec<_>>();
+        let keys = self
+            .inputs
+            .iter()
+            .map(|item| item.bits.clone())
+            .collect::<Vec<_>>();
+        let favored = schedule::cull(&scores, &keys);
+        for (item, favored) in self.inputs.iter_mut().zip(favored.into_iter()) {
+            item.favored = favored;
+        }
+        self.pending_favored = self.inputs.iter().filter(|item| item.favored).count();
+    }
+
+    pub fn favored_len(&self) -> usize {
+        return self
+            .inputs
+            .iter()
+            .chain(self.processed.iter())
+            .filter(|item| item.favored)
+            .count();
     }
 }
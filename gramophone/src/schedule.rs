@@ -0,0 +1,129 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashSet};
+
+use bitmap;
+use config::PowerSchedule;
+
+//No entry gets more than this multiple of the normal havoc, splice and havoc_recursion iterations
+pub const MAX_ENERGY: f64 = 32.0;
+//Number of path frequency counters for the fast schedule, paths with the same hash modulo this share one
+pub const PATH_FREQUENCY_SIZE: usize = 1 << 21;
+//Probability (in percent) that an entry which is not favored is skipped, as in AFL
+pub const SKIP_NON_FAVORED_PROB: u32 = 95;
+pub const SKIP_NON_FAVORED_PENDING_PROB: u32 = 99;
+
+//Greedy culling as in AFL: every (edge, bucket) key gets the entry with the lowest score that covers
+//it, then the keys are walked in order and the best entry of every key that is not covered yet is
+//favored. The favored entries cover all keys. Returns one flag per entry.
+pub fn cull(scores: &[u64], keys: &[Vec<usize>]) -> Vec<bool> {
+    let mut top_rated: BTreeMap<usize, usize> = BTreeMap::new();
+    for (i, entry_keys) in keys.iter().enumerate() {
+        for key in entry_keys.iter() {
+            let best = top_rated.entry(*key).or_insert(i);
+            if scores[i] < scores[*best] {
+                *best = i;
+            }
+        }
+    }
+    let mut favored = vec![false; keys.len()];
+    let mut covered = HashSet::new();
+    for (key, &i) in top_rated.iter() {
+        if covered.contains(key) {
+            continue;
+        }
+        favored[i] = true;
+        covered.extend(keys[i].iter().cloned());
+    }
+    return favored;
+}
+
+//Multiple of the normal number of iterations an entry gets when it is picked.
+//times_fuzzed: how often the entry was picked before
+//path_frequency: number of executions that took the same path as the entry
+//rarest_key_count: number of queue entries that cover the rarest key of the entry
+pub fn energy(
+    schedule: PowerSchedule,
+    times_fuzzed: u32,
+    path_frequency: u32,
+    rarest_key_count: usize,
+) -> f64 {
+    match schedule {
+        PowerSchedule::Fixed => return 1.0,
+        PowerSchedule::Coverage => {
+            let count = cmp::max(rarest_key_count, 1) as f64;
+            return (4.0 / count).min(MAX_ENERGY);
+        }
+        PowerSchedule::Fast => {
+            //AFLFast: exponential in the number of times the entry was picked, divided by the path frequency
+            let frequency = cmp::max(path_frequency, 1) as f64;
+            let factor = 2.0f64.powi(cmp::min(times_fuzzed, 16) as i32) / frequency;
+            return factor.min(MAX_ENERGY);
+        }
+    }
+}
+
+//Every stage runs at least once, even for entries with a tiny energy
+pub fn iterations(base: usize, energy: f64) -> usize {
+    return cmp::max((base as f64 * energy) as usize, 1);
+}
+
+//Cheap hash of a classified bitmap that identifies the path of an execution
+pub fn path_hash(bitmap: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for (i, chunk) in bitmap.chunks(8).enumerate() {
+        let word = bitmap::word(chunk);
+        if word != 0 {
+            hash = (hash ^ word ^ i as u64).wrapping_mul(0x100000001b3);
+            hash ^= hash >> 29;
+        }
+    }
+    return hash;
+}
+
+pub fn path_frequency_index(hash: u64) -> usize {
+    return (hash % PATH_FREQUENCY_SIZE as u64) as usize;
+}
+
+#[cfg(test)]
+mod tests {
+    use config::PowerSchedule;
+    use schedule::{cull, energy, iterations, path_hash, MAX_ENERGY};
+
+    #[test]
+    fn check_cull() {
+        //Entry 1 covers everything but is slow, entries 0 and 2 cover it together and are cheaper
+        let keys = vec![vec![1, 2], vec![1, 2, 3], vec![3], vec![2]];
+        let scores = vec![10, 100, 10, 1];
+        assert_eq!(cull(&scores, &keys), vec![true, false, true, false]);
+        //Now entry 1 is the best one for keys 1 and 3 and covers key 2 on the way
+        let scores = vec![10, 5, 10, 1];
+        assert_eq!(cull(&scores, &keys), vec![false, true, false, false]);
+        assert!(cull(&[], &[]).is_empty());
+    }
+
+    #[test]
+    fn check_energy() {
+        assert_eq!(energy(PowerSchedule::Fixed, 10, 1000, 1000), 1.0);
+        assert_eq!(energy(PowerSchedule::Coverage, 0, 0, 1), 4.0);
+        assert_eq!(energy(PowerSchedule::Coverage, 0, 0, 16), 0.25);
+        assert_eq!(energy(PowerSchedule::Fast, 0, 4, 0), 0.25);
+        assert_eq!(energy(PowerSchedule::Fast, 3, 4, 0), 2.0);
+        assert_eq!(energy(PowerSchedule::Fast, 100, 0, 0), MAX_ENERGY);
+        assert_eq!(iterations(100, 1.0), 100);
+        assert_eq!(iterations(100, 0.001), 1);
+        assert_eq!(iterations(20, 2.5), 50);
+    }
+
+    #[test]
+    fn check_path_hash() {
+        let mut bitmap = vec![0u8; 64];
+        let empty = path_hash(&bitmap);
+        bitmap[9] = 1;
+        let one = path_hash(&bitmap);
+        bitmap[9] = 2;
+        let two = path_hash(&bitmap);
+        assert!(empty != one && one != two && empty != two);
+        bitmap[9] = 1;
+        assert_eq!(path_hash(&bitmap), one);
+    }
+}
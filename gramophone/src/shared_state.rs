@@ -1,10 +1,17 @@
+use calibration::TimeoutCalibration;
+use grammartec::tree::Tree;
 use queue::Queue;
-use std::collections::HashMap;
+use schedule::PATH_FREQUENCY_SIZE;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 pub struct GlobalSharedState {
     pub queue: Queue,
     //false for not crashing input. True for crashing inputs
     pub bitmaps: HashMap<bool, Vec<u8>>,
+    //Bitmap indices that changed between runs of the same input, new bits on them are ignored
+    pub variable_bits: HashSet<usize>,
+    //Number of executions per path hash, for the fast power schedule
+    pub path_frequency: Vec<u32>,
     pub execution_count: u64,
     pub average_executions_per_sec: u32,
     pub bits_found_by_havoc: u64,
@@ -15,6 +22,8 @@ pub struct GlobalSharedState {
     pub bits_found_by_det: u64,
     pub bits_found_by_det_afl: u64,
     pub bits_found_by_gen: u64,
+    pub bits_found_by_seed: u64,
+    pub bits_found_by_sync: u64,
     pub asan_found_by_havoc: u64,
     pub asan_found_by_havoc_rec: u64,
     pub asan_found_by_min: u64,
@@ -29,15 +38,22 @@ pub struct GlobalSharedState {
     pub state_saved: String,
     pub total_found_asan: u64,
     pub total_found_sig: u64,
+    pub timeouts: TimeoutCalibration,
+    //Bucket and tree of the first crash of every new bucket, minimized by the minimizer thread
+    pub crashes_to_minimize: VecDeque<(String, Tree)>,
+    //Set on SIGINT/SIGTERM, all threads stop once they see it
+    pub shutdown: bool,
 }
 
 impl GlobalSharedState {
-    pub fn new(work_dir: String) -> Self {
+    pub fn new(work_dir: String, timeouts: TimeoutCalibration) -> Self {
         let queue = Queue::new(work_dir);
         let bitmaps = HashMap::new();
         return GlobalSharedState {
             queue,
             bitmaps,
+            variable_bits: HashSet::new(),
+            path_frequency: vec![0; PATH_FREQUENCY_SIZE],
             execution_count: 0,
             average_executions_per_sec: 0,
             bits_found_by_havoc: 0,
@@ -48,6 +64,8 @@ impl GlobalSharedState {
             bits_found_by_det: 0,
             bits_found_by_det_afl: 0,
             bits_found_by_gen: 0,
+            bits_found_by_seed: 0,
+            bits_found_by_sync: 0,
             asan_found_by_havoc: 0,
             asan_found_by_havoc_rec: 0,
             asan_found_by_min: 0,
@@ -62,6 +80,16 @@ impl GlobalSharedState {
             state_saved: String::from("State not saved yet."),
             total_found_asan: 0,
             total_found_sig: 0,
+            timeouts,
+            crashes_to_minimize: VecDeque::new(),
+            shutdown: false,
         };
     }
+
+    //Adds the path frequencies a fuzzer counted since the last call
+    pub fn add_path_frequency(&mut self, path_frequency: &mut HashMap<usize, u32>) {
+        for (index, count) in path_frequency.drain() {
+            self.path_frequency[index] = self.path_frequency[index].saturating_add(count);
+        }
+    }
 }
@@ -10,9 +10,16 @@ use grammartec::mutator::Mutator;
 use grammartec::tree::{TreeLike, TreeMutation};
 
 use forksrv::error::SubprocessError;
-use fuzzer::{ExecutionReason, Fuzzer};
+use executor::ExecutionReason;
+use fuzzer::Fuzzer;
 use queue::QueueItem;
 use config::Config;
+use schedule;
+
+//Iterations per stage for an entry with energy 1
+const HAVOC_ITERATIONS: usize = 100;
+const HAVOC_RECURSION_ITERATIONS: usize = 20;
+const SPLICE_ITERATIONS: usize = 100;
 
 pub struct FuzzingState {
     pub cks: Arc<ChunkStoreWrapper>,
@@ -145,7 +152,7 @@ impl FuzzingState {
     pub fn havoc(&mut self, input: &mut QueueItem) -> Result<(), SubprocessError> {
         let ctx = &mut self.ctx;
         let fuzzer = &mut self.fuzzer;
-        for _i in 0..100 {
+        for _i in 0..schedule::iterations(HAVOC_ITERATIONS, input.energy) {
             self.mutator
                 .mut_random(&input.tree, ctx, &mut |t: &TreeMutation, ctx: &Context| {
                     fuzzer.run_on_with_dedup(t, ExecutionReason::Havoc, ctx).map(|_|())
@@ -155,7 +162,7 @@ impl FuzzingState {
     }
 
     pub fn havoc_recursion(&mut self, input: &mut QueueItem) -> Result<(), SubprocessError> {
-        for _i in 0..20 {
+        for _i in 0..schedule::iterations(HAVOC_RECURSION_ITERATIONS, input.energy) {
             if let Some(ref recursions) = input.recursions {
                 let ctx = &mut self.ctx;
                 let fuzzer = &mut self.fuzzer;
@@ -175,7 +182,7 @@ impl FuzzingState {
     pub fn splice(&mut self, input: &mut QueueItem) -> Result<(), SubprocessError> {
         let ctx = &mut self.ctx;
         let fuzzer = &mut self.fuzzer;
-        for _i in 0..100 {
+        for _i in 0..schedule::iterations(SPLICE_ITERATIONS, input.energy) {
             let now = Instant::now();
             while self.cks.is_locked.load(Ordering::SeqCst) {
                 if now.elapsed().as_secs() > 30 {
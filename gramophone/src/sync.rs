@@ -0,0 +1,387 @@
+//AFL style synchronization between fuzzer instances. Every instance exports its queue entries to
+//<sync_dir>/<name>/queue and imports the entries the other instances exported there. AflSync does
+//the same with the sync folder of AFL/AFL++ instances (afl-fuzz -o).
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use checkpoint::write_atomic;
+use grammartec::context::Context;
+use grammartec::parser::Parser;
+use grammartec::sync::{afl_tree_path, afl_trees_dir, SyncEntry};
+use grammartec::tree::{Tree, TreeLike};
+use ron;
+use shared_state::GlobalSharedState;
+
+pub struct Instance {
+    pub name: String,
+    //The main instance runs the deterministic stages, secondary instances skip them (-M/-S)
+    pub main: bool,
+    pub sync_dir: String,
+    hash_of_grammar: u64,
+    exported: HashSet<usize>,
+    //Ids imported from each of the other instances. Entries are not exported in id order (popped
+    //entries are exported when they are back in the queue), so a highest id would skip some.
+    imported: HashMap<String, HashSet<usize>>,
+}
+
+impl Instance {
+    //A resumed campaign keeps its queue ids, so the entries already in the sync folder count as
+    //exported, and the entries it imported before are not run again. A new campaign starts again at
+    //id 0 and imports everything.
+    pub fn new(name: String, main: bool, sync_dir: String, hash_of_grammar: u64, resume: bool) -> Self {
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            panic!("Invalid instance name: {:?}", name);
+        }
+        let queue_dir = format!("{}/{}/queue", sync_dir, name);
+        fs::create_dir_all(&queue_dir).expect("Could not create sync folder");
+        let exported = if resume {
+            entries_in(&queue_dir, parse_tree_file_name)
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect()
+        } else {
+            HashSet::new()
+        };
+        let imported = load_imported(&format!("{}/{}", sync_dir, name), resume);
+        return Instance {
+            name,
+            main,
+            sync_dir,
+            hash_of_grammar,
+            exported,
+            imported,
+        };
+    }
+
+    //Writes the queue entries that were not exported yet. The bytes are written first and the tree
+    //is renamed into place, so an instance that sees the tree file can read both.
+    pub fn export(&mut self, global_state: &Mutex<GlobalSharedState>, ctx: &Context) -> usize {
+        let new_entries = not_exported(global_state, &self.exported);
+        for (id, tree) in new_entries.iter() {
+            let path = format!("{}/{}/queue/id:{:09}", self.sync_dir, self.name, id);
+            let mut file = File::create(&path).expect("cannot create sync entry");
+            tree.unparse_to(ctx, &mut file).expect("RAND_1750307213");
+            let entry = SyncEntry {
+                hash_of_grammar: self.hash_of_grammar,
+                tree: tree.clone(),
+            };
+            write_atomic(
+                &(path + ".tree"),
+                &ron::ser::to_string(&entry).expect("Serialization of SyncEntry failed!"),
+            );
+            self.exported.insert(*id);
+        }
+        return new_entries.len();
+    }
+
+    //Calls run for every tree the other instances exported since the last call. Trees for another
+    //grammar are parsed again from their bytes, entries that don't match the grammar are skipped.
+    pub fn import<F, E>(&mut self, ctx: &Context, run: &mut F) -> Result<usize, E>
+    where
+        F: FnMut(&Tree) -> Result<(), E>,
+    {
+        let mut imported = 0;
+        let parser = Parser::new(ctx);
+        let queue_dirs = other_queue_dirs(&self.sync_dir, &self.name);
+        for (instance, entries) in new_files(&queue_dirs, &self.imported, parse_tree_file_name) {
+            if entries.is_empty() {
+                continue;
+            }
+            for (id, path) in entries {
+                let tree_path = path.clone() + ".tree";
+                let entry: Option<SyncEntry> = ron::de::from_str(&read_to_string(&tree_path)).ok();
+                let tree = match entry {
+                    Some(ref entry) if entry.hash_of_grammar == self.hash_of_grammar => {
+                        Some(entry.tree.clone())
+                    }
+                    _ => {
+                        let mut bytes = vec![];
+                        File::open(&path)
+                            .and_then(|mut f| f.read_to_end(&mut bytes))
+                            .ok()
+                            .and_then(|_| parser.parse(ctx.nt_id("START"), &bytes).ok())
+                    }
+                };
+                //The entry is done even if it is skipped, it won't become valid later. If run fails
+                //it is tried again by the next call.
+                if let Some(tree) = tree {
+                    run(&tree)?;
+                    imported += 1;
+                }
+                self.imported.entry(instance.clone()).or_insert_with(HashSet::new).insert(id);
+            }
+            save_imported(&format!("{}/{}", self.sync_dir, self.name), &instance, &self.imported);
+        }
+        return Ok(imported);
+    }
+}
+
+//Id and path of the bytes for <dir>/id:<id>.tree
+fn parse_tree_file_name(path: &Path) -> Option<(usize, String)> {
+    let file_name = path.file_name()?.to_str()?;
+    if !file_name.starts_with("id:") || !file_name.ends_with(".tree") {
+        return None;
+    }
+    let id = file_name[3..file_name.len() - 5].parse::<usize>().ok()?;
+    let bytes_path = path.with_file_name(&file_name[..file_name.len() - 5]);
+    return Some((id, bytes_path.to_str()?.to_string()));
+}
+
+//Id and path of AFL queue entries, named id:NNNNNN[,...]
+fn parse_afl_file_name(path: &Path) -> Option<(usize, String)> {
+    let file_name = path.file_name()?.to_str()?;
+    if !file_name.starts_with("id:") || !path.is_file() {
+        return None;
+    }
+    let id = file_name[3..].split(',').next()?.parse::<usize>().ok()?;
+    return Some((id, path.to_str()?.to_string()));
+}
+
+//Queue id in the src: part of the names written by AflSync::export
+fn parse_afl_source(path: &str) -> Option<usize> {
+    let file_name = Path::new(path).file_name()?.to_str()?;
+    let src = file_name.split(',').find(|part| part.starts_with("src:"))?;
+    return src[4..].parse::<usize>().ok();
+}
+
+//The ids imported from each instance are saved in <own folder>/.synced/<instance>, one per line,
+//similar to the .synced folder of AFL. Without resume they are removed.
+fn load_imported(own_dir: &str, resume: bool) -> HashMap<String, HashSet<usize>> {
+    let synced_dir = format!("{}/.synced", own_dir);
+    if !resume {
+        let _ = fs::remove_dir_all(&synced_dir);
+    }
+    fs::create_dir_all(&synced_dir).expect("Could not create .synced folder");
+    let mut res = HashMap::new();
+    let files = fs::read_dir(&synced_dir).expect("cannot read .synced folder");
+    for file in files.filter_map(|file| file.ok()) {
+        let instance = file.file_name().to_string_lossy().into_owned();
+        //Left over from an interrupted write_atomic
+        if instance.ends_with(".tmp") {
+            continue;
+        }
+        let ids = read_to_string(&file.path().to_string_lossy())
+            .lines()
+            .filter_map(|line| line.parse::<usize>().ok())
+            .collect();
+        res.insert(instance, ids);
+    }
+    return res;
+}
+
+fn save_imported(own_dir: &str, instance: &str, imported: &HashMap<String, HashSet<usize>>) {
+    let mut ids = match imported.get(instance) {
+        Some(ids) => ids.iter().cloned().collect::<Vec<_>>(),
+        None => return,
+    };
+    ids.sort();
+    let content = ids.iter().map(|id| format!("{}\n", id)).collect::<String>();
+    write_atomic(&format!("{}/.synced/{}", own_dir, instance), &content);
+}
+
+fn read_to_string(path: &str) -> String {
+    let mut content = String::new();
+    let _ = File::open(path).and_then(|mut f| f.read_to_string(&mut content));
+    return content;
+}
+
+//Exchanges entries with AFL/AFL++ instances that use sync_dir as their output folder. Exported
+//entries use AFL names so that afl-fuzz syncs them like the ones of another AFL instance.
+pub struct AflSync {
+    pub sync_dir: String,
+    name: String,
+    next_id: usize,
+    hash_of_grammar: u64,
+    exported: HashSet<usize>,
+    //Ids imported from each AFL instance
+    imported: HashMap<String, HashSet<usize>>,
+}
+
+impl AflSync {
+    //The src: part of the names in the sync folder are the queue ids exported by a resumed campaign.
+    //Like Instance::new, a resumed campaign doesn't run the AFL entries it imported before again.
+    pub fn new(sync_dir: String, name: String, hash_of_grammar: u64, resume: bool) -> Self {
+        let queue_dir = format!("{}/{}/queue", sync_dir, name);
+        fs::create_dir_all(&queue_dir).expect("Could not create AFL sync folder");
+        fs::create_dir_all(afl_trees_dir(Path::new(&sync_dir), &name)).expect("Could not create AFL sync folder");
+        let entries = entries_in(&queue_dir, parse_afl_file_name);
+        //AFL only imports ids above the last one it saw, so a restart continues the numbering
+        let next_id = entries.iter().map(|&(id, _)| id + 1).max().unwrap_or(0);
+        let exported = if resume {
+            entries.iter().filter_map(|&(_, ref path)| parse_afl_source(path)).collect()
+        } else {
+            HashSet::new()
+        };
+        let imported = load_imported(&format!("{}/{}", sync_dir, name), resume);
+        return AflSync {
+            sync_dir,
+            name,
+            next_id,
+            hash_of_grammar,
+            exported,
+            imported,
+        };
+    }
+
+    //Writes the new queue entries as <sync_dir>/<name>/queue/id:NNNNNN,src:<queue id>,op:nautilus
+    //and their trees to <sync_dir>/<name>/.trees/id:NNNNNN.tree for the AFL++ custom mutator
+    pub fn export(&mut self, global_state: &Mutex<GlobalSharedState>, ctx: &Context) -> usize {
+        let new_entries = not_exported(global_state, &self.exported);
+        for (id, tree) in new_entries.iter() {
+            let path = format!(
+                "{}/{}/queue/id:{:06},src:{:06},op:nautilus",
+                self.sync_dir, self.name, self.next_id, id
+            );
+            let tmp_path = format!("{}/{}/.id:{:06}.tmp", self.sync_dir, self.name, self.next_id);
+            {
+                let mut file = File::create(&tmp_path).expect("cannot create AFL sync entry");
+                tree.unparse_to(ctx, &mut file).expect("RAND_2497034581");
+            }
+            let entry = SyncEntry {
+                hash_of_grammar: self.hash_of_grammar,
+                tree: tree.clone(),
+            };
+            let tree_path = afl_tree_path(Path::new(&self.sync_dir), &self.name, self.next_id);
+            write_atomic(
+                tree_path.to_str().expect("RAND_1745395290"),
+                &ron::ser::to_string(&entry).expect("Serialization of SyncEntry failed!"),
+            );
+            //AFL may read the queue at any time, it must never see a partially written file
+            fs::rename(&tmp_path, &path).expect("cannot rename AFL sync entry");
+            self.next_id += 1;
+            self.exported.insert(*id);
+        }
+        return new_entries.len();
+    }
+
+    //Parses the new entries of the AFL instances and calls run for every one that matches the
+    //grammar. AFL copies of our own entries are skipped.
+    pub fn import<F, E>(&mut self, ctx: &Context, run: &mut F) -> Result<usize, E>
+    where
+        F: FnMut(&Tree) -> Result<(), E>,
+    {
+        let mut imported = 0;
+        let parser = Parser::new(ctx);
+        let own_copies = format!(",sync:{},", self.name);
+        let queue_dirs = other_queue_dirs(&self.sync_dir, &self.name);
+        for (instance, entries) in new_files(&queue_dirs, &self.imported, parse_afl_file_name) {
+            if entries.is_empty() {
+                continue;
+            }
+            for (id, path) in entries {
+                let mut bytes = vec![];
+                let tree = if path.contains(&own_copies) {
+                    None
+                } else {
+                    File::open(&path)
+                        .and_then(|mut f| f.read_to_end(&mut bytes))
+                        .ok()
+                        .and_then(|_| parser.parse(ctx.nt_id("START"), &bytes).ok())
+                };
+                if let Some(tree) = tree {
+                    run(&tree)?;
+                    imported += 1;
+                }
+                //Only recorded once run succeeded, a failed entry is tried again by the next call
+                self.imported.entry(instance.clone()).or_insert_with(HashSet::new).insert(id);
+            }
+            save_imported(&format!("{}/{}", self.sync_dir, self.name), &instance, &self.imported);
+        }
+        return Ok(imported);
+    }
+}
+
+//Queue entries whose ids are not in exported, sorted by id. Entries that are being fuzzed are not
+//in the queue, they are exported by a later call.
+fn not_exported(global_state: &Mutex<GlobalSharedState>, exported: &HashSet<usize>) -> Vec<(usize, Tree)> {
+    let gstate = global_state.lock().expect("RAND_1208264373");
+    let mut res = gstate
+        .queue
+        .inputs
+        .iter()
+        .chain(gstate.queue.processed.iter())
+        .filter(|item| !exported.contains(&item.id))
+        .map(|item| (item.id, item.tree.clone()))
+        .collect::<Vec<_>>();
+    res.sort_by_key(|&(id, _)| id);
+    return res;
+}
+
+//Id and path of every entry in queue_dir, parse returns them for the files that are entries
+fn entries_in<P>(queue_dir: &str, parse: P) -> Vec<(usize, String)>
+where
+    P: Fn(&Path) -> Option<(usize, String)>,
+{
+    return fs::read_dir(queue_dir)
+        .expect("cannot read sync folder")
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| parse(&entry.path()))
+        .collect();
+}
+
+//Name and queue folder of every instance in sync_dir except own_name
+fn other_queue_dirs(sync_dir: &str, own_name: &str) -> Vec<(String, PathBuf)> {
+    let dirs = match fs::read_dir(sync_dir) {
+        Ok(dirs) => dirs,
+        Err(_) => return vec![],
+    };
+    return dirs
+        .filter_map(|dir| dir.ok())
+        .map(|dir| (dir.file_name().to_string_lossy().into_owned(), dir.path().join("queue")))
+        .filter(|&(ref name, ref queue_dir)| name != own_name && !name.starts_with('.') && queue_dir.is_dir())
+        .collect();
+}
+
+//Entries of every queue folder that were not imported yet, sorted by id. parse returns the id and
+//the path to import for the files that are entries.
+fn new_files<P>(
+    queue_dirs: &[(String, PathBuf)],
+    imported: &HashMap<String, HashSet<usize>>,
+    parse: P,
+) -> Vec<(String, Vec<(usize, String)>)>
+where
+    P: Fn(&Path) -> Option<(usize, String)>,
+{
+    let mut res = vec![];
+    for (instance, queue_dir) in queue_dirs.iter() {
+        let done = imported.get(instance);
+        let mut entries = match fs::read_dir(queue_dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| parse(&entry.path()))
+                .filter(|&(id, _)| done.map(|done| !done.contains(&id)).unwrap_or(true))
+                .collect::<Vec<_>>(),
+            Err(_) => continue,
+        };
+        entries.sort();
+        res.push((instance.clone(), entries));
+    }
+    return res;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::fs;
+    use std::process;
+    use sync::{load_imported, save_imported};
+
+    #[test]
+    fn check_imported_ids_are_saved() {
+        let own_dir = format!("/tmp/gramophone_sync_test_{}/main", process::id());
+        assert!(load_imported(&own_dir, true).is_empty());
+        let mut imported = HashMap::new();
+        imported.insert("secondary".to_string(), [3, 1, 2].iter().cloned().collect::<HashSet<_>>());
+        save_imported(&own_dir, "secondary", &imported);
+        save_imported(&own_dir, "unknown", &imported);
+        assert_eq!(load_imported(&own_dir, true), imported);
+        //A new campaign imports everything again
+        assert!(load_imported(&own_dir, false).is_empty());
+        assert!(load_imported(&own_dir, true).is_empty());
+        let _ = fs::remove_dir_all(format!("/tmp/gramophone_sync_test_{}", process::id()));
+    }
+}
@@ -7,17 +7,24 @@ extern crate time as othertime;
 #[macro_use]
 extern crate serde_derive;
 extern crate argparse;
+extern crate rand;
 extern crate ron;
 
+mod bitmap;
+mod calibration;
 mod config;
+mod executor;
 mod fuzzer;
 mod queue;
 mod rules;
+mod schedule;
 mod shared_state;
 mod state;
+mod triage;
 
 use std::collections::HashMap;
 
+use calibration::TimeoutCalibration;
 use config::Config;
 use forksrv::error::SubprocessError;
 use fuzzer::Fuzzer;
@@ -38,7 +45,7 @@ use std::io::{Read, Write};
 use std::path::Path;
 use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::{thread, time};
 
 fn main() {
@@ -73,6 +80,7 @@ fn main() {
 
     let global_state = Arc::new(Mutex::new(GlobalSharedState::new(
         config.path_to_workdir.clone(),
+        TimeoutCalibration::new(&config),
     )));
 
     let mut fuzzer = Fuzzer::new(
@@ -81,6 +89,8 @@ fn main() {
         global_state.clone(),
         config.dump_mode,
         config.path_to_workdir.clone(),
+        config.backend,
+        Duration::from_millis(config.timeout_in_millis),
     ).expect("RAND_3617502350");
 
 
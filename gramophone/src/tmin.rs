@@ -0,0 +1,136 @@
+extern crate forksrv;
+extern crate grammartec;
+extern crate serde_json;
+extern crate time as othertime;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate clap;
+extern crate ron;
+
+mod config;
+mod executor;
+mod triage;
+
+use config::Config;
+use grammartec::context::Context;
+use grammartec::loader::{self, LoadOptions};
+use grammartec::mutator::Mutator;
+use grammartec::parser::Parser;
+use grammartec::tree::{TreeLike, TreeMutation};
+use triage::{Triager, DEFAULT_NUMBER_OF_FRAMES};
+
+use clap::{App, Arg};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::process;
+use std::time::Duration;
+
+fn main() {
+    //Parse parameters
+    let matches = App::new("tmin")
+        .about("Minimize a crashing input with the grammar while keeping its crash bucket")
+        .arg(Arg::with_name("config")
+             .short("g")
+             .value_name("CONFIG")
+             .takes_value(true)
+             .help("Path to configuration file")
+             .default_value("config.ron"))
+        .arg(Arg::with_name("output")
+             .short("o")
+             .value_name("OUTPUT")
+             .takes_value(true)
+             .help("Where to write the minimized input [default: CRASH.min]"))
+        .arg(Arg::with_name("frames")
+             .short("n")
+             .value_name("FRAMES")
+             .takes_value(true)
+             .help("Number of stack frames that identify a bucket [default: 5]"))
+        .arg(Arg::with_name("dumb")
+             .short("d")
+             .help("Don't use fancy calculations to generate trees (dumb mode)"))
+        .arg(Arg::with_name("crash")
+             .value_name("CRASH")
+             .required(true)
+             .help("The crashing input"))
+        .get_matches();
+
+    let config_file_path = matches.value_of("config")
+        .expect("the path to the configuration file has a default value");
+    let crash_path = matches.value_of("crash")
+        .expect("crash is a required parameter")
+        .to_string();
+    let output_path = matches.value_of("output")
+        .map(|path| path.to_string())
+        .unwrap_or(crash_path.clone() + ".min");
+    let number_of_frames = value_t!(matches, "frames", usize)
+        .unwrap_or(DEFAULT_NUMBER_OF_FRAMES);
+    let dumb = matches.is_present("dumb");
+
+    let mut config_file = File::open(&config_file_path).expect("cannot read config file");
+    let mut config_file_contents = String::new();
+    config_file
+        .read_to_string(&mut config_file_contents)
+        .expect("RAND_1799513236");
+    let config: Config = ron::de::from_str(&config_file_contents).expect("Failed to deserialize");
+
+    //Generate rules using a grammar
+    let mut options = LoadOptions::new(config.max_tree_size);
+    options.dumb = dumb;
+    let ctx = loader::load_path(&config.path_to_grammar, &options)
+        .unwrap_or_else(|e| panic!("{}", e));
+
+    let mut crash = vec![];
+    File::open(&crash_path)
+        .and_then(|mut f| f.read_to_end(&mut crash))
+        .expect("cannot read crash file");
+    let mut tree = match Parser::new(&ctx).parse(ctx.nt_id("START"), &crash) {
+        Ok(tree) => tree,
+        Err(e) => {
+            println!("{} does not match the grammar: {}", crash_path, e);
+            process::exit(1);
+        }
+    };
+
+    let mut triager = Triager::new(
+        config.backend,
+        config.path_to_bin_target.clone(),
+        config.arguments.clone(),
+        config.path_to_workdir.clone(),
+        Duration::from_millis(config.timeout_in_millis),
+        number_of_frames,
+    ).expect("RAND_2617530806");
+    let expected = match triager.classify(&crash).expect("RAND_1446004713") {
+        Some(expected) => expected,
+        None => {
+            println!("{} does not crash", crash_path);
+            process::exit(1);
+        }
+    };
+    println!("Crash: {:?} in bucket {}", expected.0, expected.1);
+
+    let mut executions = 0;
+    Mutator::new(&ctx)
+        .minimize_crash(
+            &mut tree,
+            &ctx,
+            &mut |t: &TreeMutation, _: &HashSet<usize>, ctx: &Context| {
+                executions += 1;
+                let res = triager.classify(&t.unparse_to_vec(ctx))?;
+                return Ok(res.as_ref() == Some(&expected));
+            },
+        )
+        .expect("RAND_3040565817");
+
+    let minimized = tree.unparse_to_vec(&ctx);
+    let mut file = File::create(&output_path).expect("cannot create output file");
+    file.write_all(&minimized).expect("RAND_2238047418");
+    println!(
+        "Minimized {} bytes to {} bytes in {} executions, written to {}",
+        crash.len(),
+        minimized.len(),
+        executions,
+        output_path
+    );
+}
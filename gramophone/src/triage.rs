@@ -0,0 +1,308 @@
+extern crate time as othertime;
+use othertime::strftime;
+
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::thread;
+use std::time::Duration;
+
+use config::Backend;
+use executor::{ExecutionReason, Executor};
+use forksrv::error::SubprocessError;
+use forksrv::exitreason::ExitReason;
+use serde_json;
+
+//Number of stack frames (from the top) that identify a crash bucket
+pub const DEFAULT_NUMBER_OF_FRAMES: usize = 5;
+
+//64 bit FNV-1a. The bucket names have to stay the same across Rust releases, which DefaultHasher
+//does not guarantee.
+pub struct Fnv(u64);
+
+impl Fnv {
+    pub fn new() -> Self {
+        return Fnv(0xcbf2_9ce4_8422_2325);
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for b in bytes.iter() {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    //Length prefixed, so that e.g. the frames ["ab", "c"] and ["a", "bc"] hash differently
+    pub fn write_str(&mut self, s: &str) {
+        self.write(&(s.len() as u64).to_le_bytes());
+        self.write(s.as_bytes());
+    }
+
+    pub fn finish(&self) -> u64 {
+        return self.0;
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct CrashReport {
+    //e.g. "AddressSanitizer: heap-buffer-overflow"
+    pub sanitizer: Option<String>,
+    pub frames: Vec<String>,
+}
+
+impl CrashReport {
+    //Extracts the sanitizer headline and the function names of the first stack trace from the
+    //stderr of a crashing run. Understands sanitizer reports and gdb style backtraces.
+    pub fn parse(stderr: &str) -> Self {
+        let mut report = CrashReport::default();
+        for line in stderr.lines() {
+            let line = line.trim();
+            if report.sanitizer.is_none() {
+                if let Some(pos) = line.find("ERROR: ") {
+                    if line.starts_with("==") {
+                        report.sanitizer = Some(
+                            line[pos + "ERROR: ".len()..]
+                                .split(" on ")
+                                .next()
+                                .expect("RAND_1034822467")
+                                .trim()
+                                .to_string(),
+                        );
+                    }
+                } else if line.contains(": runtime error: ") {
+                    report.sanitizer = Some("UndefinedBehaviorSanitizer: runtime error".to_string());
+                }
+            }
+            if !line.starts_with('#') {
+                continue;
+            }
+            //"#0 0x4f3e1a in mrb_foo /src/foo.c:12:3" or "#0  0x0000555555554a2e in main () at x.c:5"
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            if words.len() < 4 || words[2] != "in" {
+                continue;
+            }
+            //Sanitizers print several stacks (e.g. where memory was freed), only the first counts
+            if words[0] == "#0" && !report.frames.is_empty() {
+                break;
+            }
+            let function = words[3];
+            if function.starts_with("__asan")
+                || function.starts_with("__sanitizer")
+                || function.starts_with("__ubsan")
+            {
+                continue;
+            }
+            report.frames.push(function.to_string());
+        }
+        return report;
+    }
+
+    pub fn bucket(&self, exitreason: ExitReason, number_of_frames: usize) -> String {
+        let mut s = Fnv::new();
+        //Without any frames the signal and sanitizer type are all that is left to tell crashes apart
+        if self.frames.is_empty() {
+            s.write_str(&format!("{:?}", exitreason));
+        }
+        match self.sanitizer {
+            Some(ref sanitizer) => {
+                s.write(&[1]);
+                s.write_str(sanitizer);
+            }
+            None => s.write(&[0]),
+        }
+        for frame in self.frames.iter().take(number_of_frames) {
+            s.write_str(frame);
+        }
+        return format!("{:016x}", s.finish());
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CrashSummary {
+    pub bucket: String,
+    pub exitreason: ExitReason,
+    pub signal: Option<i32>,
+    pub sanitizer: Option<String>,
+    pub frames: Vec<String>,
+    pub first_seen: String,
+    pub execution_reason: Option<ExecutionReason>,
+}
+
+pub struct Triager {
+    forksrv: Executor,
+    work_dir: String,
+    timeout: Duration,
+    number_of_frames: usize,
+}
+
+impl Triager {
+    pub fn new(
+        backend: Backend,
+        path: String,
+        args: Vec<String>,
+        work_dir: String,
+        timeout: Duration,
+        number_of_frames: usize,
+    ) -> Result<Self, SubprocessError> {
+        let crash_dir = format!("{}outputs/crashes", work_dir);
+        fs::create_dir_all(&crash_dir).expect("Could not create crashes folder");
+        //The file is truncated before every run, so it only holds the last stderr
+        let err_path = format!(
+            "{}/.stderr_{}",
+            crash_dir,
+            thread::current().name().unwrap_or("triage")
+        );
+        let forksrv = Executor::new(backend, &path, &args, "/dev/null".into(), err_path)?;
+        return Ok(Triager {
+            forksrv,
+            work_dir,
+            timeout,
+            number_of_frames,
+        });
+    }
+
+    //Runs the input with stderr captured. Returns None if it did not crash.
+    pub fn run(&mut self, input: &[u8]) -> Result<Option<(ExitReason, String)>, SubprocessError> {
+        let exitreason = self.forksrv.run_on(input, self.timeout)?;
+        match exitreason {
+            ExitReason::Signaled(_) | ExitReason::Normal(223) => {}
+            _ => return Ok(None),
+        }
+        let mut stderr = String::new();
+        File::open(self.forksrv.err_path())
+            .and_then(|mut f| f.read_to_string(&mut stderr))
+            .expect("cannot read captured stderr");
+        return Ok(Some((exitreason, stderr)));
+    }
+
+    //Exit reason and bucket of the crash, this is what a minimized crash has to keep
+    pub fn classify(&mut self, input: &[u8]) -> Result<Option<(ExitReason, String)>, SubprocessError> {
+        if let Some((exitreason, stderr)) = self.run(input)? {
+            let bucket = CrashReport::parse(&stderr).bucket(exitreason, self.number_of_frames);
+            return Ok(Some((exitreason, bucket)));
+        }
+        return Ok(None);
+    }
+
+    //Runs the input once more with stderr captured and stores it in its crash bucket. Returns
+    //the bucket name and whether the bucket is new, or None if the input did not crash this time.
+    pub fn triage(
+        &mut self,
+        input: &[u8],
+        execution_reason: Option<ExecutionReason>,
+    ) -> Result<Option<(String, bool)>, SubprocessError> {
+        let (exitreason, stderr) = match self.run(input)? {
+            Some(res) => res,
+            None => return Ok(None),
+        };
+        let signal = match exitreason {
+            ExitReason::Signaled(sig) => Some(sig),
+            _ => None,
+        };
+        let report = CrashReport::parse(&stderr);
+        let bucket = report.bucket(exitreason, self.number_of_frames);
+        let bucket_dir = self.bucket_dir(&bucket);
+        fs::create_dir_all(&bucket_dir).expect("Could not create crash bucket folder");
+
+        //Only the first crash of a bucket writes the summary, create_new makes this race free
+        let mut is_new = false;
+        if let Ok(mut file) = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(format!("{}/summary.json", bucket_dir))
+        {
+            is_new = true;
+            let summary = CrashSummary {
+                bucket: bucket.clone(),
+                exitreason,
+                signal,
+                sanitizer: report.sanitizer,
+                frames: report.frames,
+                first_seen: strftime("[%Y-%m-%d] %H:%M:%S", &othertime::now())
+                    .expect("RAND_3969400617"),
+                execution_reason,
+            };
+            serde_json::to_writer_pretty(&mut file, &summary).expect("RAND_1215767478");
+            let mut report_file =
+                File::create(format!("{}/stderr", bucket_dir)).expect("RAND_2309446581");
+            report_file
+                .write_all(stderr.as_bytes())
+                .expect("RAND_2646811722");
+        }
+        let mut hasher = Fnv::new();
+        hasher.write(input);
+        let mut file = File::create(format!("{}/{:016x}", bucket_dir, hasher.finish()))
+            .expect("RAND_4118519263");
+        file.write_all(input).expect("RAND_3542358011");
+        return Ok(Some((bucket, is_new)));
+    }
+
+    pub fn save_minimized(&self, bucket: &str, input: &[u8]) {
+        let mut file = File::create(format!("{}/minimized", self.bucket_dir(bucket)))
+            .expect("RAND_1100420985");
+        file.write_all(input).expect("RAND_3170425745");
+    }
+
+    fn bucket_dir(&self, bucket: &str) -> String {
+        return format!("{}outputs/crashes/{}", self.work_dir, bucket);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use forksrv::exitreason::ExitReason;
+    use triage::{CrashReport, Fnv};
+
+    #[test]
+    fn parse_asan_report() {
+        let stderr = "=================================================================
+==4242==ERROR: AddressSanitizer: heap-use-after-free on address 0x602000000010 at pc 0x4f3e1a
+READ of size 4 at 0x602000000010 thread T0
+    #0 0x4f3e1a in mrb_ary_ref /src/mruby/src/array.c:612:10
+    #1 0x4f4021 in mrb_vm_exec /src/mruby/src/vm.c:1234:5
+    #2 0x7f01 in __libc_start_main
+freed by thread T0 here:
+    #0 0x4c2b10 in __interceptor_free
+    #1 0x4f0000 in mrb_free /src/mruby/src/gc.c:12:3
+";
+        let report = CrashReport::parse(stderr);
+        assert_eq!(
+            report.sanitizer,
+            Some("AddressSanitizer: heap-use-after-free".to_string())
+        );
+        assert_eq!(
+            report.frames,
+            vec!["mrb_ary_ref", "mrb_vm_exec", "__libc_start_main"]
+        );
+    }
+
+    #[test]
+    fn bucket_ignores_deeper_frames() {
+        let a = CrashReport {
+            sanitizer: None,
+            frames: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+        };
+        let b = CrashReport {
+            sanitizer: None,
+            frames: vec!["a".to_string(), "b".to_string(), "d".to_string()],
+        };
+        let sig = ExitReason::Signaled(11);
+        assert_eq!(a.bucket(sig, 2), b.bucket(sig, 2));
+        assert!(a.bucket(sig, 3) != b.bucket(sig, 3));
+        let empty = CrashReport::default();
+        assert!(empty.bucket(sig, 2) != empty.bucket(ExitReason::Signaled(6), 2));
+    }
+
+    #[test]
+    fn bucket_names_are_fixed() {
+        let mut s = Fnv::new();
+        s.write(b"a");
+        assert_eq!(s.finish(), 0xaf63_dc4c_8601_ec8c);
+        //Changing this name splits the existing crash buckets
+        let report = CrashReport {
+            sanitizer: Some("AddressSanitizer: SEGV".to_string()),
+            frames: vec!["mrb_vm_exec".to_string()],
+        };
+        assert_eq!(report.bucket(ExitReason::Signaled(11), 5), "62b4a2c12942a57c");
+    }
+}
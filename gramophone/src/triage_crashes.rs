@@ -0,0 +1,116 @@
+extern crate forksrv;
+extern crate serde_json;
+extern crate time as othertime;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate clap;
+extern crate ron;
+
+mod config;
+mod executor;
+mod triage;
+
+use config::Config;
+use triage::{Triager, DEFAULT_NUMBER_OF_FRAMES};
+
+use clap::{App, Arg};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::time::Duration;
+
+fn main() {
+    //Parse parameters
+    let matches = App::new("triage")
+        .about("Re-run crashing inputs and sort them into buckets by their stack trace")
+        .arg(Arg::with_name("config")
+             .short("g")
+             .value_name("CONFIG")
+             .takes_value(true)
+             .help("Path to configuration file")
+             .default_value("config.ron"))
+        .arg(Arg::with_name("frames")
+             .short("n")
+             .value_name("FRAMES")
+             .takes_value(true)
+             .help("Number of stack frames that identify a bucket [default: 5]"))
+        .arg(Arg::with_name("inputs")
+             .value_name("DIR")
+             .help("Folder with crashing inputs [default: outputs/signaled in the working directory]"))
+        .get_matches();
+
+    let config_file_path = matches.value_of("config")
+        .expect("the path to the configuration file has a default value");
+    let number_of_frames = value_t!(matches, "frames", usize)
+        .unwrap_or(DEFAULT_NUMBER_OF_FRAMES);
+
+    let mut config_file = File::open(&config_file_path).expect("cannot read config file");
+    let mut config_file_contents = String::new();
+    config_file
+        .read_to_string(&mut config_file_contents)
+        .expect("RAND_2950871064");
+    let config: Config = ron::de::from_str(&config_file_contents).expect("Failed to deserialize");
+
+    let input_dir = matches.value_of("inputs")
+        .map(|dir| dir.to_string())
+        .unwrap_or(config.path_to_workdir.clone() + "outputs/signaled");
+
+    let mut triager = Triager::new(
+        config.backend,
+        config.path_to_bin_target.clone(),
+        config.arguments.clone(),
+        config.path_to_workdir.clone(),
+        Duration::from_millis(config.timeout_in_millis),
+        number_of_frames,
+    ).expect("RAND_1652360171");
+
+    let mut paths = fs::read_dir(&input_dir)
+        .expect("cannot read input folder")
+        .map(|entry| entry.expect("RAND_3355512570").path())
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    let mut buckets: HashMap<String, usize> = HashMap::new();
+    let mut not_reproducible = vec![];
+    for path in paths.iter() {
+        let mut input = vec![];
+        File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut input))
+            .expect("cannot read input file");
+        //The execution reason that found an input is not stored in outputs/signaled
+        match triager.triage(&input, None) {
+            Ok(Some((bucket, _))) => *buckets.entry(bucket).or_insert(0) += 1,
+            Ok(None) => not_reproducible.push(path.clone()),
+            Err(e) => {
+                println!("Triage of {} failed: {:?}", path.display(), e);
+                triager = Triager::new(
+                    config.backend,
+                    config.path_to_bin_target.clone(),
+                    config.arguments.clone(),
+                    config.path_to_workdir.clone(),
+                    Duration::from_millis(config.timeout_in_millis),
+                    number_of_frames,
+                ).expect("RAND_3787040066");
+            }
+        }
+    }
+
+    let mut buckets = buckets.into_iter().collect::<Vec<_>>();
+    buckets.sort_by(|a, b| b.1.cmp(&a.1));
+    for (bucket, count) in buckets.iter() {
+        println!("{}: {} inputs", bucket, count);
+    }
+    for path in not_reproducible.iter() {
+        println!("Did not crash again: {}", path.display());
+    }
+    println!(
+        "{} inputs, {} buckets, {} not reproducible. Results in {}outputs/crashes",
+        paths.len(),
+        buckets.len(),
+        not_reproducible.len(),
+        config.path_to_workdir
+    );
+}